@@ -1,27 +1,46 @@
 use crate::{
-    client::ClientMessage,
+    client::{ClientMessage, EncodedPublish, TOPIC_ALIAS_MAXIMUM},
     plugin::{AuthentificationResult, Noop, Plugin},
-    tree::SubscriptionTree,
+    tree::{RetainedTree, SubscriptionTree},
 };
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future;
 use log::{debug, info, warn};
 use mqtt_v5::{
-    topic::TopicFilter,
+    decoder::DecodeMode,
+    encoder::{encode_string, encode_variable_int},
+    topic::{Topic, TopicFilter, TopicLevel},
     types::{
-        properties::{AssignedClientIdentifier, SessionExpiryInterval},
-        AuthenticatePacket, ConnectAckPacket, ConnectPacket, ConnectReason, DisconnectReason,
-        FinalWill, Packet, ProtocolVersion, PublishAckPacket, PublishCompletePacket,
-        PublishCompleteReason, PublishPacket, PublishReceivedPacket, PublishReleasePacket,
-        PublishReleaseReason, QoS, SubscribeAckPacket, SubscribeAckReason, SubscribePacket,
+        properties::{
+            AssignedClientIdentifier, MaximumPacketSize, MessageExpiryInterval, ReasonString,
+            ReceiveMaximum, RequestProblemInformation, RequestResponseInformation,
+            ResponseInformation, RetainAvailable, ServerReference, SessionExpiryInterval,
+            SharedSubscriptionAvailable, SubscriptionIdentifier, SubscriptionIdentifierAvailable,
+            TopicAlias, TopicAliasMaximum,
+        },
+        AuthenticatePacket, ConnectAckPacket, ConnectPacket, ConnectReason, DisconnectPacket,
+        DisconnectReason, Encode, FinalWill, Packet, PacketType, ProtocolVersion, PublishAckPacket,
+        PublishAckReason, PublishCompletePacket, PublishCompleteReason, PublishPacket,
+        PublishReceivedPacket, PublishReceivedReason, PublishReleasePacket, PublishReleaseReason,
+        QoS, RetainHandling, SubscribeAckPacket, SubscribeAckReason, SubscribePacket,
         UnsubscribeAckPacket, UnsubscribeAckReason, UnsubscribePacket,
     },
+    TOPIC_SEPARATOR,
 };
+use rand::Rng;
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    cmp::Reverse,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque,
+    },
+    hash::{Hash, Hasher},
+    sync::{Arc, OnceLock},
     time::Duration,
 };
 use tokio::{
     sync::mpsc::{self, Receiver, Sender},
-    time,
+    time::{self, Instant},
 };
 
 /// A client connected but not yet authenticated.
@@ -39,14 +58,355 @@ impl UnauthenticatedConnection {
     /// Send a `ClientMessage` to the client via the channel handle.
     /// This is a fire and forget operation because upon any error on the
     /// connection the `Client` will send a `BrokerMessage::Disconnect`.
-    async fn send(&self, message: ClientMessage) {
+    async fn send(&self, mut message: ClientMessage) {
+        if !requests_problem_information(&self.connect_packet) {
+            strip_problem_information(&mut message);
+        }
+
         drop(self.client_sender.send(message).await);
     }
 }
 
+/// Returns false if the client asked not to receive reason strings
+/// and user properties in case of failures.
+fn requests_problem_information(connect_packet: &ConnectPacket) -> bool {
+    connect_packet.request_problem_information != Some(RequestProblemInformation(0))
+}
+
+/// Remove reason strings and user properties from all packets other than PUBLISH, CONNACK
+/// and DISCONNECT, for clients which don't request problem information [MQTT-3.1.2-29].
+fn strip_problem_information(message: &mut ClientMessage) {
+    let packets = match message {
+        ClientMessage::Packet(packet) => std::slice::from_mut(packet),
+        ClientMessage::Packets(packets) => packets.as_mut_slice(),
+        ClientMessage::Publish(_) | ClientMessage::Disconnect(_) => return,
+    };
+
+    for packet in packets {
+        let (reason_string, user_properties) = match packet {
+            Packet::PublishAck(p) => (&mut p.reason_string, &mut p.user_properties),
+            Packet::PublishReceived(p) => (&mut p.reason_string, &mut p.user_properties),
+            Packet::PublishRelease(p) => (&mut p.reason_string, &mut p.user_properties),
+            Packet::PublishComplete(p) => (&mut p.reason_string, &mut p.user_properties),
+            Packet::SubscribeAck(p) => (&mut p.reason_string, &mut p.user_properties),
+            Packet::UnsubscribeAck(p) => (&mut p.reason_string, &mut p.user_properties),
+            Packet::Authenticate(p) => (&mut p.reason_string, &mut p.user_properties),
+            _ => continue,
+        };
+
+        *reason_string = None;
+        user_properties.clear();
+    }
+}
+
+/// The parts of a PUBLISH packet which are the same for all receivers
+/// using one protocol version, encoded once for all their deliveries.
+#[derive(Debug)]
+struct SharedEncoding {
+    /// The topic name, prefixed with its length.
+    topic: Bytes,
+    /// The properties which don't depend on the receiver, followed by the payload.
+    body: Bytes,
+    /// The size of the properties at the start of `body`.
+    property_size: u32,
+}
+
+impl SharedEncoding {
+    fn new(packet: &PublishPacket, protocol_version: ProtocolVersion) -> Self {
+        let mut topic = BytesMut::new();
+        encode_string(packet.topic.topic_name(), &mut topic);
+
+        let mut body = BytesMut::with_capacity(packet.payload.len());
+
+        if protocol_version == ProtocolVersion::V500 {
+            packet.payload_format_indicator.encode(&mut body);
+            packet.response_topic.encode(&mut body);
+            packet.correlation_data.encode(&mut body);
+            packet.user_properties.encode(&mut body);
+            packet.content_type.encode(&mut body);
+        }
+
+        let property_size = body.len() as u32;
+        body.extend_from_slice(&packet.payload);
+
+        Self { topic: topic.freeze(), body: body.freeze(), property_size }
+    }
+}
+
+/// A received message together with its shared encodings, which are created
+/// when the message is first delivered with the respective protocol version.
+#[derive(Debug)]
+struct SharedMessage {
+    packet: PublishPacket,
+    v3_encoding: OnceLock<SharedEncoding>,
+    v5_encoding: OnceLock<SharedEncoding>,
+}
+
+impl SharedMessage {
+    fn encoding(&self, protocol_version: ProtocolVersion) -> &SharedEncoding {
+        let encoding = match protocol_version {
+            ProtocolVersion::V500 => &self.v5_encoding,
+            // MQTT 3.1 and 3.1.1 encode PUBLISH packets the same way.
+            ProtocolVersion::V310 | ProtocolVersion::V311 => &self.v3_encoding,
+        };
+
+        encoding.get_or_init(|| SharedEncoding::new(&self.packet, protocol_version))
+    }
+}
+
+/// A message waiting to be delivered, with the time it expires. The received packet
+/// and its encodings are shared by all receivers of the message, only the retain flag
+/// and subscription identifiers are stored for each of them.
+#[derive(Debug, Clone)]
+struct StoredPublish {
+    message: Arc<SharedMessage>,
+    retain: bool,
+    subscription_identifiers: Vec<SubscriptionIdentifier>,
+    expires_at: Option<Instant>,
+}
+
+impl StoredPublish {
+    /// Store a packet which was just received by the broker.
+    fn new(packet: PublishPacket) -> Self {
+        let expires_at = packet
+            .message_expiry_interval
+            .as_ref()
+            .map(|interval| Instant::now() + Duration::from_secs(interval.0 as u64));
+
+        Self {
+            retain: packet.retain,
+            subscription_identifiers: vec![],
+            message: Arc::new(SharedMessage {
+                packet,
+                v3_encoding: OnceLock::new(),
+                v5_encoding: OnceLock::new(),
+            }),
+            expires_at,
+        }
+    }
+
+    /// The packet the message was published with.
+    fn packet(&self) -> &PublishPacket {
+        &self.message.packet
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false)
+    }
+
+    /// The message expiry interval to deliver the message with, the remaining lifetime of it.
+    fn message_expiry_interval(&self, now: Instant) -> Option<MessageExpiryInterval> {
+        self.expires_at.map(|expires_at| {
+            let remaining = expires_at.saturating_duration_since(now);
+            // Round up, a message which is not expired yet has at least one second left.
+            let remaining_secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            MessageExpiryInterval(remaining_secs as u32)
+        })
+    }
+
+    /// Encode the message for delivery to a client. Only the fixed header, packet ID and
+    /// the properties which differ per receiver are encoded here, the topic, remaining
+    /// properties and payload are copied from or shared with the other deliveries.
+    /// `topic_alias` is the alias to send and whether the client already knows it.
+    fn encode(
+        &self,
+        protocol_version: ProtocolVersion,
+        qos: QoS,
+        packet_id: Option<u16>,
+        is_duplicate: bool,
+        topic_alias: Option<(u16, bool)>,
+        now: Instant,
+    ) -> EncodedPublish {
+        let shared = self.message.encoding(protocol_version);
+        let mut variable_header = BytesMut::new();
+
+        match topic_alias {
+            // Clients which know the alias of a topic receive an empty topic name.
+            Some((_, true)) => encode_string("", &mut variable_header),
+            _ => variable_header.extend_from_slice(&shared.topic),
+        }
+
+        if let Some(packet_id) = packet_id {
+            variable_header.put_u16(packet_id);
+        }
+
+        if protocol_version == ProtocolVersion::V500 {
+            let mut properties = BytesMut::new();
+            self.message_expiry_interval(now).encode(&mut properties);
+            topic_alias.map(|(alias, _)| TopicAlias(alias)).encode(&mut properties);
+            self.subscription_identifiers.encode(&mut properties);
+
+            encode_variable_int(
+                properties.len() as u32 + shared.property_size,
+                &mut variable_header,
+            );
+            variable_header.extend_from_slice(&properties);
+        }
+
+        let mut flags = (qos as u8) << 1;
+
+        if is_duplicate {
+            flags |= 0b0000_1000;
+        }
+
+        if self.retain {
+            flags |= 0b0000_0001;
+        }
+
+        // The fixed header takes up to 5 bytes.
+        let mut header = BytesMut::with_capacity(5 + variable_header.len());
+        header.put_u8((PacketType::Publish as u8) << 4 | flags);
+        encode_variable_int((variable_header.len() + shared.body.len()) as u32, &mut header);
+        header.extend_from_slice(&variable_header);
+
+        EncodedPublish { header: header.freeze(), body: shared.body.clone() }
+    }
+}
+
+/// Topic aliases the broker assigned for publish packets sent to a client.
+/// When all aliases are in use, the least recently used one is reassigned.
+#[derive(Debug)]
+struct OutgoingTopicAliases {
+    /// The topic alias maximum the client sent in its CONNECT packet.
+    maximum: u16,
+    /// Maps each aliased topic to its alias and the tick it was last used.
+    aliases: HashMap<Topic, (u16, u64)>,
+    /// The aliased topics ordered by the tick they were last used.
+    last_used: BTreeMap<u64, Topic>,
+    tick: u64,
+}
+
+impl OutgoingTopicAliases {
+    fn new(maximum: u16) -> Self {
+        Self { maximum, aliases: HashMap::new(), last_used: BTreeMap::new(), tick: 0 }
+    }
+
+    /// The alias to send `topic` with and whether the client already knows the topic by it,
+    /// or `None` if the client doesn't accept topic aliases. Topics without an alias get
+    /// the next unused one, or the least recently used one once all of them are in use.
+    /// The first packet for a topic carries the topic and its new alias, subsequent
+    /// packets only carry the alias.
+    fn get(&self, topic: &Topic) -> Option<(u16, bool)> {
+        if self.maximum == 0 {
+            return None;
+        }
+
+        if let Some((alias, _)) = self.aliases.get(topic) {
+            return Some((*alias, true));
+        }
+
+        let alias = if self.aliases.len() < self.maximum as usize {
+            self.aliases.len() as u16 + 1
+        } else {
+            let (_, least_recently_used) = self.last_used.iter().next().unwrap();
+            self.aliases[least_recently_used].0
+        };
+
+        Some((alias, false))
+    }
+
+    /// Record that a packet for `topic` was sent with the alias returned by `get`.
+    fn mark_used(&mut self, topic: &Topic) {
+        self.tick += 1;
+
+        if let Some((_, last_used)) = self.aliases.get_mut(topic) {
+            let topic = self.last_used.remove(last_used).unwrap();
+            *last_used = self.tick;
+            self.last_used.insert(self.tick, topic);
+            return;
+        }
+
+        let alias = if self.aliases.len() < self.maximum as usize {
+            self.aliases.len() as u16 + 1
+        } else {
+            let (_, least_recently_used) = self.last_used.pop_first().unwrap();
+            self.aliases.remove(&least_recently_used).unwrap().0
+        };
+
+        self.aliases.insert(topic.clone(), (alias, self.tick));
+        self.last_used.insert(self.tick, topic.clone());
+    }
+}
+
+/// Allocates packet identifiers and keeps track of the value using each of them,
+/// so an identifier isn't handed out again until it is released.
+#[derive(Debug)]
+struct PacketIdAllocator<T> {
+    next_packet_id: u16,
+    // Increases with every allocation, to keep track of the allocation order.
+    next_sequence: u64,
+    in_use: HashMap<u16, (u64, T)>,
+}
+
+impl<T> PacketIdAllocator<T> {
+    fn new() -> Self {
+        Self { next_packet_id: 1, next_sequence: 0, in_use: HashMap::new() }
+    }
+
+    /// The number of packet identifiers in use.
+    fn len(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// Returns true if all packet identifiers are in use.
+    fn is_exhausted(&self) -> bool {
+        self.in_use.len() >= u16::MAX as usize
+    }
+
+    /// Allocate the next free packet identifier for the value built by `value`,
+    /// or return `None` if all packet identifiers are in use.
+    fn allocate(&mut self, value: impl FnOnce(u16) -> T) -> Option<u16> {
+        if self.is_exhausted() {
+            return None;
+        }
+
+        while self.in_use.contains_key(&self.next_packet_id) {
+            self.advance();
+        }
+
+        let packet_id = self.next_packet_id;
+        self.advance();
+
+        self.in_use.insert(packet_id, (self.next_sequence, value(packet_id)));
+        self.next_sequence += 1;
+
+        Some(packet_id)
+    }
+
+    fn advance(&mut self) {
+        // Handle u16 wraparound, 0 is an invalid packet ID
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+    }
+
+    fn get_mut(&mut self, packet_id: u16) -> Option<&mut T> {
+        self.in_use.get_mut(&packet_id).map(|(_, value)| value)
+    }
+
+    /// Free the packet identifier, returning the value which used it.
+    fn release(&mut self, packet_id: u16) -> Option<T> {
+        self.in_use.remove(&packet_id).map(|(_, value)| value)
+    }
+
+    /// The packet identifiers in use and their values, in allocation order.
+    fn in_order(&self) -> Vec<(u16, &T)> {
+        let mut in_use: Vec<_> = self.in_use.iter().collect();
+        in_use.sort_by_key(|(_, (sequence, _))| *sequence);
+
+        in_use.into_iter().map(|(packet_id, (_, value))| (*packet_id, value)).collect()
+    }
+}
+
+/// An outgoing QoS 1 or 2 publish which isn't fully acknowledged by the client yet.
+#[derive(Debug)]
+enum OutgoingPublish {
+    /// Sent to the client with the given QoS, waiting for a PUBACK or PUBREC.
+    Published(StoredPublish, QoS),
+    /// PUBREL sent to the client, waiting for a PUBCOMP.
+    Released,
+}
+
 #[derive(Debug)]
 struct Session {
-    #[allow(unused)]
     pub protocol_version: ProtocolVersion,
     // pub subscriptions: HashSet<SubscriptionTopic>,
     // pub shared_subscriptions: HashSet<SubscriptionTopic>,
@@ -55,54 +415,85 @@ struct Session {
     // Used to unsubscribe from topics
     subscription_tokens: Vec<(TopicFilter, u64)>,
 
-    // Keep track of outgoing packets with QoS 1 or 2
-    outgoing_packets: Vec<PublishPacket>,
+    // Keep track of outgoing packets with QoS 1 or 2 by their packet ID,
+    // until the client acknowledged them.
+    outgoing_publishes: PacketIdAllocator<OutgoingPublish>,
 
     // Keep track of outgoing PublishReceived packets.
-    // TODO(bschwind) - Consider a HashSet if order isn't important.
-    outgoing_publish_receives: Vec<u16>,
+    outgoing_publish_receives: HashSet<u16>,
 
-    // Keep track of outgoing PublishReleased packets.
-    // TODO(bschwind) - Consider a HashSet if order isn't important.
-    outgoing_publish_released: Vec<u16>,
+    // Outgoing packets which are not sent yet, because the client's receive
+    // maximum is reached or the client is offline. Bounded by the broker config.
+    pending_publishes: VecDeque<(StoredPublish, QoS)>,
 
-    packet_counter: u16,
+    // The total payload size of `pending_publishes` in bytes.
+    pending_publish_bytes: usize,
 
     session_expiry_interval: Option<Duration>,
 
     will: Option<FinalWill>,
+
+    // Only valid for the current connection, reset when the client reconnects.
+    topic_aliases: OutgoingTopicAliases,
+
+    // The number of QoS 1 and 2 packets the client is willing to process concurrently.
+    receive_maximum: u16,
+
+    // The largest packet in bytes the client is willing to accept.
+    maximum_packet_size: Option<u32>,
+
+    // When the client disconnected, if it is currently offline.
+    disconnected_at: Option<Instant>,
+
+    // Whether reason strings and user properties may be sent on all packets.
+    request_problem_information: bool,
 }
 
 impl Session {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         protocol_version: ProtocolVersion,
         will: Option<FinalWill>,
         session_expiry_interval: Option<Duration>,
+        topic_alias_maximum: u16,
+        receive_maximum: u16,
+        maximum_packet_size: Option<u32>,
+        request_problem_information: bool,
         client_sender: Sender<ClientMessage>,
     ) -> Self {
         Self {
             protocol_version,
             // subscriptions: HashSet::new(),
             // shared_subscriptions: HashSet::new(),
-            /// Tx handle for a connected client
+            // Tx handle for a connected client
             client_sender: Some(client_sender),
             subscription_tokens: Vec::new(),
-            outgoing_packets: Vec::new(),
-            outgoing_publish_receives: Vec::new(),
-            outgoing_publish_released: Vec::new(),
-            packet_counter: 1,
+            outgoing_publishes: PacketIdAllocator::new(),
+            outgoing_publish_receives: HashSet::new(),
+            pending_publishes: VecDeque::new(),
+            pending_publish_bytes: 0,
             session_expiry_interval,
             will,
+            topic_aliases: OutgoingTopicAliases::new(topic_alias_maximum),
+            receive_maximum,
+            maximum_packet_size,
+            disconnected_at: None,
+            request_problem_information,
         }
     }
 
     /// Turn self, an existing Session, into a new session suitable
     /// for a client which just connected.
+    #[allow(clippy::too_many_arguments)]
     pub fn into_new_session(
         self,
         protocol_version: ProtocolVersion,
         will: Option<FinalWill>,
         session_expiry_interval: Option<Duration>,
+        topic_alias_maximum: u16,
+        receive_maximum: u16,
+        maximum_packet_size: Option<u32>,
+        request_problem_information: bool,
         client_sender: Sender<ClientMessage>,
     ) -> Self {
         Self {
@@ -110,79 +501,266 @@ impl Session {
             client_sender: Some(client_sender),
             session_expiry_interval,
             will,
+            topic_aliases: OutgoingTopicAliases::new(topic_alias_maximum),
+            receive_maximum,
+            maximum_packet_size,
+            disconnected_at: None,
+            request_problem_information,
             ..self
         }
     }
 
+    /// Store an outgoing publish until it is acknowledged, returning its packet ID,
+    /// or `None` if all packet IDs are in use.
     pub fn store_outgoing_publish(
         &mut self,
-        mut publish: PublishPacket,
+        publish: StoredPublish,
         subscriber_qos: QoS,
-    ) -> u16 {
+    ) -> Option<u16> {
         assert!(subscriber_qos == QoS::AtLeastOnce || subscriber_qos == QoS::ExactlyOnce);
 
-        // TODO(bschwind) - Prevent using packet IDs which already exist in `outgoing_packets`
-        let packet_id = self.packet_counter;
-        publish.packet_id = Some(self.packet_counter);
-        publish.qos = subscriber_qos;
-        publish.is_duplicate = false;
-        self.packet_counter = self.packet_counter.wrapping_add(1);
+        self.outgoing_publishes.allocate(|_| OutgoingPublish::Published(publish, subscriber_qos))
+    }
+
+    /// Send a publish packet to the client with the given QoS. Packets with QoS 1 or 2
+    /// are queued until the client's receive maximum allows sending them, QoS 0 packets
+    /// are only queued for offline clients if `BrokerConfig::queue_qos0_messages` is set.
+    /// Returns false if the packet was rejected because the queue is full.
+    async fn send_publish(
+        &mut self,
+        publish: StoredPublish,
+        qos: QoS,
+        config: &BrokerConfig,
+    ) -> bool {
+        if !self.queues_publish(qos, config) {
+            self.transmit_publish(publish, qos).await;
+            return true;
+        }
 
-        // Handle u16 wraparound, 0 is an invalid packet ID
-        if self.packet_counter == 0 {
-            self.packet_counter = self.packet_counter.wrapping_add(1);
+        let payload_size = publish.packet().payload.len();
+
+        if self.pending_publishes_full(payload_size, config) {
+            // Expired messages are dropped before anything else.
+            self.drop_expired_pending_publishes();
+        }
+
+        if self.pending_publishes_full(payload_size, config) {
+            match config.queue_overflow_policy {
+                QueueOverflowPolicy::DropOldest => {
+                    while self.pending_publishes_full(payload_size, config) {
+                        match self.pop_pending_publish() {
+                            Some((dropped, _)) => {
+                                debug!("Queue full, dropping publish on {}", dropped.packet().topic)
+                            },
+                            None => {
+                                debug!(
+                                    "Dropping publish on {} larger than the queue",
+                                    publish.packet().topic
+                                );
+                                return true;
+                            },
+                        }
+                    }
+                },
+                QueueOverflowPolicy::DropNewest => {
+                    debug!("Queue full, dropping publish on {}", publish.packet().topic);
+                    return true;
+                },
+                QueueOverflowPolicy::Reject => {
+                    debug!("Queue full, rejecting publish on {}", publish.packet().topic);
+                    return false;
+                },
+            }
         }
 
-        self.outgoing_packets.push(publish);
+        self.pending_publish_bytes += payload_size;
+        self.pending_publishes.push_back((publish, qos));
+        self.send_pending_publishes().await;
 
-        packet_id
+        true
     }
 
-    pub fn remove_outgoing_publish(&mut self, packet_id: u16) {
-        if let Some(pos) = self.outgoing_packets.iter().position(|p| p.packet_id == Some(packet_id))
+    /// Returns true if a publish packet with the given QoS goes through the pending publishes.
+    fn queues_publish(&self, qos: QoS, config: &BrokerConfig) -> bool {
+        match qos {
+            QoS::AtMostOnce => self.client_sender.is_none() && config.queue_qos0_messages,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => true,
+        }
+    }
+
+    fn drop_expired_pending_publishes(&mut self) {
+        let now = Instant::now();
+        let mut dropped_bytes = 0;
+        self.pending_publishes.retain(|(publish, _)| {
+            let expired = publish.is_expired(now);
+            if expired {
+                dropped_bytes += publish.packet().payload.len();
+            }
+            !expired
+        });
+        self.pending_publish_bytes -= dropped_bytes;
+    }
+
+    /// Returns true if a packet with the given payload size doesn't fit into the pending publishes.
+    fn pending_publishes_full(&self, payload_size: usize, config: &BrokerConfig) -> bool {
+        self.pending_publishes.len() >= config.max_queued_messages
+            || self.pending_publish_bytes + payload_size > config.max_queued_bytes
+    }
+
+    fn pop_pending_publish(&mut self) -> Option<(StoredPublish, QoS)> {
+        let (publish, qos) = self.pending_publishes.pop_front()?;
+        self.pending_publish_bytes -= publish.packet().payload.len();
+
+        Some((publish, qos))
+    }
+
+    /// Send queued packets in order while the client is connected, the number of
+    /// QoS 1 and 2 packets in flight is below the client's receive maximum
+    /// and packet IDs are available.
+    async fn send_pending_publishes(&mut self) {
+        while self.client_sender.is_some()
+            && self.inflight_count() < self.receive_maximum as usize
+            && !self.outgoing_publishes.is_exhausted()
         {
-            let _packet = self.outgoing_packets.remove(pos);
+            match self.pop_pending_publish() {
+                Some((publish, qos)) => self.transmit_publish(publish, qos).await,
+                None => break,
+            }
+        }
+    }
+
+    /// Returns true if the packet would exceed the client's maximum packet size.
+    fn exceeds_maximum_packet_size(&self, packet: &EncodedPublish) -> bool {
+        match self.maximum_packet_size {
+            Some(maximum_packet_size) => {
+                packet.header.len() + packet.body.len() > maximum_packet_size as usize
+            },
+            None => false,
+        }
+    }
+
+    /// Send a publish packet to the client, unless its message expired. Packets
+    /// with QoS 1 or 2 are stored until they are acknowledged by the client.
+    async fn transmit_publish(&mut self, publish: StoredPublish, qos: QoS) {
+        let now = Instant::now();
+
+        if publish.is_expired(now) {
+            debug!("Discarding expired publish on {}", publish.packet().topic);
+            return;
+        }
+
+        let topic = publish.packet().topic.clone();
+
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            _ => match self.store_outgoing_publish(publish.clone(), qos) {
+                Some(packet_id) => Some(packet_id),
+                None => {
+                    warn!("No packet ID available for publish on {}", topic);
+                    return;
+                },
+            },
+        };
+
+        // Stored packets keep their topic, aliases are only valid while the client is connected.
+        let topic_alias = match self.client_sender {
+            Some(_) => self.topic_aliases.get(&topic),
+            None => None,
+        };
+
+        let outgoing_packet =
+            publish.encode(self.protocol_version, qos, packet_id, false, topic_alias, now);
+
+        // The server must not send packets exceeding the client's maximum packet size,
+        // they are discarded as if they were delivered [MQTT-3.1.2-24, MQTT-3.1.2-25].
+        if self.exceeds_maximum_packet_size(&outgoing_packet) {
+            debug!("Discarding publish on {} exceeding the maximum packet size", topic);
+
+            if let Some(packet_id) = packet_id {
+                self.outgoing_publishes.release(packet_id);
+            }
+
+            return;
+        }
+
+        if topic_alias.is_some() {
+            self.topic_aliases.mark_used(&topic);
+        }
+
+        self.send(ClientMessage::Publish(outgoing_packet)).await;
+    }
+
+    /// The number of QoS 1 and 2 publish packets which are not yet fully
+    /// acknowledged by the client.
+    fn inflight_count(&self) -> usize {
+        self.outgoing_publishes.len()
+    }
+
+    /// Remove a publish acknowledged by a PUBACK, freeing its packet ID.
+    pub fn remove_outgoing_publish(&mut self, packet_id: u16) {
+        if let Some(OutgoingPublish::Published(..)) = self.outgoing_publishes.get_mut(packet_id) {
+            self.outgoing_publishes.release(packet_id);
         }
     }
 
+    /// Resend unacknowledged packets to a reconnected client in their original order,
+    /// followed by the packets queued while it was offline. Publishes which expired
+    /// in the meantime are discarded instead.
     async fn resend_packets(&mut self) {
-        if !self.outgoing_packets.is_empty() {
-            let message = ClientMessage::Packets(
-                self.outgoing_packets
-                    .iter()
-                    .cloned()
-                    .map(|mut p| {
-                        // Publish retries have their DUP flag set to true.
-                        p.is_duplicate = true;
-                        Packet::Publish(p)
-                    })
-                    .collect(),
-            );
-            self.send(message).await;
+        let now = Instant::now();
+
+        let expired: Vec<u16> = self
+            .outgoing_publishes
+            .in_order()
+            .into_iter()
+            .filter_map(|(packet_id, outgoing)| match outgoing {
+                OutgoingPublish::Published(publish, _) if publish.is_expired(now) => {
+                    Some(packet_id)
+                },
+                _ => None,
+            })
+            .collect();
+
+        for packet_id in expired {
+            debug!("Discarding expired publish with packet ID {}", packet_id);
+            self.outgoing_publishes.release(packet_id);
         }
 
-        if !self.outgoing_publish_released.is_empty() {
-            let message = ClientMessage::Packets(
-                self.outgoing_publish_released
-                    .iter()
-                    .cloned()
-                    .map(|p| {
-                        Packet::PublishRelease(PublishReleasePacket {
-                            packet_id: p,
+        let protocol_version = self.protocol_version;
+        let messages: Vec<ClientMessage> =
+            self.outgoing_publishes
+                .in_order()
+                .into_iter()
+                .map(|(packet_id, outgoing)| match outgoing {
+                    // Publish retries have their DUP flag set to true.
+                    OutgoingPublish::Published(publish, qos) => ClientMessage::Publish(
+                        publish.encode(protocol_version, *qos, Some(packet_id), true, None, now),
+                    ),
+                    OutgoingPublish::Released => {
+                        ClientMessage::Packet(Packet::PublishRelease(PublishReleasePacket {
+                            packet_id,
                             reason_code: PublishReleaseReason::Success,
                             reason_string: None,
                             user_properties: vec![],
-                        })
-                    })
-                    .collect(),
-            );
+                        }))
+                    },
+                })
+                .collect();
+
+        for message in messages {
             self.send(message).await;
         }
+
+        self.send_pending_publishes().await;
     }
 
     /// Attempt to send a `ClientMessage` to the client via the channel handle.
     /// If the channel is closed, the handle is removed from the session.
-    async fn send(&mut self, message: ClientMessage) {
+    async fn send(&mut self, mut message: ClientMessage) {
+        if !self.request_problem_information {
+            strip_problem_information(&mut message);
+        }
+
         if let Some(ref client_sender) = self.client_sender {
             if client_sender.send(message).await.is_err() {
                 warn!("Failed to send message to client. Dropping sender");
@@ -196,77 +774,357 @@ impl Session {
 struct SessionSubscription {
     client_id: String,
     maximum_qos: QoS,
+    /// The full `$share/{group_name}/{filter}` topic filter if this subscription
+    /// is part of a shared subscription group.
+    share_group: Option<TopicFilter>,
+    /// The identifier sent along with the SUBSCRIBE packet, which is
+    /// attached to every message delivered through this subscription.
+    subscription_identifier: Option<SubscriptionIdentifier>,
+    /// Don't deliver messages published by the subscribing client itself.
+    no_local: bool,
+    /// Keep the retain flag of messages as published instead of clearing it.
+    retain_as_published: bool,
 }
 
-#[derive(Debug)]
-pub enum WillDisconnectLogic {
-    Send,
-    DoNotSend,
+/// The key by which `SessionSubscription`s matching a published topic are grouped,
+/// each group receives a single copy of the message.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum SubscriptionGroup<'a> {
+    /// All non-shared subscriptions of a client.
+    Client(&'a str),
+    /// All members of a shared subscription group.
+    Shared(&'a TopicFilter),
 }
 
-/// Unique identifier for a connection
-pub type ConnectionId = u64;
-
-/// Client ID
-pub type ClientId = String;
+/// Decides which member of a shared subscription group receives a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SharedSubscriptionStrategy {
+    /// Deliver to each member of the group in turn.
+    #[default]
+    RoundRobin,
+    /// Deliver to a randomly chosen member.
+    Random,
+    /// Deliver all messages of a publishing client to the same member.
+    StickyByClient,
+    /// Deliver to the member with the fewest unacknowledged QoS 1 and 2 messages.
+    LeastInflight,
+}
 
-#[derive(Debug)]
-pub enum BrokerMessage {
-    Connect(ConnectionId, Box<ConnectPacket>, Sender<ClientMessage>),
-    Disconnect(ConnectionId, String, WillDisconnectLogic),
-    Authenticate(ConnectionId, ClientId, AuthenticatePacket),
-    Publish(ConnectionId, ClientId, Box<PublishPacket>),
-    PublishAck(ConnectionId, ClientId, PublishAckPacket), // TODO - This can be handled by the client task
-    PublishRelease(ConnectionId, ClientId, PublishReleasePacket), // TODO - This can be handled by the client task
-    PublishReceived(ConnectionId, ClientId, PublishReceivedPacket),
-    PublishComplete(ConnectionId, ClientId, PublishCompletePacket),
-    PublishFinalWill(ConnectionId, ClientId, FinalWill),
-    Subscribe(ConnectionId, ClientId, SubscribePacket), // TODO - replace string client_id with int
-    Unsubscribe(ConnectionId, ClientId, UnsubscribePacket), // TODO - replace string client_id with int
+/// Decides what happens to a message for a client whose message queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOverflowPolicy {
+    /// Drop the oldest queued messages to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Drop the new message for this client and report `QuotaExceeded` to its publisher.
+    /// Other subscribers still receive the message.
+    Reject,
 }
 
-pub struct Broker<A = Noop> {
-    /// A map of client IDs to unauthenticated connections. Once a client passes
-    /// authentication, the connection is promoted to a session.
-    unauthenticated_connections: HashMap<ConnectionId, UnauthenticatedConnection>,
-    sessions: HashMap<String, Session>,
-    sender: Sender<BrokerMessage>,
-    receiver: Receiver<BrokerMessage>,
-    subscriptions: SubscriptionTree<SessionSubscription>,
-    plugin: A,
+/// Configuration options for the broker.
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    pub shared_subscription_strategy: SharedSubscriptionStrategy,
+    /// The number of QoS 2 publish packets a client may have in flight
+    /// towards the broker. Advertised to clients in the CONNACK packet.
+    pub receive_maximum: u16,
+    /// The largest packet in bytes the broker accepts from a client.
+    /// Advertised to clients in the CONNACK packet.
+    pub maximum_packet_size: u32,
+    /// How strictly packets received from clients are validated. With `DecodeMode::Strict`,
+    /// repeated or disallowed properties and invalid fixed header flags are protocol errors,
+    /// strings must not contain null characters, and a will's payload must match its
+    /// payload format indicator.
+    pub decode_mode: DecodeMode,
+    /// The maximum number of messages queued for a client which is
+    /// offline or has reached its receive maximum.
+    pub max_queued_messages: usize,
+    /// The maximum total payload size in bytes of the messages queued for a client.
+    pub max_queued_bytes: usize,
+    pub queue_overflow_policy: QueueOverflowPolicy,
+    /// Queue QoS 0 messages for offline clients instead of discarding them.
+    pub queue_qos0_messages: bool,
+    /// Clients requesting response information get `{prefix}/{client_id}` assigned as
+    /// their namespace for response topics. Other clients can't subscribe to it.
+    ///
+    /// Wildcards in the first topic level don't match prefixes starting with `$`. Any other
+    /// prefix makes the broker refuse every `#` and `+/...` subscription which could match
+    /// the response topics, so regular wildcard subscribers need a `$` prefix.
+    pub response_topic_prefix: Option<String>,
+    /// Redirect all connecting clients to another server.
+    pub redirect: Option<Redirect>,
+    /// Reject publishes whose payload format indicator marks the payload as UTF-8,
+    /// but which aren't valid UTF-8, with the `PayloadFormatInvalid` reason code.
+    pub validate_payload_format: bool,
 }
 
-impl Default for Broker {
+impl Default for BrokerConfig {
     fn default() -> Self {
-        Broker::<Noop>::new()
+        Self {
+            shared_subscription_strategy: SharedSubscriptionStrategy::default(),
+            receive_maximum: u16::MAX,
+            maximum_packet_size: 1024 * 1024,
+            decode_mode: DecodeMode::Lenient,
+            max_queued_messages: 1000,
+            max_queued_bytes: 16 * 1024 * 1024,
+            queue_overflow_policy: QueueOverflowPolicy::default(),
+            queue_qos0_messages: false,
+            response_topic_prefix: Some("$response".to_string()),
+            redirect: None,
+            validate_payload_format: true,
+        }
     }
 }
 
-impl<A: Plugin> Broker<A> {
-    /// Construct a new Broker.
-    pub fn new() -> Broker {
-        let (sender, receiver) = mpsc::channel(100);
+/// Points clients to another server, e.g. to drain a broker for maintenance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Whether the client should use the other server permanently.
+    pub permanent: bool,
+    /// The other server to connect to, like `mqtt.example.com:1883`.
+    pub server_reference: String,
+}
 
-        Broker {
-            unauthenticated_connections: HashMap::new(),
-            sessions: HashMap::new(),
-            sender,
-            receiver,
-            subscriptions: SubscriptionTree::new(),
-            plugin: Noop,
+impl Redirect {
+    fn connect_reason(&self) -> ConnectReason {
+        if self.permanent {
+            ConnectReason::ServerMoved
+        } else {
+            ConnectReason::UseAnotherServer
         }
     }
 
-    /// Construct a new Broker.
-    pub fn with_plugin(plugin: A) -> Broker<A> {
-        let (sender, receiver) = mpsc::channel(100);
+    fn disconnect_packet(&self) -> DisconnectPacket {
+        let reason_code = if self.permanent {
+            DisconnectReason::ServerMoved
+        } else {
+            DisconnectReason::UseAnotherServer
+        };
 
-        Broker {
-            unauthenticated_connections: HashMap::new(),
-            sessions: HashMap::new(),
-            sender,
+        DisconnectPacket {
+            server_reference: Some(ServerReference(self.server_reference.clone())),
+            ..reason_code.into()
+        }
+    }
+}
+
+/// A CONNACK packet refusing the connection.
+pub(crate) fn failed_connect_ack(
+    reason_code: ConnectReason,
+    reason_string: Option<String>,
+    server_reference: Option<ServerReference>,
+) -> ConnectAckPacket {
+    ConnectAckPacket {
+        // Variable header
+        session_present: false,
+        reason_code,
+
+        // Properties
+        session_expiry_interval: None,
+        receive_maximum: None,
+        maximum_qos: None,
+        retain_available: None,
+        maximum_packet_size: None,
+        assigned_client_identifier: None,
+        topic_alias_maximum: None,
+        reason_string: reason_string.map(ReasonString),
+        user_properties: Vec::with_capacity(0),
+        wildcard_subscription_available: None,
+        subscription_identifiers_available: None,
+        shared_subscription_available: None,
+        server_keep_alive: None,
+        response_information: None,
+        server_reference,
+        authentication_method: None,
+        authentication_data: None,
+    }
+}
+
+/// Refuse a connection with a CONNACK packet carrying the reason code, followed by a DISCONNECT.
+async fn refuse_connection(
+    client_sender: &Sender<ClientMessage>,
+    client_id: &str,
+    reason_code: ConnectReason,
+    reason_string: Option<String>,
+) {
+    info!("Authentification reason code for client ID {} is {:?}", client_id, reason_code);
+
+    let connect_ack = failed_connect_ack(reason_code, reason_string, None);
+
+    // Ignore send errors because the client could already be disconnected and the rx
+    // handle of this channel is dropped. The disconnection is handled by a
+    // `BrokerMessage::Disconnect`.
+    // No DISCONNECT follows, a server must not send one before a successful CONNACK
+    // [MQTT-3.2.2-6]. The client task closes the connection after a failed CONNACK.
+    debug!("Sending CONNACK to client ID {} with reason code {:?}", client_id, reason_code);
+    client_sender.send(ClientMessage::Packet(Packet::ConnectAck(connect_ack))).await.ok();
+}
+
+/// Refuse a connection by pointing the client to another server.
+async fn redirect_connection(
+    client_sender: &Sender<ClientMessage>,
+    client_id: &str,
+    redirect: &Redirect,
+) {
+    info!("Redirecting client ID {} to {}", client_id, redirect.server_reference);
+
+    let server_reference = ServerReference(redirect.server_reference.clone());
+    let connect_ack = failed_connect_ack(redirect.connect_reason(), None, Some(server_reference));
+
+    // Ignore send errors, the client might already be disconnected.
+    client_sender.send(ClientMessage::Packet(Packet::ConnectAck(connect_ack))).await.ok();
+    client_sender.send(ClientMessage::Disconnect(redirect.disconnect_packet())).await.ok();
+}
+
+/// Returns true if `topic_filter` could match topics in the response topic namespace
+/// of a client other than `client_id`, see `BrokerConfig::response_topic_prefix`.
+fn matches_foreign_response_topics(
+    topic_filter: &TopicFilter,
+    response_topic_prefix: &str,
+    client_id: &str,
+) -> bool {
+    let mut filter_levels = topic_filter.levels();
+
+    for (index, prefix_level) in response_topic_prefix.split(TOPIC_SEPARATOR).enumerate() {
+        // Wildcards in the first level don't match topics with a leading dollar.
+        let dollar_prefix = index == 0 && prefix_level.starts_with('$');
+
+        match filter_levels.next() {
+            Some(TopicLevel::Concrete(level)) if level == prefix_level => {},
+            Some(TopicLevel::SingleLevelWildcard) if !dollar_prefix => {},
+            Some(TopicLevel::MultiLevelWildcard) => return !dollar_prefix,
+            _ => return false,
+        }
+    }
+
+    match filter_levels.next() {
+        Some(TopicLevel::Concrete(level)) => level != client_id,
+        Some(TopicLevel::SingleLevelWildcard) | Some(TopicLevel::MultiLevelWildcard) => true,
+        None => false,
+    }
+}
+
+#[derive(Debug)]
+pub enum WillDisconnectLogic {
+    Send,
+    DoNotSend,
+}
+
+/// Reason string for publishes rejected by `QueueOverflowPolicy::Reject`.
+const QUEUE_FULL_REASON: &str = "The message queue of a subscriber is full";
+
+/// Reason string for publishes rejected by `BrokerConfig::validate_payload_format`.
+const INVALID_PAYLOAD_FORMAT_REASON: &str = "The payload is not valid UTF-8";
+
+/// Sleep until the given deadline, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum DeadlineKind {
+    WillDelay,
+    SessionExpiry,
+}
+
+/// A point in time at which something has to happen to the session of a
+/// disconnected client, unless the client reconnected in the meantime.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Deadline {
+    at: Instant,
+    kind: DeadlineKind,
+    client_id: ClientId,
+    /// When the client disconnected, to tell apart deadlines of earlier disconnects.
+    disconnected_at: Instant,
+}
+
+impl Deadline {
+    /// Returns false if the client reconnected since, or its session ended.
+    fn is_pending(&self, sessions: &HashMap<ClientId, Session>) -> bool {
+        sessions
+            .get(&self.client_id)
+            .is_some_and(|session| session.disconnected_at == Some(self.disconnected_at))
+    }
+}
+
+/// Unique identifier for a connection
+pub type ConnectionId = u64;
+
+/// Client ID
+pub type ClientId = String;
+
+#[derive(Debug)]
+pub enum BrokerMessage {
+    Connect(ConnectionId, Box<ConnectPacket>, Sender<ClientMessage>),
+    Disconnect(ConnectionId, String, WillDisconnectLogic),
+    Authenticate(ConnectionId, ClientId, AuthenticatePacket),
+    Publish(ConnectionId, ClientId, Box<PublishPacket>),
+    PublishAck(ConnectionId, ClientId, PublishAckPacket), // TODO - This can be handled by the client task
+    PublishRelease(ConnectionId, ClientId, PublishReleasePacket), // TODO - This can be handled by the client task
+    PublishReceived(ConnectionId, ClientId, PublishReceivedPacket),
+    PublishComplete(ConnectionId, ClientId, PublishCompletePacket),
+    Subscribe(ConnectionId, ClientId, SubscribePacket), // TODO - replace string client_id with int
+    Unsubscribe(ConnectionId, ClientId, UnsubscribePacket), // TODO - replace string client_id with int
+    /// Redirect new connections to another server and disconnect all connected
+    /// clients with a reference to it, or stop redirecting with `None`.
+    Redirect(Option<Redirect>),
+}
+
+pub struct Broker<A = Noop> {
+    /// A map of client IDs to unauthenticated connections. Once a client passes
+    /// authentication, the connection is promoted to a session.
+    unauthenticated_connections: HashMap<ConnectionId, UnauthenticatedConnection>,
+    sessions: HashMap<String, Session>,
+    sender: Sender<BrokerMessage>,
+    receiver: Receiver<BrokerMessage>,
+    subscriptions: SubscriptionTree<SessionSubscription>,
+    /// The last retained message published to each topic.
+    retained_messages: RetainedTree<StoredPublish>,
+    /// The next member index for each shared subscription group, used by
+    /// `SharedSubscriptionStrategy::RoundRobin`.
+    shared_subscription_cursors: HashMap<TopicFilter, usize>,
+    /// Pending will and session expiry deadlines of disconnected clients.
+    deadlines: BinaryHeap<Reverse<Deadline>>,
+    config: BrokerConfig,
+    plugin: A,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Broker::<Noop>::new()
+    }
+}
+
+impl<A: Plugin> Broker<A> {
+    /// Construct a new Broker.
+    pub fn new() -> Broker {
+        Broker::with_plugin(Noop)
+    }
+
+    /// Construct a new Broker.
+    pub fn with_plugin(plugin: A) -> Broker<A> {
+        Broker::with_plugin_and_config(plugin, BrokerConfig::default())
+    }
+
+    /// Construct a new Broker with the given configuration.
+    pub fn with_plugin_and_config(plugin: A, config: BrokerConfig) -> Broker<A> {
+        let (sender, receiver) = mpsc::channel(100);
+
+        Broker {
+            unauthenticated_connections: HashMap::new(),
+            sessions: HashMap::new(),
+            sender,
             receiver,
             subscriptions: SubscriptionTree::new(),
+            retained_messages: RetainedTree::new(),
+            shared_subscription_cursors: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+            config,
             plugin,
         }
     }
@@ -280,17 +1138,18 @@ impl<A: Plugin> Broker<A> {
         client_id: &str,
         new_client_clean_start: bool,
     ) -> Option<Session> {
-        let existing_session = if let Some(existing_session) = self.sessions.remove(client_id) {
+        let existing_session = if let Some(mut existing_session) = self.sessions.remove(client_id) {
             if let Some(client_sender) = &existing_session.client_sender {
-                if let Err(e) = client_sender
-                    .try_send(ClientMessage::Disconnect(DisconnectReason::SessionTakenOver))
-                {
+                if let Err(e) = client_sender.try_send(ClientMessage::disconnect(
+                    DisconnectReason::SessionTakenOver,
+                    "Another connection took over the session",
+                )) {
                     warn!("Failed to send disconnect packet to taken-over session - {:?}", e);
                 }
             }
 
             // Publish the session's will if required.
-            if let Some(will) = &existing_session.will {
+            if let Some(will) = existing_session.will.take() {
                 // If the Will Delay Interval of the existing Network Connection is 0 and
                 // there is a Will Message, it will be sent because the Network Connection
                 // is closed. If the Session Expiry Interval of the existing Network Connection
@@ -303,7 +1162,7 @@ impl<A: Plugin> Broker<A> {
                     || new_client_clean_start;
 
                 if should_send_will {
-                    self.publish_message(will.clone().into()).await;
+                    self.publish_message(client_id, will.into()).await;
                 }
             }
 
@@ -317,6 +1176,16 @@ impl<A: Plugin> Broker<A> {
         // If the Server accepts a connection with Clean Start set to 0 and the Server has Session State for the ClientID,
         // it MUST set Session Present to 1 in the CONNACK packet, otherwise it MUST set Session Present to 0 in the CONNACK packet.
         if new_client_clean_start {
+            // The existing session ends, unsubscribe it from all topics it subscribed to.
+            for (topic, token) in existing_session.into_iter().flat_map(|s| s.subscription_tokens) {
+                Self::remove_subscription(
+                    &mut self.subscriptions,
+                    &mut self.shared_subscription_cursors,
+                    &topic,
+                    token,
+                );
+            }
+
             None
         } else {
             existing_session
@@ -329,63 +1198,38 @@ impl<A: Plugin> Broker<A> {
         connect_packet: ConnectPacket,
         client_msg_sender: Sender<ClientMessage>,
     ) {
+        if let Some(redirect) = &self.config.redirect {
+            redirect_connection(&client_msg_sender, &connect_packet.client_id, redirect).await;
+            return;
+        }
+
+        // It is a Protocol Error to include the Receive Maximum value of 0 [MQTT-3.1.2.11.3].
+        if let Some(ReceiveMaximum(0)) = connect_packet.receive_maximum {
+            refuse_connection(
+                &client_msg_sender,
+                &connect_packet.client_id,
+                ConnectReason::ProtocolError,
+                Some("Receive Maximum must be greater than 0".to_string()),
+            )
+            .await;
+            return;
+        }
+
         debug!(
             "Trying to authenticate client {} (connection {})",
             connect_packet.client_id, connection_id
         );
         match self.plugin.on_connect(&connect_packet) {
-            AuthentificationResult::Reason(ConnectReason::Success) => {
+            AuthentificationResult::Reason(ConnectReason::Success, _) => {
                 info!("Authentification successful for client {}", connect_packet.client_id);
                 self.handle_authenticated_client(connect_packet, client_msg_sender).await;
             },
-            AuthentificationResult::Reason(reason_code) => {
-                info!(
-                    "Authentification reason code for client {} is {:?}",
-                    connect_packet.client_id, reason_code
-                );
-                let connect_ack = ConnectAckPacket {
-                    session_present: false,
-                    reason_code,
-                    session_expiry_interval: None,
-                    receive_maximum: None,
-                    maximum_qos: None,
-                    retain_available: None,
-                    maximum_packet_size: None,
-                    assigned_client_identifier: None,
-                    topic_alias_maximum: None,
-                    reason_string: None,
-                    user_properties: Vec::with_capacity(0),
-                    wildcard_subscription_available: None,
-                    subscription_identifiers_available: None,
-                    shared_subscription_available: None,
-                    server_keep_alive: None,
-                    response_information: None,
-                    server_reference: None,
-                    authentication_method: None,
-                    authentication_data: None,
-                };
-
-                // Send a disconnect packet to the client. Ignore send errors because
-                // the client could already be disconnected and the rx handle of this
-                // channel is dropped.
-                debug!(
-                    "Sending CONNACK to client ID {} with reason code {:?}",
-                    connect_packet.client_id, reason_code
-                );
-                client_msg_sender
-                    .send(ClientMessage::Packet(Packet::ConnectAck(connect_ack)))
-                    .await
-                    .ok();
-
-                debug!(
-                    "Sending DISCONNECT to client {} with disconnect reason code {:?}",
-                    connect_packet.client_id,
-                    DisconnectReason::NotAuthorized
-                );
-                client_msg_sender
-                    .send(ClientMessage::Disconnect(DisconnectReason::NotAuthorized))
-                    .await
-                    .ok();
+            AuthentificationResult::Reason(reason_code, reason_string) => {
+                let client_id = &connect_packet.client_id;
+                refuse_connection(&client_msg_sender, client_id, reason_code, reason_string).await;
+            },
+            AuthentificationResult::Redirect(redirect) => {
+                redirect_connection(&client_msg_sender, &connect_packet.client_id, &redirect).await;
             },
             AuthentificationResult::Packet(packet) => {
                 client_msg_sender
@@ -406,15 +1250,11 @@ impl<A: Plugin> Broker<A> {
         connect_packet: ConnectPacket,
         client_msg_sender: Sender<ClientMessage>,
     ) {
-        let mut takeover_session = self
+        let takeover_session = self
             .take_over_existing_client(&connect_packet.client_id, connect_packet.clean_start)
             .await;
         let session_present = takeover_session.is_some();
 
-        if let Some(existing_session) = &mut takeover_session {
-            existing_session.resend_packets().await;
-        }
-
         info!(
             "Client ID {} connected (Version: {:?})",
             connect_packet.client_id, connect_packet.protocol_version
@@ -430,6 +1270,27 @@ impl<A: Plugin> Broker<A> {
             },
             None => None,
         };
+        let topic_alias_maximum =
+            connect_packet.topic_alias_maximum.as_ref().map(|maximum| maximum.0).unwrap_or(0);
+        let receive_maximum =
+            connect_packet.receive_maximum.as_ref().map(|maximum| maximum.0).unwrap_or(u16::MAX);
+        let maximum_packet_size =
+            connect_packet.maximum_packet_size.as_ref().map(|maximum| maximum.0);
+        let request_problem_information = requests_problem_information(&connect_packet);
+        // The response information is only sent if the client requested it, and if its
+        // client ID can be used as a single topic level.
+        let response_information = match (
+            &self.config.response_topic_prefix,
+            &connect_packet.request_response_information,
+        ) {
+            (Some(prefix), Some(RequestResponseInformation(1)))
+                if !connect_packet.client_id.is_empty()
+                    && !connect_packet.client_id.contains(&['/', '+', '#'][..]) =>
+            {
+                Some(ResponseInformation(format!("{}/{}", prefix, connect_packet.client_id)))
+            },
+            _ => None,
+        };
         let session_expiry_duration = session_expiry_interval.map(|i| {
             let duration = Duration::from_secs(i.0 as u64);
             debug!(
@@ -454,21 +1315,21 @@ impl<A: Plugin> Broker<A> {
 
             // Properties
             session_expiry_interval,
-            receive_maximum: None,
+            receive_maximum: Some(ReceiveMaximum(self.config.receive_maximum)),
             maximum_qos: None,
-            retain_available: None,
-            maximum_packet_size: None,
+            retain_available: Some(RetainAvailable(1)),
+            maximum_packet_size: Some(MaximumPacketSize(self.config.maximum_packet_size)),
             assigned_client_identifier: Some(AssignedClientIdentifier(
                 connect_packet.client_id.clone(),
             )),
-            topic_alias_maximum: None,
+            topic_alias_maximum: Some(TopicAliasMaximum(TOPIC_ALIAS_MAXIMUM)),
             reason_string: None,
             user_properties: Vec::with_capacity(0),
             wildcard_subscription_available: None,
-            subscription_identifiers_available: None,
-            shared_subscription_available: None,
+            subscription_identifiers_available: Some(SubscriptionIdentifierAvailable(1)),
+            shared_subscription_available: Some(SharedSubscriptionAvailable(1)),
             server_keep_alive: None,
-            response_information: None,
+            response_information,
             server_reference: None,
             authentication_method: None,
             authentication_data: None,
@@ -484,6 +1345,10 @@ impl<A: Plugin> Broker<A> {
                 connect_packet.protocol_version,
                 connect_packet.will,
                 session_expiry_duration,
+                topic_alias_maximum,
+                receive_maximum,
+                maximum_packet_size,
+                request_problem_information,
                 client_msg_sender,
             );
 
@@ -495,6 +1360,10 @@ impl<A: Plugin> Broker<A> {
                 connect_packet.protocol_version,
                 connect_packet.will,
                 session_expiry_duration,
+                topic_alias_maximum,
+                receive_maximum,
+                maximum_packet_size,
+                request_problem_information,
                 client_msg_sender,
             )
         };
@@ -522,45 +1391,18 @@ impl<A: Plugin> Broker<A> {
         };
 
         match self.plugin.on_authenticate(&packet) {
-            AuthentificationResult::Reason(ConnectReason::Success) => {
+            AuthentificationResult::Reason(ConnectReason::Success, _) => {
                 let (client_id, UnauthenticatedConnection { client_sender, connect_packet }) =
                     entry.remove_entry();
                 info!("Authentification successful for client ID {}", client_id);
                 self.handle_authenticated_client(connect_packet, client_sender).await;
             },
-            AuthentificationResult::Reason(reason_code) => {
-                info!("Authentification result for client ID {} is {:?}", entry.key(), reason_code);
-                let connect_ack = ConnectAckPacket {
-                    // Variable header
-                    session_present: false,
-                    reason_code,
-
-                    // Properties
-                    session_expiry_interval: None,
-                    receive_maximum: None,
-                    maximum_qos: None,
-                    retain_available: None,
-                    maximum_packet_size: None,
-                    assigned_client_identifier: None,
-                    topic_alias_maximum: None,
-                    reason_string: None,
-                    user_properties: Vec::with_capacity(0),
-                    wildcard_subscription_available: None,
-                    subscription_identifiers_available: None,
-                    shared_subscription_available: None,
-                    server_keep_alive: None,
-                    response_information: None,
-                    server_reference: None,
-                    authentication_method: None,
-                    authentication_data: None,
-                };
-
-                // If the client disconnected in the meantime, the rx part of the client handle is dropped
-                // and a send attempt will fail. Ignore this error, because the disconnection is handled
-                // by a BrokerMessage::Disconnect.
-                let session = entry.get();
-                session.send(ClientMessage::Packet(Packet::ConnectAck(connect_ack))).await;
-                session.send(ClientMessage::Disconnect(DisconnectReason::NotAuthorized)).await;
+            AuthentificationResult::Reason(reason_code, reason_string) => {
+                let client_sender = &entry.get().client_sender;
+                refuse_connection(client_sender, &client_id, reason_code, reason_string).await;
+            },
+            AuthentificationResult::Redirect(redirect) => {
+                redirect_connection(&entry.get().client_sender, &client_id, &redirect).await;
             },
             AuthentificationResult::Packet(packet) => {
                 let session = entry.get();
@@ -583,10 +1425,65 @@ impl<A: Plugin> Broker<A> {
             return;
         }
 
+        let now = Instant::now();
+        // Expired retained messages are removed once a subscription comes across them.
+        let mut expired_retained_topics = vec![];
+
         let subscriptions = &mut self.subscriptions;
+        let shared_subscription_cursors = &mut self.shared_subscription_cursors;
+        let retained_messages = &self.retained_messages;
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
-            let plugin_ack = self.plugin.on_subscribe(&packet);
+            // It is a Protocol Error to set the No Local bit to 1 on a Shared Subscription [MQTT-3.8.3-4].
+            if packet
+                .subscription_topics
+                .iter()
+                .any(|topic| topic.no_local && topic.topic_filter.is_shared())
+            {
+                warn!("Client ID {} subscribed to a shared filter with no local", client_id);
+                let message = ClientMessage::disconnect(
+                    DisconnectReason::ProtocolError,
+                    "Shared subscriptions can't use the no local option",
+                );
+                session.send(message).await;
+                return;
+            }
+
+            let mut plugin_ack = self.plugin.on_subscribe(&packet);
+
+            // Response topics are private to the client they were assigned to.
+            if let Some(prefix) = &self.config.response_topic_prefix {
+                for (topic, reason_code) in
+                    packet.subscription_topics.iter().zip(&mut plugin_ack.reason_codes)
+                {
+                    if matches_foreign_response_topics(&topic.topic_filter, prefix, &client_id) {
+                        warn!(
+                            "Client ID {} is not allowed to subscribe to {}",
+                            client_id, topic.topic_filter
+                        );
+                        *reason_code = SubscribeAckReason::NotAuthorized;
+                        plugin_ack.reason_string.get_or_insert_with(|| {
+                            ReasonString(
+                                "Not allowed to subscribe to response topics of other clients"
+                                    .to_string(),
+                            )
+                        });
+                    }
+                }
+            }
+
+            // Remember which topic filters were already subscribed to before they
+            // get replaced, for `RetainHandling::SendAtSubscribeTimeIfNonexistent`.
+            let existing_subscriptions: Vec<bool> = packet
+                .subscription_topics
+                .iter()
+                .map(|topic| {
+                    session
+                        .subscription_tokens
+                        .iter()
+                        .any(|(session_topic, _)| *session_topic == topic.topic_filter)
+                })
+                .collect();
 
             // If a Server receives a SUBSCRIBE packet containing a Topic Filter that
             // is identical to a Non‑shared Subscription’s Topic Filter for the current
@@ -606,7 +1503,12 @@ impl<A: Plugin> Broker<A> {
                 // Unsubscribe the old session from all topics it subscribed to.
                 session.subscription_tokens.retain(|(session_topic, token)| {
                     if *session_topic == *topic {
-                        subscriptions.remove(session_topic, *token);
+                        Self::remove_subscription(
+                            subscriptions,
+                            shared_subscription_cursors,
+                            session_topic,
+                            *token,
+                        );
                         false
                     } else {
                         true
@@ -614,19 +1516,57 @@ impl<A: Plugin> Broker<A> {
                 });
             }
 
+            let mut retained_publishes = vec![];
+            let subscription_identifier = packet.subscription_identifier;
+
             // Iterate through each subscription, insert into the subscription tree,
             // and return the QoS that was granted.
             let granted_qos_values = packet
                 .subscription_topics
                 .into_iter()
                 .zip(plugin_ack.reason_codes)
-                .map(|(topic, plugin_reason)| match plugin_reason {
+                .zip(existing_subscriptions)
+                .map(|((topic, plugin_reason), subscription_existed)| match plugin_reason {
                     SubscribeAckReason::GrantedQoSZero
                     | SubscribeAckReason::GrantedQoSOne
                     | SubscribeAckReason::GrantedQoSTwo => {
+                        let send_retained = match topic.retain_handling {
+                            RetainHandling::SendAtSubscribeTime => true,
+                            RetainHandling::SendAtSubscribeTimeIfNonexistent => {
+                                !subscription_existed
+                            },
+                            RetainHandling::DoNotSend => false,
+                        };
+
+                        // Retained messages are never sent for shared subscriptions.
+                        if send_retained && !topic.topic_filter.is_shared() {
+                            for retained in retained_messages.matching_values(&topic.topic_filter) {
+                                if retained.is_expired(now) {
+                                    expired_retained_topics.push(retained.packet().topic.clone());
+                                    continue;
+                                }
+
+                                let qos = retained.packet().qos.min(topic.maximum_qos);
+                                let mut retained = retained.clone();
+                                retained.subscription_identifiers =
+                                    subscription_identifier.iter().cloned().collect();
+                                retained_publishes.push((retained, qos));
+                            }
+                        }
+
+                        let share_group = if topic.topic_filter.is_shared() {
+                            Some(topic.topic_filter.clone())
+                        } else {
+                            None
+                        };
+
                         let session_subscription = SessionSubscription {
                             client_id: client_id.clone(),
                             maximum_qos: topic.maximum_qos,
+                            share_group,
+                            subscription_identifier: subscription_identifier.clone(),
+                            no_local: topic.no_local,
+                            retain_as_published: topic.retain_as_published,
                         };
                         let token = subscriptions.insert(&topic.topic_filter, session_subscription);
 
@@ -645,6 +1585,14 @@ impl<A: Plugin> Broker<A> {
             };
 
             session.send(ClientMessage::Packet(Packet::SubscribeAck(subscribe_ack))).await;
+
+            for (publish, qos) in retained_publishes {
+                session.send_publish(publish, qos, &self.config).await;
+            }
+        }
+
+        for topic in expired_retained_topics {
+            self.retained_messages.remove(&topic);
         }
     }
 
@@ -663,13 +1611,19 @@ impl<A: Plugin> Broker<A> {
         }
 
         let subscriptions = &mut self.subscriptions;
+        let shared_subscription_cursors = &mut self.shared_subscription_cursors;
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
             for filter in &packet.topic_filters {
                 // Unsubscribe the old session from all topics it subscribed to.
                 session.subscription_tokens.retain(|(session_topic, token)| {
                     if *session_topic == *filter {
-                        subscriptions.remove(session_topic, *token);
+                        Self::remove_subscription(
+                            subscriptions,
+                            shared_subscription_cursors,
+                            session_topic,
+                            *token,
+                        );
                         false
                     } else {
                         true
@@ -685,7 +1639,12 @@ impl<A: Plugin> Broker<A> {
                         session.subscription_tokens.iter().position(|(topic, _)| filter == *topic)
                     {
                         let (topic, token) = session.subscription_tokens.remove(pos);
-                        subscriptions.remove(&topic, token);
+                        Self::remove_subscription(
+                            subscriptions,
+                            shared_subscription_cursors,
+                            &topic,
+                            token,
+                        );
                         UnsubscribeAckReason::Success
                     } else {
                         UnsubscribeAckReason::NoSubscriptionExisted
@@ -704,7 +1663,7 @@ impl<A: Plugin> Broker<A> {
         }
     }
 
-    fn handle_disconnect(
+    async fn handle_disconnect(
         &mut self,
         connection_id: ConnectionId,
         client_id: String,
@@ -719,96 +1678,290 @@ impl<A: Plugin> Broker<A> {
 
         self.plugin.on_disconnect(&client_id);
 
-        let mut disconnect_will = None;
-        let mut session_expiry_duration = None;
+        // Deadlines of earlier disconnects are only discarded once they are due. Drop them
+        // as soon as they make up most of the heap, so a client reconnecting over and over
+        // doesn't fill it up.
+        if self.deadlines.len() >= 4 * self.sessions.len() {
+            let sessions = &self.sessions;
+            self.deadlines.retain(|Reverse(deadline)| deadline.is_pending(sessions));
+        }
 
-        if let Entry::Occupied(mut session_entry) = self.sessions.entry(client_id.clone()) {
-            {
-                let session = session_entry.get_mut();
-                session.client_sender.take();
+        let session = match self.sessions.get_mut(&client_id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        session.client_sender.take();
+
+        if let WillDisconnectLogic::DoNotSend = will_disconnect_logic {
+            session.will = None;
+        }
+
+        let expiry_interval = match session.session_expiry_interval {
+            Some(expiry_interval) => expiry_interval,
+            None => {
+                self.end_session(&client_id).await;
+                return;
+            },
+        };
+
+        // The Will Message MUST be published after the Network Connection is subsequently
+        // closed and either the Will Delay Interval has elapsed or the Session ends, unless
+        // the Will Message has been deleted by the Server on receipt of a DISCONNECT packet
+        // with Reason Code 0x00 (Normal disconnection) or a new Network Connection for the
+        // ClientID is opened before the Will Delay Interval has elapsed.
+        let disconnected_at = Instant::now();
+        session.disconnected_at = Some(disconnected_at);
+
+        if let Some(will) = &session.will {
+            let will_delay = will.will_delay_duration().unwrap_or_else(|| Duration::from_secs(0));
+
+            if will_delay < expiry_interval {
+                self.deadlines.push(Reverse(Deadline {
+                    at: disconnected_at + will_delay,
+                    kind: DeadlineKind::WillDelay,
+                    client_id: client_id.clone(),
+                    disconnected_at,
+                }));
             }
+        }
 
-            if let Some(expiry_interval) = session_entry.get().session_expiry_interval {
-                // The Will Message MUST be published after the Network Connection is subsequently
-                // closed and either the Will Delay Interval has elapsed or the Session ends, unless
-                // the Will Message has been deleted by the Server on receipt of a DISCONNECT packet
-                // with Reason Code 0x00 (Normal disconnection) or a new Network Connection for the
-                // ClientID is opened before the Will Delay Interval has elapsed.
-                if let Some(will) = &session_entry.get().will {
-                    disconnect_will = Some(will.clone());
-                    session_expiry_duration = Some(expiry_interval);
-                }
+        // A session expiry interval of 0xFFFFFFFF means the session does not expire.
+        if expiry_interval < Duration::from_secs(u32::MAX as u64) {
+            self.deadlines.push(Reverse(Deadline {
+                at: disconnected_at + expiry_interval,
+                kind: DeadlineKind::SessionExpiry,
+                client_id,
+                disconnected_at,
+            }));
+        }
+    }
 
-            // TODO(bschwind) - Schedule the session to be deleted after the session expiry interval.
-            } else {
-                let session = session_entry.remove();
+    /// Remove a session and its subscriptions, publishing its will if it is still pending.
+    async fn end_session(&mut self, client_id: &str) {
+        if let Some(session) = self.sessions.remove(client_id) {
+            info!("Session of client ID {} ended", client_id);
 
-                // Unsubscribe the old session from all topics it subscribed to.
-                for (topic, token) in session.subscription_tokens {
-                    self.subscriptions.remove(&topic, token);
-                }
+            // Unsubscribe the old session from all topics it subscribed to.
+            for (topic, token) in session.subscription_tokens {
+                Self::remove_subscription(
+                    &mut self.subscriptions,
+                    &mut self.shared_subscription_cursors,
+                    &topic,
+                    token,
+                );
+            }
 
-                if let Some(will) = session.will {
-                    disconnect_will = Some(will);
-                }
+            if let Some(will) = session.will {
+                self.publish_message(client_id, will.into()).await;
             }
+        }
+    }
 
-            // Schedule the will to be sent
-            if let Some(will) = disconnect_will {
-                match will_disconnect_logic {
-                    WillDisconnectLogic::Send => {
-                        let will_send_delay_duration =
-                            session_expiry_duration.unwrap_or_else(|| Duration::from_secs(0)).min(
-                                will.will_delay_duration()
-                                    .unwrap_or_else(|| Duration::from_secs(0)),
-                            );
-                        let broker_sender = self.sender.clone();
-
-                        // Spawn a task that publishes the will after `will_send_delay_duration`
-                        tokio::spawn(async move {
-                            time::sleep(will_send_delay_duration).await;
-                            broker_sender
-                                .send(BrokerMessage::PublishFinalWill(
-                                    connection_id,
-                                    client_id,
-                                    will,
-                                ))
-                                .await
-                                .expect("Failed to send final will message to broker");
-                        });
-                    },
-                    WillDisconnectLogic::DoNotSend => {},
-                }
+    /// Handle all deadlines which have passed. Deadlines of clients which
+    /// reconnected in the meantime are discarded.
+    async fn handle_deadlines(&mut self) {
+        let now = Instant::now();
+
+        while let Some(Reverse(deadline)) = self.deadlines.peek() {
+            if deadline.at > now {
+                break;
+            }
+
+            let Reverse(deadline) = self.deadlines.pop().unwrap();
+
+            if !deadline.is_pending(&self.sessions) {
+                continue;
+            }
+
+            let session = self.sessions.get_mut(&deadline.client_id).unwrap();
+
+            match deadline.kind {
+                DeadlineKind::WillDelay => {
+                    if let Some(will) = session.will.take() {
+                        self.publish_message(&deadline.client_id, will.into()).await;
+                    }
+                },
+                DeadlineKind::SessionExpiry => {
+                    self.end_session(&deadline.client_id).await;
+                },
             }
         }
     }
 
-    async fn publish_message(&mut self, packet: PublishPacket) {
-        let topic = &packet.topic;
+    /// Store or clear the retained message for the packet's topic. A retained
+    /// publish with an empty payload removes the existing retained message.
+    fn retain_message(&mut self, publish: &StoredPublish) {
+        let topic = &publish.packet().topic;
+
+        if publish.packet().payload.is_empty() {
+            self.retained_messages.remove(topic);
+        } else {
+            self.retained_messages.insert(topic, publish.clone());
+        }
+    }
+
+    /// Remove a subscription from the tree. Once the last member of a shared subscription
+    /// group is gone, the group's round robin cursor is removed as well.
+    fn remove_subscription(
+        subscriptions: &mut SubscriptionTree<SessionSubscription>,
+        shared_subscription_cursors: &mut HashMap<TopicFilter, usize>,
+        topic_filter: &TopicFilter,
+        token: u64,
+    ) {
+        subscriptions.remove(topic_filter, token);
+
+        if topic_filter.is_shared()
+            && !subscriptions
+                .exact_subscribers(topic_filter)
+                .any(|subscription| subscription.share_group.as_ref() == Some(topic_filter))
+        {
+            shared_subscription_cursors.remove(topic_filter);
+        }
+    }
+
+    /// Pick the member of a shared subscription group which receives the next message.
+    /// Connected members are preferred over sessions whose client is offline.
+    fn select_shared_subscriber<'a>(
+        strategy: SharedSubscriptionStrategy,
+        cursors: &mut HashMap<TopicFilter, usize>,
+        sessions: &HashMap<String, Session>,
+        share_group: &TopicFilter,
+        members: Vec<&'a SessionSubscription>,
+        publisher_client_id: &str,
+    ) -> &'a SessionSubscription {
+        let connected_members: Vec<_> = members
+            .iter()
+            .copied()
+            .filter(|member| {
+                sessions
+                    .get(&member.client_id)
+                    .map(|session| session.client_sender.is_some())
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        let candidates = if connected_members.is_empty() { members } else { connected_members };
+
+        match strategy {
+            SharedSubscriptionStrategy::RoundRobin => {
+                let cursor = cursors.entry(share_group.clone()).or_insert(0);
+                let member = candidates[*cursor % candidates.len()];
+                *cursor = cursor.wrapping_add(1);
+                member
+            },
+            SharedSubscriptionStrategy::Random => {
+                candidates[rand::thread_rng().gen_range(0..candidates.len())]
+            },
+            SharedSubscriptionStrategy::StickyByClient => {
+                let mut hasher = DefaultHasher::new();
+                publisher_client_id.hash(&mut hasher);
+                candidates[(hasher.finish() % candidates.len() as u64) as usize]
+            },
+            SharedSubscriptionStrategy::LeastInflight => candidates
+                .into_iter()
+                .min_by_key(|member| {
+                    sessions
+                        .get(&member.client_id)
+                        .map(|session| session.inflight_count())
+                        .unwrap_or(usize::MAX)
+                })
+                .unwrap(),
+        }
+    }
+
+    /// Publish a message to all matching subscribers. Returns false if the message queue of
+    /// any of them rejected it, see `QueueOverflowPolicy::Reject`. The other subscribers
+    /// still receive the message.
+    async fn publish_message(
+        &mut self,
+        publisher_client_id: &str,
+        mut packet: PublishPacket,
+    ) -> bool {
+        // Subscription identifiers are only ever set by the server for each receiver.
+        packet.subscription_identifiers.clear();
+
+        // Decoded packets share the read buffer of their connection. Messages which might
+        // be retained or queued get their own copy, so they don't keep that buffer alive.
+        let may_be_kept =
+            packet.retain || packet.qos != QoS::AtMostOnce || self.config.queue_qos0_messages;
+        let publish = StoredPublish::new(if may_be_kept { packet.detached() } else { packet });
+
+        let topic = &publish.packet().topic;
         let sessions = &mut self.sessions;
 
-        for session_subscription in self.subscriptions.matching_subscribers(topic) {
-            if let Some(session) = sessions.get_mut(&session_subscription.client_id) {
-                let outgoing_packet_id = match session_subscription.maximum_qos {
-                    QoS::AtLeastOnce | QoS::ExactlyOnce => {
-                        Some(session.store_outgoing_publish(
-                            packet.clone(),
-                            session_subscription.maximum_qos,
-                        ))
-                    },
-                    _ => None,
-                };
+        // Overlapping subscriptions of a client are delivered together as a single message,
+        // while each shared subscription group receives the message only once.
+        let groups = self.subscriptions.grouped_matching_subscribers(topic, |subscription| {
+            match &subscription.share_group {
+                Some(share_group) => SubscriptionGroup::Shared(share_group),
+                None => SubscriptionGroup::Client(&subscription.client_id),
+            }
+        });
 
-                let outgoing_packet = PublishPacket {
-                    packet_id: outgoing_packet_id,
-                    qos: session_subscription.maximum_qos,
-                    is_duplicate: false,
-                    ..packet.clone()
-                };
+        let mut receivers = vec![];
+
+        for (group, mut subscriptions) in groups {
+            subscriptions.retain(|subscription| {
+                !(subscription.no_local && subscription.client_id == publisher_client_id)
+            });
+
+            if subscriptions.is_empty() {
+                continue;
+            }
+
+            match group {
+                SubscriptionGroup::Client(_) => receivers.push(subscriptions),
+                SubscriptionGroup::Shared(share_group) => {
+                    receivers.push(vec![Self::select_shared_subscriber(
+                        self.config.shared_subscription_strategy,
+                        &mut self.shared_subscription_cursors,
+                        sessions,
+                        share_group,
+                        subscriptions,
+                        publisher_client_id,
+                    )]);
+                },
+            }
+        }
+
+        let deliveries: Vec<_> = receivers
+            .into_iter()
+            .map(|subscriptions| {
+                // The message is delivered with the highest QoS granted by any of the
+                // matching subscriptions, carrying all of their identifiers.
+                let maximum_qos = subscriptions
+                    .iter()
+                    .map(|subscription| subscription.maximum_qos)
+                    .max()
+                    .unwrap_or(QoS::AtMostOnce);
+
+                let mut publish = publish.clone();
+                publish.subscription_identifiers = subscriptions
+                    .iter()
+                    .filter_map(|subscription| subscription.subscription_identifier.clone())
+                    .collect();
+                publish.retain &=
+                    subscriptions.iter().any(|subscription| subscription.retain_as_published);
 
-                session.send(ClientMessage::Packet(Packet::Publish(outgoing_packet))).await;
+                let qos = publish.packet().qos.min(maximum_qos);
+                (subscriptions[0].client_id.clone(), publish, qos)
+            })
+            .collect();
+
+        if publish.retain {
+            self.retain_message(&publish);
+        }
+
+        let mut accepted = true;
+
+        for (client_id, publish, qos) in deliveries {
+            if let Some(session) = self.sessions.get_mut(&client_id) {
+                accepted &= session.send_publish(publish, qos, &self.config).await;
             }
         }
+
+        accepted
     }
 
     async fn handle_publish(
@@ -826,51 +1979,116 @@ impl<A: Plugin> Broker<A> {
         }
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
-            match packet.qos {
-                QoS::AtMostOnce => {
-                    if self.plugin.on_publish_received_qos0(&packet) {
-                        self.publish_message(packet).await;
-                    }
-                },
-                QoS::AtLeastOnce => {
-                    let (publish, publish_ack) = self.plugin.on_publish_received_qos1(&packet);
-                    if let Some(publish_ack) = publish_ack {
+            if self.config.validate_payload_format && !packet.has_valid_payload_format() {
+                warn!("Rejecting publish with an invalid payload from client ID {}", client_id);
+                let reason_string = Some(ReasonString(INVALID_PAYLOAD_FORMAT_REASON.to_string()));
+
+                // QoS 0 publishes can't be answered, they are only discarded.
+                match (packet.qos, packet.packet_id) {
+                    (QoS::AtLeastOnce, Some(packet_id)) => {
+                        let publish_ack = PublishAckPacket {
+                            packet_id,
+                            reason_code: PublishAckReason::PayloadFormatInvalid,
+                            reason_string,
+                            user_properties: vec![],
+                        };
+                        session.send(ClientMessage::Packet(Packet::PublishAck(publish_ack))).await;
+                    },
+                    (QoS::ExactlyOnce, Some(packet_id)) => {
+                        let publish_recv = PublishReceivedPacket {
+                            packet_id,
+                            reason_code: PublishReceivedReason::PayloadFormatInvalid,
+                            reason_string,
+                            user_properties: vec![],
+                        };
+                        session
+                            .send(ClientMessage::Packet(Packet::PublishReceived(publish_recv)))
+                            .await;
+                    },
+                    _ => {},
+                }
+
+                return;
+            }
+
+            match packet.qos {
+                QoS::AtMostOnce => {
+                    if self.plugin.on_publish_received_qos0(&packet) {
+                        self.publish_message(&client_id, packet).await;
+                    }
+                },
+                QoS::AtLeastOnce => {
+                    let (publish, publish_ack) = self.plugin.on_publish_received_qos1(&packet);
+                    let accepted = !publish || self.publish_message(&client_id, packet).await;
+
+                    if let (Some(mut publish_ack), Some(session)) =
+                        (publish_ack, self.sessions.get_mut(&client_id))
+                    {
+                        if !accepted {
+                            publish_ack.reason_code = PublishAckReason::QuotaExceeded;
+                            publish_ack.reason_string =
+                                Some(ReasonString(QUEUE_FULL_REASON.to_string()));
+                        }
+
                         session.send(ClientMessage::Packet(Packet::PublishAck(publish_ack))).await;
-                    }
-                    if publish {
-                        self.publish_message(packet).await;
                     }
                 },
                 // For QoS2, ensure this packet isn't delivered twice. So if we have an outgoing
                 // publish receive with the same ID, just send the publish receive again but don't forward
                 // the message.
                 QoS::ExactlyOnce => {
+                    let is_new_packet = packet
+                        .packet_id
+                        .map(|id| !session.outgoing_publish_receives.contains(&id))
+                        .unwrap_or(true);
+
+                    // The client must not send more QoS 2 packets than the broker's
+                    // receive maximum before they are released.
+                    if is_new_packet
+                        && session.outgoing_publish_receives.len()
+                            >= self.config.receive_maximum as usize
+                    {
+                        warn!("Client ID {} exceeded the receive maximum", client_id);
+                        let message = ClientMessage::disconnect(
+                            DisconnectReason::ReceiveMaximumExceeded,
+                            "Too many unacknowledged QoS 2 publishes",
+                        );
+                        session.send(message).await;
+                        return;
+                    }
+
                     let (mut publish, publish_rec) = self.plugin.on_publish_received_qos2(&packet);
 
-                    if let Some(publish_recv) = publish_rec {
-                        let packet_id = publish_recv.packet_id;
-                        let is_dup = session.outgoing_publish_receives.contains(&packet_id);
+                    let is_dup = publish_rec.as_ref().is_some_and(|publish_recv| {
+                        session.outgoing_publish_receives.contains(&publish_recv.packet_id)
+                    });
 
-                        publish = publish && !is_dup;
+                    publish = publish && !is_dup;
 
-                        if !is_dup {
-                            session.outgoing_publish_receives.push(packet_id)
+                    let accepted = !publish || self.publish_message(&client_id, packet).await;
+
+                    if let (Some(mut publish_recv), Some(session)) =
+                        (publish_rec, self.sessions.get_mut(&client_id))
+                    {
+                        if !accepted {
+                            // The message is not forwarded, so the packet ID is not kept either.
+                            publish_recv.reason_code = PublishReceivedReason::QuotaExceeded;
+                            publish_recv.reason_string =
+                                Some(ReasonString(QUEUE_FULL_REASON.to_string()));
+                        } else if !is_dup {
+                            session.outgoing_publish_receives.insert(publish_recv.packet_id);
                         }
 
                         session
                             .send(ClientMessage::Packet(Packet::PublishReceived(publish_recv)))
                             .await;
                     }
-
-                    if publish {
-                        self.publish_message(packet).await;
-                    }
                 },
             }
         }
     }
 
-    fn handle_publish_ack(
+    async fn handle_publish_ack(
         &mut self,
         connection_id: ConnectionId,
         client_id: ClientId,
@@ -886,6 +2104,7 @@ impl<A: Plugin> Broker<A> {
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
             session.remove_outgoing_publish(packet.packet_id);
+            session.send_pending_publishes().await;
         }
     }
 
@@ -901,11 +2120,7 @@ impl<A: Plugin> Broker<A> {
         }
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
-            if let Some(pos) =
-                session.outgoing_publish_receives.iter().position(|x| *x == packet.packet_id)
-            {
-                session.outgoing_publish_receives.remove(pos);
-
+            if session.outgoing_publish_receives.remove(&packet.packet_id) {
                 let outgoing_packet = PublishCompletePacket {
                     packet_id: packet.packet_id,
                     reason_code: PublishCompleteReason::Success,
@@ -930,14 +2145,11 @@ impl<A: Plugin> Broker<A> {
         }
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
-            if let Some(pos) = session.outgoing_packets.iter().position(|p| {
-                p.qos == QoS::ExactlyOnce
-                    && p.packet_id.map(|id| id == packet.packet_id).unwrap_or(false)
-            }) {
-                // TODO(bschwind) - remove it here?
-                session.outgoing_packets.remove(pos);
+            let outgoing = session.outgoing_publishes.get_mut(packet.packet_id);
 
-                session.outgoing_publish_released.push(packet.packet_id);
+            if let Some(outgoing @ OutgoingPublish::Published(_, QoS::ExactlyOnce)) = outgoing {
+                // The packet ID stays in use until the PUBCOMP is received.
+                *outgoing = OutgoingPublish::Released;
 
                 let outgoing_packet = PublishReleasePacket {
                     packet_id: packet.packet_id,
@@ -951,7 +2163,7 @@ impl<A: Plugin> Broker<A> {
         }
     }
 
-    fn handle_publish_complete(
+    async fn handle_publish_complete(
         &mut self,
         connection_id: ConnectionId,
         client_id: ClientId,
@@ -963,49 +2175,54 @@ impl<A: Plugin> Broker<A> {
         }
 
         if let Some(session) = self.sessions.get_mut(&client_id) {
-            if let Some(pos) =
-                session.outgoing_publish_released.iter().position(|x| *x == packet.packet_id)
+            if let Some(OutgoingPublish::Released) =
+                session.outgoing_publishes.get_mut(packet.packet_id)
             {
-                session.outgoing_publish_released.remove(pos);
+                session.outgoing_publishes.release(packet.packet_id);
+                session.send_pending_publishes().await;
             }
         }
     }
 
-    async fn publish_final_will(
-        &mut self,
-        connection_id: ConnectionId,
-        client_id: ClientId,
-        final_will: FinalWill,
-    ) {
-        if self.unauthenticated_connections.contains_key(&connection_id) {
-            warn!(
-                "Discarding final will packet from unauthenticated client ID {} on connection {}",
-                client_id, connection_id
-            );
-            return;
-        }
+    async fn handle_redirect(&mut self, redirect: Option<Redirect>) {
+        if let Some(redirect) = &redirect {
+            info!("Redirecting all clients to {}", redirect.server_reference);
 
-        if let Some(session) = self.sessions.get_mut(&client_id) {
-            if session.client_sender.is_some() {
-                // They've reconnected, don't send out the will message.
-                self.publish_message(final_will.into()).await;
-            } else {
-                // They haven't reconnected, send out the will message.
+            for session in self.sessions.values_mut() {
+                session.send(ClientMessage::Disconnect(redirect.disconnect_packet())).await;
+            }
+
+            for connection in self.unauthenticated_connections.values() {
+                connection.send(ClientMessage::Disconnect(redirect.disconnect_packet())).await;
             }
         } else {
-            // No existing session, send out the will message.
-            self.publish_message(final_will.into()).await;
+            info!("No longer redirecting clients");
         }
+
+        self.config.redirect = redirect;
     }
 
     pub async fn run(mut self) {
-        while let Some(msg) = self.receiver.recv().await {
+        loop {
+            let next_deadline = self.deadlines.peek().map(|Reverse(deadline)| deadline.at);
+
+            let msg = tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = sleep_until(next_deadline) => {
+                    self.handle_deadlines().await;
+                    continue;
+                },
+            };
+
             match msg {
                 BrokerMessage::Connect(connection_id, connect_packet, client_msg_sender) => {
                     self.handle_new_client(connection_id, *connect_packet, client_msg_sender).await;
                 },
                 BrokerMessage::Disconnect(connection_id, client_id, will_disconnect_logic) => {
-                    self.handle_disconnect(connection_id, client_id, will_disconnect_logic);
+                    self.handle_disconnect(connection_id, client_id, will_disconnect_logic).await;
                 },
                 BrokerMessage::Authenticate(connection_id, client_id, packet) => {
                     self.handle_authenticate(connection_id, client_id, packet).await;
@@ -1020,7 +2237,7 @@ impl<A: Plugin> Broker<A> {
                     self.handle_publish(connection_id, client_id, *packet).await;
                 },
                 BrokerMessage::PublishAck(connection_id, client_id, packet) => {
-                    self.handle_publish_ack(connection_id, client_id, packet);
+                    self.handle_publish_ack(connection_id, client_id, packet).await;
                 },
                 BrokerMessage::PublishRelease(connection_id, client_id, packet) => {
                     self.handle_publish_release(connection_id, client_id, packet).await;
@@ -1029,10 +2246,10 @@ impl<A: Plugin> Broker<A> {
                     self.handle_publish_received(connection_id, client_id, packet).await;
                 },
                 BrokerMessage::PublishComplete(connection_id, client_id, packet) => {
-                    self.handle_publish_complete(connection_id, client_id, packet);
+                    self.handle_publish_complete(connection_id, client_id, packet).await;
                 },
-                BrokerMessage::PublishFinalWill(connection_id, client_id, final_will) => {
-                    self.publish_final_will(connection_id, client_id, final_will).await;
+                BrokerMessage::Redirect(redirect) => {
+                    self.handle_redirect(redirect).await;
                 },
             }
         }
@@ -1042,14 +2259,25 @@ impl<A: Plugin> Broker<A> {
 #[cfg(test)]
 mod tests {
     use crate::{
-        broker::{Broker, BrokerMessage},
-        client::ClientMessage,
+        broker::{
+            matches_foreign_response_topics, strip_problem_information, Broker, BrokerConfig,
+            BrokerMessage, OutgoingTopicAliases, PacketIdAllocator, QueueOverflowPolicy, Redirect,
+            SharedSubscriptionStrategy, StoredPublish, WillDisconnectLogic,
+        },
+        client::{ClientMessage, EncodedPublish, TOPIC_ALIAS_MAXIMUM},
         plugin::Noop,
     };
-    use mqtt_v5::types::{properties::*, ProtocolVersion, *};
+    use bytes::{Bytes, BytesMut};
+    use mqtt_v5::{
+        decoder::decode_mqtt,
+        topic::{Topic, TopicFilter},
+        types::{properties::*, ProtocolVersion, *},
+    };
+    use std::{cmp::Reverse, time::Duration};
     use tokio::{
-        runtime::Runtime,
-        sync::mpsc::{self, Sender},
+        runtime::{Builder, Runtime},
+        sync::mpsc::{self, Receiver, Sender},
+        time::{self, Instant},
     };
 
     async fn run_client(broker_tx: Sender<BrokerMessage>) {
@@ -1077,10 +2305,7 @@ mod tests {
             password: Some("test".into()),
         };
 
-        let _ = broker_tx
-            .send(BrokerMessage::Connect(0, Box::new(connect_packet), sender))
-            .await
-            .unwrap();
+        broker_tx.send(BrokerMessage::Connect(0, Box::new(connect_packet), sender)).await.unwrap();
 
         let resp = receiver.recv().await.unwrap();
 
@@ -1091,17 +2316,19 @@ mod tests {
                 reason_code: ConnectReason::Success,
 
                 session_expiry_interval: None,
-                receive_maximum: None,
+                receive_maximum: Some(ReceiveMaximum(65535)),
                 maximum_qos: None,
-                retain_available: None,
-                maximum_packet_size: None,
+                retain_available: Some(RetainAvailable(1)),
+                maximum_packet_size: Some(MaximumPacketSize(
+                    BrokerConfig::default().maximum_packet_size
+                )),
                 assigned_client_identifier: Some(AssignedClientIdentifier("TEST".to_string())),
-                topic_alias_maximum: None,
+                topic_alias_maximum: Some(TopicAliasMaximum(TOPIC_ALIAS_MAXIMUM)),
                 reason_string: None,
                 user_properties: vec![],
                 wildcard_subscription_available: None,
-                subscription_identifiers_available: None,
-                shared_subscription_available: None,
+                subscription_identifiers_available: Some(SubscriptionIdentifierAvailable(1)),
+                shared_subscription_available: Some(SharedSubscriptionAvailable(1)),
                 server_keep_alive: None,
                 response_information: None,
                 server_reference: None,
@@ -1110,7 +2337,7 @@ mod tests {
             }))
         );
 
-        let _ = broker_tx
+        broker_tx
             .send(BrokerMessage::Subscribe(
                 0,
                 "TEST".to_string(),
@@ -1143,6 +2370,1883 @@ mod tests {
         );
     }
 
+    fn connect_packet(client_id: &str) -> ConnectPacket {
+        ConnectPacket {
+            protocol_name: "MQTT".to_string(),
+            protocol_version: ProtocolVersion::V500,
+            clean_start: true,
+            keep_alive: 1000,
+
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            user_properties: vec![],
+            authentication_method: None,
+            authentication_data: None,
+
+            client_id: client_id.to_string(),
+            will: None,
+            user_name: Some("test".into()),
+            password: Some("test".into()),
+        }
+    }
+
+    async fn connect_client(
+        broker_tx: &Sender<BrokerMessage>,
+        connection_id: u64,
+        client_id: &str,
+    ) -> Receiver<ClientMessage> {
+        connect_client_with(broker_tx, connection_id, connect_packet(client_id)).await
+    }
+
+    async fn connect_client_with(
+        broker_tx: &Sender<BrokerMessage>,
+        connection_id: u64,
+        connect_packet: ConnectPacket,
+    ) -> Receiver<ClientMessage> {
+        let (sender, mut receiver) = mpsc::channel(5);
+
+        broker_tx
+            .send(BrokerMessage::Connect(connection_id, Box::new(connect_packet), sender))
+            .await
+            .unwrap();
+
+        match receiver.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::ConnectAck(ack)) => {
+                assert_eq!(ack.reason_code, ConnectReason::Success)
+            },
+            msg => panic!("Expected CONNACK, got {:?}", msg),
+        }
+
+        receiver
+    }
+
+    async fn subscribe(
+        broker_tx: &Sender<BrokerMessage>,
+        receiver: &mut Receiver<ClientMessage>,
+        connection_id: u64,
+        client_id: &str,
+        topic_filter: &str,
+        retain_handling: RetainHandling,
+    ) {
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: topic_filter.parse().unwrap(),
+            maximum_qos: QoS::AtMostOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling,
+        };
+
+        subscribe_with(broker_tx, receiver, connection_id, client_id, subscription_topic).await;
+    }
+
+    async fn subscribe_with(
+        broker_tx: &Sender<BrokerMessage>,
+        receiver: &mut Receiver<ClientMessage>,
+        connection_id: u64,
+        client_id: &str,
+        subscription_topic: SubscriptionTopic,
+    ) {
+        broker_tx
+            .send(BrokerMessage::Subscribe(
+                connection_id,
+                client_id.to_string(),
+                SubscribePacket {
+                    packet_id: 1,
+                    subscription_identifier: None,
+                    user_properties: vec![],
+                    subscription_topics: vec![subscription_topic],
+                },
+            ))
+            .await
+            .unwrap();
+
+        match receiver.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::SubscribeAck(_)) => {},
+            msg => panic!("Expected SUBACK, got {:?}", msg),
+        }
+    }
+
+    async fn publish(
+        broker_tx: &Sender<BrokerMessage>,
+        connection_id: u64,
+        client_id: &str,
+        topic: &str,
+        payload: &'static [u8],
+        retain: bool,
+    ) {
+        let packet = PublishPacket { retain, ..publish_packet(topic, payload) };
+
+        publish_with(broker_tx, connection_id, client_id, packet).await;
+    }
+
+    fn publish_packet(topic: &str, payload: &'static [u8]) -> PublishPacket {
+        PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+
+            topic: topic.parse().unwrap(),
+            packet_id: None,
+
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            subscription_identifiers: vec![],
+            content_type: None,
+
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    async fn publish_with(
+        broker_tx: &Sender<BrokerMessage>,
+        connection_id: u64,
+        client_id: &str,
+        packet: PublishPacket,
+    ) {
+        broker_tx
+            .send(BrokerMessage::Publish(connection_id, client_id.to_string(), Box::new(packet)))
+            .await
+            .unwrap();
+    }
+
+    fn decode_publish(
+        publish: &EncodedPublish,
+        protocol_version: ProtocolVersion,
+    ) -> PublishPacket {
+        let mut bytes = BytesMut::from(&publish.header[..]);
+        bytes.extend_from_slice(&publish.body);
+
+        match decode_mqtt(&mut bytes, protocol_version) {
+            Ok(Some(Packet::Publish(packet))) if bytes.is_empty() => packet,
+            result => panic!("Expected a single PUBLISH, got {:?}", result),
+        }
+    }
+
+    async fn expect_encoded_publish(receiver: &mut Receiver<ClientMessage>) -> EncodedPublish {
+        match receiver.recv().await.unwrap() {
+            ClientMessage::Publish(publish) => publish,
+            msg => panic!("Expected PUBLISH, got {:?}", msg),
+        }
+    }
+
+    async fn expect_publish(receiver: &mut Receiver<ClientMessage>) -> PublishPacket {
+        decode_publish(&expect_encoded_publish(receiver).await, ProtocolVersion::V500)
+    }
+
+    async fn run_retained_messages(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut subscriber = connect_client(&broker_tx, 1, "SUB").await;
+
+        publish(&broker_tx, 0, "PUB", "home/kitchen/temperature", b"21.5", true).await;
+
+        // A new subscription receives the retained message, with the retain flag set.
+        let filter = "home/+/temperature";
+        subscribe(
+            &broker_tx,
+            &mut subscriber,
+            1,
+            "SUB",
+            filter,
+            RetainHandling::SendAtSubscribeTime,
+        )
+        .await;
+        let retained = expect_publish(&mut subscriber).await;
+        assert!(retained.retain);
+        assert_eq!(retained.payload, Bytes::from_static(b"21.5"));
+
+        // The subscription already exists, so nothing is sent.
+        let handling = RetainHandling::SendAtSubscribeTimeIfNonexistent;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", filter, handling).await;
+        let handling = RetainHandling::DoNotSend;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", filter, handling).await;
+
+        // An empty retained payload is forwarded, and clears the retained message.
+        publish(&broker_tx, 0, "PUB", "home/kitchen/temperature", b"", true).await;
+        let cleared = expect_publish(&mut subscriber).await;
+        assert!(cleared.payload.is_empty());
+
+        let handling = RetainHandling::SendAtSubscribeTime;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "home/kitchen/humidity", handling).await;
+
+        publish(&broker_tx, 0, "PUB", "home/kitchen/humidity", b"40", false).await;
+        let live = expect_publish(&mut subscriber).await;
+        assert_eq!(live.payload, Bytes::from_static(b"40"));
+    }
+
+    async fn run_shared_subscriptions(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut member_a = connect_client(&broker_tx, 1, "A").await;
+        let mut member_b = connect_client(&broker_tx, 2, "B").await;
+        let mut observer = connect_client(&broker_tx, 3, "OBSERVER").await;
+
+        let filter = "$share/group/sensors/+";
+        let handling = RetainHandling::DoNotSend;
+        subscribe(&broker_tx, &mut member_a, 1, "A", filter, handling).await;
+        subscribe(&broker_tx, &mut member_b, 2, "B", filter, handling).await;
+        subscribe(&broker_tx, &mut observer, 3, "OBSERVER", "sensors/+", handling).await;
+
+        for payload in [b"1", b"2", b"3", b"4"] {
+            publish(&broker_tx, 0, "PUB", "sensors/temperature", payload, false).await;
+        }
+
+        // Group members take turns, non-shared subscriptions receive every message.
+        assert_eq!(expect_publish(&mut member_a).await.payload, Bytes::from_static(b"1"));
+        assert_eq!(expect_publish(&mut member_b).await.payload, Bytes::from_static(b"2"));
+        assert_eq!(expect_publish(&mut member_a).await.payload, Bytes::from_static(b"3"));
+        assert_eq!(expect_publish(&mut member_b).await.payload, Bytes::from_static(b"4"));
+
+        for payload in [b"1", b"2", b"3", b"4"] {
+            assert_eq!(expect_publish(&mut observer).await.payload, Bytes::from_static(payload));
+        }
+
+        // Nothing else is queued before the SUBACK.
+        subscribe(&broker_tx, &mut member_a, 1, "A", "other", handling).await;
+        subscribe(&broker_tx, &mut member_b, 2, "B", "other", handling).await;
+    }
+
+    async fn run_sticky_shared_subscriptions(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut member_a = connect_client(&broker_tx, 1, "A").await;
+        let mut member_b = connect_client(&broker_tx, 2, "B").await;
+
+        let filter = "$share/group/sensors/#";
+        let handling = RetainHandling::DoNotSend;
+        subscribe(&broker_tx, &mut member_a, 1, "A", filter, handling).await;
+        subscribe(&broker_tx, &mut member_b, 2, "B", filter, handling).await;
+
+        for payload in [b"1", b"2", b"3"] {
+            publish(&broker_tx, 0, "PUB", "sensors/temperature", payload, false).await;
+        }
+
+        // Wait until the broker handled every publish.
+        subscribe(&broker_tx, &mut publisher, 0, "PUB", "other", handling).await;
+
+        let mut counts = [0, 0];
+        for (count, receiver) in counts.iter_mut().zip([&mut member_a, &mut member_b]) {
+            while let Ok(ClientMessage::Publish(_)) = receiver.try_recv() {
+                *count += 1;
+            }
+        }
+
+        counts.sort_unstable();
+        assert_eq!(counts, [0, 3]);
+    }
+
+    async fn run_outgoing_topic_aliases(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = || ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(60)),
+            topic_alias_maximum: Some(TopicAliasMaximum(2)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect()).await;
+        let handling = RetainHandling::DoNotSend;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "sensors/+", handling).await;
+
+        // (topic, expected alias, expect the full topic)
+        let expected = [
+            ("sensors/a", 1, true),
+            ("sensors/a", 1, false),
+            ("sensors/b", 2, true),
+            ("sensors/b", 2, false),
+            // Evicts "sensors/a", which was used least recently.
+            ("sensors/c", 1, true),
+            ("sensors/b", 2, false),
+            // Evicts "sensors/c".
+            ("sensors/a", 1, true),
+        ];
+
+        for (topic, alias, full_topic) in expected {
+            publish(&broker_tx, 0, "PUB", topic, b"1", false).await;
+            let packet = expect_publish(&mut subscriber).await;
+
+            assert_eq!(packet.topic_alias, Some(TopicAlias(alias)));
+            if full_topic {
+                assert_eq!(packet.topic.topic_name(), topic);
+            } else {
+                assert!(packet.topic.is_empty());
+            }
+        }
+
+        // Aliases start over when the client reconnects.
+        let mut subscriber = connect_client_with(&broker_tx, 2, subscriber_connect()).await;
+        publish(&broker_tx, 0, "PUB", "sensors/b", b"1", false).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.topic_alias, Some(TopicAlias(1)));
+        assert_eq!(packet.topic.topic_name(), "sensors/b");
+    }
+
+    async fn run_receive_maximum(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect =
+            ConnectPacket { receive_maximum: Some(ReceiveMaximum(1)), ..connect_packet("SUB") };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect).await;
+
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: "sensors/+".parse().unwrap(),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        subscribe_with(&broker_tx, &mut subscriber, 1, "SUB", subscription_topic).await;
+
+        for (packet_id, payload) in [(1, b"1"), (2, b"2"), (3, b"3")] {
+            let packet = PublishPacket {
+                qos: QoS::AtLeastOnce,
+                packet_id: Some(packet_id),
+                ..publish_packet("sensors/temperature", payload)
+            };
+            publish_with(&broker_tx, 0, "PUB", packet).await;
+
+            match publisher.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::PublishAck(_)) => {},
+                msg => panic!("Expected PUBACK, got {:?}", msg),
+            }
+        }
+
+        // Only one packet is in flight at a time, the next one is sent after the PUBACK.
+        for payload in [b"1", b"2", b"3"] {
+            let packet = expect_publish(&mut subscriber).await;
+            assert_eq!(packet.payload, Bytes::from_static(payload));
+            assert!(subscriber.try_recv().is_err());
+
+            let publish_ack = PublishAckPacket {
+                packet_id: packet.packet_id.unwrap(),
+                reason_code: PublishAckReason::Success,
+                reason_string: None,
+                user_properties: vec![],
+            };
+            broker_tx
+                .send(BrokerMessage::PublishAck(1, "SUB".to_string(), publish_ack))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn run_receive_maximum_zero(broker_tx: Sender<BrokerMessage>) {
+        let connect_packet =
+            ConnectPacket { receive_maximum: Some(ReceiveMaximum(0)), ..connect_packet("SUB") };
+        let (sender, mut receiver) = mpsc::channel(5);
+        let connect = BrokerMessage::Connect(0, Box::new(connect_packet), sender);
+        broker_tx.send(connect).await.unwrap();
+
+        match receiver.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::ConnectAck(ack)) => {
+                assert_eq!(ack.reason_code, ConnectReason::ProtocolError)
+            },
+            msg => panic!("Expected CONNACK, got {:?}", msg),
+        }
+
+        // The failed CONNACK is the last packet, no DISCONNECT follows.
+        assert!(receiver.recv().await.is_none());
+    }
+
+    async fn run_receive_maximum_exceeded(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let packet = |packet_id| PublishPacket {
+            qos: QoS::ExactlyOnce,
+            packet_id: Some(packet_id),
+            ..publish_packet("sensors/temperature", b"1")
+        };
+
+        publish_with(&broker_tx, 0, "PUB", packet(1)).await;
+        match publisher.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::PublishReceived(_)) => {},
+            msg => panic!("Expected PUBREC, got {:?}", msg),
+        }
+
+        // A retransmission of the unreleased packet is fine.
+        publish_with(&broker_tx, 0, "PUB", PublishPacket { is_duplicate: true, ..packet(1) }).await;
+        match publisher.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::PublishReceived(_)) => {},
+            msg => panic!("Expected PUBREC, got {:?}", msg),
+        }
+
+        publish_with(&broker_tx, 0, "PUB", packet(2)).await;
+        match publisher.recv().await.unwrap() {
+            ClientMessage::Disconnect(packet) => {
+                assert_eq!(packet.reason_code, DisconnectReason::ReceiveMaximumExceeded)
+            },
+            msg => panic!("Expected DISCONNECT, got {:?}", msg),
+        }
+    }
+
+    async fn run_maximum_packet_size(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = ConnectPacket {
+            maximum_packet_size: Some(MaximumPacketSize(32)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect).await;
+        let handling = RetainHandling::DoNotSend;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "sensors/+", handling).await;
+
+        // Too large for the subscriber, so it is discarded.
+        publish(&broker_tx, 0, "PUB", "sensors/temperature", &[0; 32], false).await;
+        publish(&broker_tx, 0, "PUB", "sensors/temperature", b"21.5", false).await;
+
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.payload, Bytes::from_static(b"21.5"));
+    }
+
+    async fn run_maximum_packet_size_boundary(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = ConnectPacket {
+            maximum_packet_size: Some(MaximumPacketSize(130)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect).await;
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: "t".parse().unwrap(),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        subscribe_with(&broker_tx, &mut subscriber, 1, "SUB", subscription_topic).await;
+
+        // The remaining length needs a second byte from a payload of 122 bytes on,
+        // once the packet ID is added.
+        let mut delivered = vec![];
+
+        for payload_size in 118..=126 {
+            let packet = PublishPacket {
+                qos: QoS::AtLeastOnce,
+                packet_id: Some(1),
+                payload: Bytes::from(vec![0; payload_size]),
+                ..publish_packet("t", b"")
+            };
+            publish_with(&broker_tx, 0, "PUB", packet).await;
+            publisher.recv().await.unwrap();
+
+            publish(&broker_tx, 0, "PUB", "t", b"end", false).await;
+
+            loop {
+                let packet = expect_publish(&mut subscriber).await;
+
+                if packet.payload == Bytes::from_static(b"end") {
+                    break;
+                }
+
+                assert!(packet.encoded_size(ProtocolVersion::V500) <= 130);
+                delivered.push(packet.payload.len());
+            }
+        }
+
+        assert_eq!(delivered, vec![118, 119, 120, 121]);
+    }
+
+    #[test]
+    fn outgoing_topic_aliases_eviction_test() {
+        let mut aliases = OutgoingTopicAliases::new(2);
+        let topic = |name: &str| name.parse::<Topic>().unwrap();
+
+        assert_eq!(aliases.get(&topic("a")), Some((1, false)));
+        aliases.mark_used(&topic("a"));
+        assert_eq!(aliases.get(&topic("b")), Some((2, false)));
+        aliases.mark_used(&topic("b"));
+        aliases.mark_used(&topic("a"));
+
+        // "b" is the least recently used topic, so its alias is reassigned.
+        assert_eq!(aliases.get(&topic("c")), Some((2, false)));
+        aliases.mark_used(&topic("c"));
+        assert_eq!(aliases.get(&topic("a")), Some((1, true)));
+        assert_eq!(aliases.get(&topic("b")), Some((1, false)));
+        assert_eq!(aliases.last_used.len(), 2);
+
+        assert_eq!(OutgoingTopicAliases::new(0).get(&topic("a")), None);
+    }
+
+    #[test]
+    fn reconnect_deadlines_test() {
+        let mut broker = Broker::<Noop>::new();
+        let runtime = Runtime::new().unwrap();
+
+        let flapping_connect = || ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(3600)),
+            ..connect_packet("FLAPPING")
+        };
+
+        runtime.block_on(async {
+            for connection_id in 0..100 {
+                let (sender, _receiver) = mpsc::channel(5);
+                broker.handle_new_client(connection_id, flapping_connect(), sender).await;
+                let will = WillDisconnectLogic::Send;
+                broker.handle_disconnect(connection_id, "FLAPPING".to_string(), will).await;
+
+                // Deadlines of earlier disconnects don't pile up until they are due.
+                assert!(broker.deadlines.len() <= 4);
+            }
+
+            // Sessions which never expire don't get a deadline.
+            let connect = ConnectPacket {
+                session_expiry_interval: Some(SessionExpiryInterval(u32::MAX)),
+                ..connect_packet("FOREVER")
+            };
+            let (sender, _receiver) = mpsc::channel(5);
+            broker.handle_new_client(100, connect, sender).await;
+            let will = WillDisconnectLogic::Send;
+            broker.handle_disconnect(100, "FOREVER".to_string(), will).await;
+
+            assert!(broker
+                .deadlines
+                .iter()
+                .all(|Reverse(deadline)| deadline.client_id == "FLAPPING"));
+        });
+    }
+
+    #[test]
+    fn shared_subscription_cursor_cleanup_test() {
+        let mut broker = Broker::<Noop>::new();
+        let runtime = Runtime::new().unwrap();
+
+        let share_group: TopicFilter = "$share/group/news".parse().unwrap();
+        let subscribe_packet = || SubscribePacket {
+            packet_id: 1,
+            subscription_identifier: None,
+            user_properties: vec![],
+            subscription_topics: vec![SubscriptionTopic {
+                topic_filter: share_group.clone(),
+                maximum_qos: QoS::AtMostOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::DoNotSend,
+            }],
+        };
+
+        runtime.block_on(async {
+            let (first_sender, _first_receiver) = mpsc::channel(5);
+            broker.handle_new_client(0, connect_packet("FIRST"), first_sender).await;
+            broker.handle_subscribe(0, "FIRST".to_string(), subscribe_packet()).await;
+
+            let (second_sender, _second_receiver) = mpsc::channel(5);
+            broker.handle_new_client(1, connect_packet("SECOND"), second_sender).await;
+            broker.handle_subscribe(1, "SECOND".to_string(), subscribe_packet()).await;
+
+            broker.handle_publish(0, "FIRST".to_string(), publish_packet("news", b"1")).await;
+            assert!(broker.shared_subscription_cursors.contains_key(&share_group));
+
+            // The group keeps its cursor while it has members left.
+            let unsubscribe = UnsubscribePacket {
+                packet_id: 2,
+                user_properties: vec![],
+                topic_filters: vec![share_group.clone()],
+            };
+            broker.handle_unsubscribe(0, "FIRST".to_string(), unsubscribe).await;
+            assert!(broker.shared_subscription_cursors.contains_key(&share_group));
+
+            // The session of the last member ends with its connection.
+            let will = WillDisconnectLogic::Send;
+            broker.handle_disconnect(1, "SECOND".to_string(), will).await;
+            assert!(broker.shared_subscription_cursors.is_empty());
+        });
+    }
+
+    #[test]
+    fn stored_publish_expiry_test() {
+        let packet = PublishPacket {
+            message_expiry_interval: Some(MessageExpiryInterval(10)),
+            ..publish_packet("alarms/fire", b"1")
+        };
+        let publish = StoredPublish::new(packet);
+        let received_at = publish.expires_at.unwrap() - Duration::from_secs(10);
+
+        let now = received_at + Duration::from_millis(3500);
+        assert!(!publish.is_expired(now));
+        assert_eq!(publish.message_expiry_interval(now), Some(MessageExpiryInterval(7)));
+
+        assert!(publish.is_expired(received_at + Duration::from_secs(10)));
+
+        // Messages without an expiry interval never expire.
+        let publish = StoredPublish::new(publish_packet("alarms/fire", b"1"));
+        assert!(!publish.is_expired(received_at + Duration::from_secs(u32::MAX as u64)));
+        assert_eq!(publish.message_expiry_interval(received_at), None);
+    }
+
+    #[test]
+    fn stored_publish_encoding_test() {
+        let packet = PublishPacket {
+            payload_format_indicator: Some(PayloadFormatIndicator(1)),
+            response_topic: Some(ResponseTopic("alarms/ack".into())),
+            correlation_data: Some(CorrelationData(Bytes::from_static(b"id"))),
+            user_properties: vec![UserProperty("key".into(), "value".into())],
+            content_type: Some(ContentType("text/plain".into())),
+            ..publish_packet("alarms/fire", b"fire")
+        };
+        let mut publish = StoredPublish::new(packet.clone());
+        publish.retain = true;
+        publish.subscription_identifiers = vec![SubscriptionIdentifier(VariableByteInt(5))];
+        let now = Instant::now();
+
+        let encoded = publish.encode(
+            ProtocolVersion::V500,
+            QoS::AtLeastOnce,
+            Some(7),
+            true,
+            Some((3, false)),
+            now,
+        );
+        let expected = PublishPacket {
+            is_duplicate: true,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            packet_id: Some(7),
+            topic_alias: Some(TopicAlias(3)),
+            subscription_identifiers: vec![SubscriptionIdentifier(VariableByteInt(5))],
+            ..packet.clone()
+        };
+        assert_eq!(decode_publish(&encoded, ProtocolVersion::V500), expected);
+
+        // Once the client knows the alias, the topic is left out. The body is shared.
+        let aliased = publish.encode(
+            ProtocolVersion::V500,
+            QoS::AtMostOnce,
+            None,
+            false,
+            Some((3, true)),
+            now,
+        );
+        let expected = PublishPacket {
+            qos: QoS::AtMostOnce,
+            topic: Topic::empty(),
+            packet_id: None,
+            topic_alias: Some(TopicAlias(3)),
+            subscription_identifiers: vec![SubscriptionIdentifier(VariableByteInt(5))],
+            retain: true,
+            ..packet.clone()
+        };
+        assert_eq!(decode_publish(&aliased, ProtocolVersion::V500), expected);
+        assert_eq!(aliased.body.as_ptr(), encoded.body.as_ptr());
+
+        // MQTT 3 packets have no properties.
+        let legacy = publish.encode(ProtocolVersion::V311, QoS::AtMostOnce, None, false, None, now);
+        let legacy_packet = decode_publish(&legacy, ProtocolVersion::V311);
+        assert_eq!(legacy_packet.topic, packet.topic);
+        assert!(legacy_packet.retain);
+        assert_eq!(legacy_packet.payload, packet.payload);
+        assert_eq!(legacy_packet.content_type, None);
+        assert_ne!(legacy.body.as_ptr(), encoded.body.as_ptr());
+
+        // MQTT 3.1 shares the encoding of MQTT 3.1.1.
+        let v310 = publish.encode(ProtocolVersion::V310, QoS::AtMostOnce, None, false, None, now);
+        assert_eq!(v310, legacy);
+        assert_eq!(v310.body.as_ptr(), legacy.body.as_ptr());
+    }
+
+    async fn run_message_expiry(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = || ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(60)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect()).await;
+
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: "alarms/+".parse().unwrap(),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        subscribe_with(&broker_tx, &mut subscriber, 1, "SUB", subscription_topic).await;
+
+        let disconnect = BrokerMessage::Disconnect(1, "SUB".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        // Queued for the offline subscriber, the first one expires before it reconnects.
+        for (packet_id, expiry_interval) in [(1, Some(1)), (2, None)] {
+            let packet = PublishPacket {
+                qos: QoS::AtLeastOnce,
+                packet_id: Some(packet_id),
+                message_expiry_interval: expiry_interval.map(MessageExpiryInterval),
+                ..publish_packet("alarms/fire", b"1")
+            };
+            publish_with(&broker_tx, 0, "PUB", packet).await;
+
+            match publisher.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::PublishAck(_)) => {},
+                msg => panic!("Expected PUBACK, got {:?}", msg),
+            }
+        }
+
+        let packet = PublishPacket {
+            qos: QoS::AtLeastOnce,
+            packet_id: Some(3),
+            retain: true,
+            message_expiry_interval: Some(MessageExpiryInterval(60)),
+            ..publish_packet("alarms/smoke", b"1")
+        };
+        publish_with(&broker_tx, 0, "PUB", packet).await;
+
+        match publisher.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::PublishAck(_)) => {},
+            msg => panic!("Expected PUBACK, got {:?}", msg),
+        }
+
+        // The clock is paused, so it only advances once the broker handled all messages.
+        time::sleep(Duration::from_millis(1100)).await;
+
+        let mut subscriber = connect_client_with(&broker_tx, 2, subscriber_connect()).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.packet_id, Some(1));
+        assert_eq!(packet.message_expiry_interval, None);
+
+        // Queued messages are delivered with their remaining expiry interval.
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.topic.topic_name(), "alarms/smoke");
+        assert_eq!(packet.message_expiry_interval, Some(MessageExpiryInterval(59)));
+
+        // So are retained messages.
+        let handling = RetainHandling::SendAtSubscribeTime;
+        subscribe(&broker_tx, &mut subscriber, 2, "SUB", "alarms/smoke", handling).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.message_expiry_interval, Some(MessageExpiryInterval(59)));
+    }
+
+    async fn run_subscription_identifiers(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut subscriber = connect_client(&broker_tx, 1, "SUB").await;
+
+        publish(&broker_tx, 0, "PUB", "home/kitchen", b"retained", true).await;
+
+        let subscription_topic = |topic_filter: &str| SubscriptionTopic {
+            topic_filter: topic_filter.parse().unwrap(),
+            maximum_qos: QoS::AtMostOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendAtSubscribeTime,
+        };
+
+        broker_tx
+            .send(BrokerMessage::Subscribe(
+                1,
+                "SUB".to_string(),
+                SubscribePacket {
+                    packet_id: 1,
+                    subscription_identifier: Some(SubscriptionIdentifier(VariableByteInt(5))),
+                    user_properties: vec![],
+                    subscription_topics: vec![subscription_topic("home/+")],
+                },
+            ))
+            .await
+            .unwrap();
+
+        match subscriber.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::SubscribeAck(_)) => {},
+            msg => panic!("Expected SUBACK, got {:?}", msg),
+        }
+
+        // Retained messages carry the identifier of the subscription they were sent for.
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.payload, Bytes::from_static(b"retained"));
+        assert_eq!(
+            packet.subscription_identifiers,
+            vec![SubscriptionIdentifier(VariableByteInt(5))]
+        );
+
+        subscribe_with(&broker_tx, &mut subscriber, 1, "SUB", subscription_topic("office/#")).await;
+
+        publish(&broker_tx, 0, "PUB", "home/garden", b"1", false).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(
+            packet.subscription_identifiers,
+            vec![SubscriptionIdentifier(VariableByteInt(5))]
+        );
+
+        // Identifiers sent by the publisher are not forwarded.
+        let packet = PublishPacket {
+            subscription_identifiers: vec![SubscriptionIdentifier(VariableByteInt(7))],
+            ..publish_packet("office/printer", b"2")
+        };
+        publish_with(&broker_tx, 0, "PUB", packet).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert!(packet.subscription_identifiers.is_empty());
+    }
+
+    #[test]
+    fn subscription_identifiers_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_subscription_identifiers(sender));
+    }
+
+    async fn run_subscription_options(broker_tx: Sender<BrokerMessage>) {
+        let mut bridge = connect_client(&broker_tx, 0, "BRIDGE").await;
+        let mut subscriber = connect_client(&broker_tx, 1, "SUB").await;
+
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: "sensors/#".parse().unwrap(),
+            maximum_qos: QoS::AtMostOnce,
+            no_local: true,
+            retain_as_published: true,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        subscribe_with(&broker_tx, &mut bridge, 0, "BRIDGE", subscription_topic).await;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "sensors/#", RetainHandling::DoNotSend)
+            .await;
+
+        // The bridge doesn't receive its own message, the retain flag is cleared by default.
+        publish(&broker_tx, 0, "BRIDGE", "sensors/humidity", b"40", true).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.payload, Bytes::from_static(b"40"));
+        assert!(!packet.retain);
+
+        // Messages from other clients reach the bridge with the retain flag as published.
+        publish(&broker_tx, 1, "SUB", "sensors/temperature", b"21", true).await;
+        let packet = expect_publish(&mut bridge).await;
+        assert_eq!(packet.payload, Bytes::from_static(b"21"));
+        assert!(packet.retain);
+
+        let no_publish = time::timeout(Duration::from_millis(100), bridge.recv()).await;
+        assert!(no_publish.is_err(), "Expected no PUBLISH, got {:?}", no_publish);
+    }
+
+    #[test]
+    fn subscription_options_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_subscription_options(sender));
+    }
+
+    async fn run_shared_no_local(broker_tx: Sender<BrokerMessage>) {
+        let mut subscriber = connect_client(&broker_tx, 0, "SUB").await;
+
+        broker_tx
+            .send(BrokerMessage::Subscribe(
+                0,
+                "SUB".to_string(),
+                SubscribePacket {
+                    packet_id: 1,
+                    subscription_identifier: None,
+                    user_properties: vec![],
+                    subscription_topics: vec![SubscriptionTopic {
+                        topic_filter: "$share/group_a/sensors/#".parse().unwrap(),
+                        maximum_qos: QoS::AtMostOnce,
+                        no_local: true,
+                        retain_as_published: false,
+                        retain_handling: RetainHandling::DoNotSend,
+                    }],
+                },
+            ))
+            .await
+            .unwrap();
+
+        match subscriber.recv().await.unwrap() {
+            ClientMessage::Disconnect(packet) => {
+                assert_eq!(packet.reason_code, DisconnectReason::ProtocolError)
+            },
+            msg => panic!("Expected DISCONNECT, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn shared_no_local_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_shared_no_local(sender));
+    }
+
+    async fn run_overlapping_subscriptions(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut subscriber = connect_client(&broker_tx, 1, "SUB").await;
+
+        for (packet_id, topic_filter, maximum_qos) in
+            [(1, "home/#", QoS::AtMostOnce), (2, "home/+/temp", QoS::AtLeastOnce)]
+        {
+            broker_tx
+                .send(BrokerMessage::Subscribe(
+                    1,
+                    "SUB".to_string(),
+                    SubscribePacket {
+                        packet_id,
+                        subscription_identifier: Some(SubscriptionIdentifier(VariableByteInt(
+                            packet_id as u32,
+                        ))),
+                        user_properties: vec![],
+                        subscription_topics: vec![SubscriptionTopic {
+                            topic_filter: topic_filter.parse().unwrap(),
+                            maximum_qos,
+                            no_local: false,
+                            retain_as_published: false,
+                            retain_handling: RetainHandling::DoNotSend,
+                        }],
+                    },
+                ))
+                .await
+                .unwrap();
+
+            match subscriber.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::SubscribeAck(_)) => {},
+                msg => panic!("Expected SUBACK, got {:?}", msg),
+            }
+        }
+
+        // A single copy at the highest granted QoS, with all matching identifiers.
+        let packet = PublishPacket {
+            qos: QoS::ExactlyOnce,
+            packet_id: Some(1),
+            ..publish_packet("home/kitchen/temp", b"21")
+        };
+        publish_with(&broker_tx, 0, "PUB", packet).await;
+
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.qos, QoS::AtLeastOnce);
+
+        let mut identifiers = packet.subscription_identifiers;
+        identifiers.sort_by_key(|SubscriptionIdentifier(VariableByteInt(id))| *id);
+        assert_eq!(
+            identifiers,
+            vec![
+                SubscriptionIdentifier(VariableByteInt(1)),
+                SubscriptionIdentifier(VariableByteInt(2))
+            ]
+        );
+
+        // The QoS is never higher than the one the message was published with.
+        publish(&broker_tx, 0, "PUB", "home/kitchen/temp", b"22", false).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.payload, Bytes::from_static(b"22"));
+        assert_eq!(packet.qos, QoS::AtMostOnce);
+
+        let no_publish = time::timeout(Duration::from_millis(100), subscriber.recv()).await;
+        assert!(no_publish.is_err(), "Expected no PUBLISH, got {:?}", no_publish);
+
+        match publisher.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::PublishReceived(_)) => {},
+            msg => panic!("Expected PUBREC, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn overlapping_subscriptions_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_overlapping_subscriptions(sender));
+    }
+
+    async fn run_offline_queue(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = || ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(60)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect()).await;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "news", RetainHandling::DoNotSend).await;
+
+        let disconnect = BrokerMessage::Disconnect(1, "SUB".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        // The queue holds two messages, the oldest one is dropped.
+        for payload in [b"1", b"2", b"3"] {
+            publish(&broker_tx, 0, "PUB", "news", payload, false).await;
+        }
+
+        let mut subscriber = connect_client_with(&broker_tx, 2, subscriber_connect()).await;
+
+        for payload in [b"2", b"3"] {
+            let packet = expect_publish(&mut subscriber).await;
+            assert_eq!(packet.payload, Bytes::from_static(payload));
+            assert_eq!(packet.qos, QoS::AtMostOnce);
+        }
+
+        let no_publish = time::timeout(Duration::from_millis(100), subscriber.recv()).await;
+        assert!(no_publish.is_err(), "Expected no PUBLISH, got {:?}", no_publish);
+    }
+
+    async fn run_offline_queue_reject(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(60)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect).await;
+
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: "news".parse().unwrap(),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        subscribe_with(&broker_tx, &mut subscriber, 1, "SUB", subscription_topic).await;
+
+        let disconnect = BrokerMessage::Disconnect(1, "SUB".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        // QoS 0 messages are not queued by default, the second QoS 1 message exceeds the
+        // queue's byte limit.
+        publish(&broker_tx, 0, "PUB", "news", b"1", false).await;
+
+        for (packet_id, expected_reason) in
+            [(1, PublishAckReason::Success), (2, PublishAckReason::QuotaExceeded)]
+        {
+            let packet = PublishPacket {
+                qos: QoS::AtLeastOnce,
+                packet_id: Some(packet_id),
+                ..publish_packet("news", b"1234")
+            };
+            publish_with(&broker_tx, 0, "PUB", packet).await;
+
+            match publisher.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::PublishAck(ack)) => {
+                    assert_eq!(ack.packet_id, packet_id);
+                    assert_eq!(ack.reason_code, expected_reason);
+                },
+                msg => panic!("Expected PUBACK, got {:?}", msg),
+            }
+        }
+    }
+
+    async fn run_queue_reject_single_subscriber(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscription_topic = || SubscriptionTopic {
+            topic_filter: "news".parse().unwrap(),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+
+        // The offline subscriber's queue is full after the first message.
+        let offline_connect = ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(60)),
+            ..connect_packet("OFFLINE")
+        };
+        let mut offline = connect_client_with(&broker_tx, 1, offline_connect).await;
+        subscribe_with(&broker_tx, &mut offline, 1, "OFFLINE", subscription_topic()).await;
+        let disconnect =
+            BrokerMessage::Disconnect(1, "OFFLINE".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        let mut online = connect_client(&broker_tx, 2, "ONLINE").await;
+        subscribe_with(&broker_tx, &mut online, 2, "ONLINE", subscription_topic()).await;
+
+        for (packet_id, expected_reason) in
+            [(1, PublishAckReason::Success), (2, PublishAckReason::QuotaExceeded)]
+        {
+            let packet = PublishPacket {
+                qos: QoS::AtLeastOnce,
+                packet_id: Some(packet_id),
+                ..publish_packet("news", b"1234")
+            };
+            publish_with(&broker_tx, 0, "PUB", packet).await;
+
+            match publisher.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::PublishAck(ack)) => {
+                    assert_eq!(ack.packet_id, packet_id);
+                    assert_eq!(ack.reason_code, expected_reason);
+                },
+                msg => panic!("Expected PUBACK, got {:?}", msg),
+            }
+        }
+
+        // Only the full queue rejects the message, the other subscriber still receives it.
+        assert_eq!(expect_publish(&mut online).await.payload, &b"1234"[..]);
+        assert_eq!(expect_publish(&mut online).await.payload, &b"1234"[..]);
+
+        // QoS 0 messages aren't held back by the full queue either.
+        publish(&broker_tx, 0, "PUB", "news", b"qos0", false).await;
+        assert_eq!(expect_publish(&mut online).await.payload, &b"qos0"[..]);
+    }
+
+    #[test]
+    fn offline_queue_test() {
+        let config = BrokerConfig {
+            max_queued_messages: 2,
+            queue_qos0_messages: true,
+            ..BrokerConfig::default()
+        };
+        let broker = Broker::with_plugin_and_config(Noop, config);
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_offline_queue(sender));
+    }
+
+    #[test]
+    fn queue_reject_single_subscriber_test() {
+        let config = BrokerConfig {
+            max_queued_messages: 1,
+            queue_overflow_policy: QueueOverflowPolicy::Reject,
+            ..BrokerConfig::default()
+        };
+        let broker = Broker::with_plugin_and_config(Noop, config);
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_queue_reject_single_subscriber(sender));
+    }
+
+    #[test]
+    fn offline_queue_reject_test() {
+        let config = BrokerConfig {
+            max_queued_bytes: 6,
+            queue_overflow_policy: QueueOverflowPolicy::Reject,
+            ..BrokerConfig::default()
+        };
+        let broker = Broker::with_plugin_and_config(Noop, config);
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_offline_queue_reject(sender));
+    }
+
+    async fn run_invalid_payload_format(broker_tx: Sender<BrokerMessage>, validate: bool) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut subscriber = connect_client(&broker_tx, 1, "SUB").await;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "text", RetainHandling::DoNotSend).await;
+
+        let utf8_publish = |packet_id, payload| PublishPacket {
+            qos: QoS::AtLeastOnce,
+            packet_id: Some(packet_id),
+            payload_format_indicator: Some(PayloadFormatIndicator(1)),
+            ..publish_packet("text", payload)
+        };
+
+        for (packet_id, payload) in [(1, &b"valid"[..]), (2, &[0xC3, 0x28][..])] {
+            publish_with(&broker_tx, 0, "PUB", utf8_publish(packet_id, payload)).await;
+
+            let expected_reason = if validate && packet_id == 2 {
+                PublishAckReason::PayloadFormatInvalid
+            } else {
+                PublishAckReason::Success
+            };
+
+            match publisher.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::PublishAck(ack)) => {
+                    assert_eq!(ack.packet_id, packet_id);
+                    assert_eq!(ack.reason_code, expected_reason);
+                },
+                msg => panic!("Expected PUBACK, got {:?}", msg),
+            }
+        }
+
+        assert_eq!(expect_publish(&mut subscriber).await.payload, Bytes::from_static(b"valid"));
+
+        if validate {
+            assert!(subscriber.try_recv().is_err());
+        } else {
+            let packet = expect_publish(&mut subscriber).await;
+            assert_eq!(packet.payload, Bytes::from_static(&[0xC3, 0x28]));
+        }
+    }
+
+    #[test]
+    fn invalid_payload_format_test() {
+        for validate in [true, false] {
+            let config =
+                BrokerConfig { validate_payload_format: validate, ..BrokerConfig::default() };
+            let broker = Broker::with_plugin_and_config(Noop, config);
+            let sender = broker.sender();
+
+            let runtime = Runtime::new().unwrap();
+
+            runtime.spawn(broker.run());
+            runtime.block_on(run_invalid_payload_format(sender, validate));
+        }
+    }
+
+    #[test]
+    fn matches_foreign_response_topics_test() {
+        let matches = |topic_filter: &str, prefix: &str| {
+            matches_foreign_response_topics(&topic_filter.parse().unwrap(), prefix, "me")
+        };
+
+        assert!(!matches("$response/me/#", "$response"));
+        assert!(!matches("$response/me", "$response"));
+        assert!(!matches("$response", "$response"));
+        assert!(!matches("#", "$response"));
+        assert!(!matches("+/other/#", "$response"));
+        assert!(!matches("home/#", "$response"));
+        assert!(matches("$response/other/#", "$response"));
+        assert!(matches("$response/+/reply", "$response"));
+        assert!(matches("$response/#", "$response"));
+        assert!(matches("$share/group/$response/other", "$response"));
+
+        assert!(!matches("rpc/response/me/+", "rpc/response"));
+        assert!(!matches("rpc/request/#", "rpc/response"));
+        assert!(matches("#", "rpc/response"));
+        assert!(matches("+/response/other", "rpc/response"));
+        assert!(matches("rpc/#", "rpc/response"));
+    }
+
+    #[test]
+    fn packet_id_allocator_test() {
+        let mut allocator = PacketIdAllocator::new();
+
+        // Packet IDs start at 1 and are handed out in order.
+        assert_eq!(allocator.allocate(|id| id), Some(1));
+        assert_eq!(allocator.allocate(|id| id), Some(2));
+        assert_eq!(allocator.allocate(|id| id), Some(3));
+        assert_eq!(allocator.release(2), Some(2));
+        assert_eq!(allocator.release(2), None);
+
+        // Wrapping around skips 0 and the IDs which are still in use.
+        while allocator.next_packet_id != u16::MAX {
+            let packet_id = allocator.allocate(|id| id).unwrap();
+            allocator.release(packet_id);
+        }
+
+        assert_eq!(allocator.allocate(|id| id), Some(u16::MAX));
+        assert_eq!(allocator.allocate(|id| id), Some(2));
+        assert_eq!(allocator.allocate(|id| id), Some(4));
+        assert_eq!(
+            allocator.in_order(),
+            vec![(1, &1), (3, &3), (u16::MAX, &u16::MAX), (2, &2), (4, &4)]
+        );
+
+        // Once all IDs are in use, no more are handed out until one is released.
+        while !allocator.is_exhausted() {
+            allocator.allocate(|id| id).unwrap();
+        }
+
+        assert_eq!(allocator.len(), u16::MAX as usize);
+        assert_eq!(allocator.allocate(|id| id), None);
+        allocator.release(42);
+        assert_eq!(allocator.allocate(|id| id), Some(42));
+    }
+
+    async fn run_response_information(broker_tx: Sender<BrokerMessage>) {
+        let (sender, mut requester) = mpsc::channel(5);
+        let connect_packet = ConnectPacket {
+            request_response_information: Some(RequestResponseInformation(1)),
+            ..connect_packet("REQUESTER")
+        };
+        broker_tx.send(BrokerMessage::Connect(0, Box::new(connect_packet), sender)).await.unwrap();
+
+        match requester.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::ConnectAck(ack)) => assert_eq!(
+                ack.response_information,
+                Some(ResponseInformation("$response/REQUESTER".to_string()))
+            ),
+            msg => panic!("Expected CONNACK, got {:?}", msg),
+        }
+
+        let mut other = connect_client(&broker_tx, 1, "OTHER").await;
+
+        for (connection_id, client_id, receiver, expected_reason) in [
+            (0, "REQUESTER", &mut requester, SubscribeAckReason::GrantedQoSZero),
+            (1, "OTHER", &mut other, SubscribeAckReason::NotAuthorized),
+        ] {
+            broker_tx
+                .send(BrokerMessage::Subscribe(
+                    connection_id,
+                    client_id.to_string(),
+                    SubscribePacket {
+                        packet_id: 1,
+                        subscription_identifier: None,
+                        user_properties: vec![],
+                        subscription_topics: vec![SubscriptionTopic {
+                            topic_filter: "$response/REQUESTER/#".parse().unwrap(),
+                            maximum_qos: QoS::AtMostOnce,
+                            no_local: false,
+                            retain_as_published: false,
+                            retain_handling: RetainHandling::DoNotSend,
+                        }],
+                    },
+                ))
+                .await
+                .unwrap();
+
+            match receiver.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::SubscribeAck(ack)) => {
+                    assert_eq!(ack.reason_codes, vec![expected_reason])
+                },
+                msg => panic!("Expected SUBACK, got {:?}", msg),
+            }
+        }
+
+        // Anyone may publish responses.
+        publish(&broker_tx, 1, "OTHER", "$response/REQUESTER/1", b"42", false).await;
+        let packet = expect_publish(&mut requester).await;
+        assert_eq!(packet.payload, Bytes::from_static(b"42"));
+
+        let no_publish = time::timeout(Duration::from_millis(100), other.recv()).await;
+        assert!(no_publish.is_err(), "Expected no PUBLISH, got {:?}", no_publish);
+    }
+
+    #[test]
+    fn response_information_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_response_information(sender));
+    }
+
+    async fn run_plain_response_topic_prefix(broker_tx: Sender<BrokerMessage>) {
+        let mut subscriber = connect_client(&broker_tx, 0, "SUB").await;
+
+        // Without a leading dollar, wildcard filters in the first level match response
+        // topics of other clients and are refused.
+        for (topic_filter, expected_reason) in [
+            ("#", SubscribeAckReason::NotAuthorized),
+            ("+/response/#", SubscribeAckReason::NotAuthorized),
+            ("home/#", SubscribeAckReason::GrantedQoSZero),
+            ("rpc/response/SUB/#", SubscribeAckReason::GrantedQoSZero),
+        ] {
+            broker_tx
+                .send(BrokerMessage::Subscribe(
+                    0,
+                    "SUB".to_string(),
+                    SubscribePacket {
+                        packet_id: 1,
+                        subscription_identifier: None,
+                        user_properties: vec![],
+                        subscription_topics: vec![SubscriptionTopic {
+                            topic_filter: topic_filter.parse().unwrap(),
+                            maximum_qos: QoS::AtMostOnce,
+                            no_local: false,
+                            retain_as_published: false,
+                            retain_handling: RetainHandling::DoNotSend,
+                        }],
+                    },
+                ))
+                .await
+                .unwrap();
+
+            match subscriber.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::SubscribeAck(ack)) => {
+                    assert_eq!(ack.reason_codes, vec![expected_reason], "{}", topic_filter)
+                },
+                msg => panic!("Expected SUBACK, got {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn plain_response_topic_prefix_test() {
+        let config = BrokerConfig {
+            response_topic_prefix: Some("rpc/response".to_string()),
+            ..BrokerConfig::default()
+        };
+        let broker = Broker::with_plugin_and_config(Noop, config);
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_plain_response_topic_prefix(sender));
+    }
+
+    async fn run_redirect(broker_tx: Sender<BrokerMessage>) {
+        let mut client = connect_client(&broker_tx, 0, "CLIENT").await;
+
+        let redirect =
+            Redirect { permanent: false, server_reference: "mqtt.example.com:1883".to_string() };
+        broker_tx.send(BrokerMessage::Redirect(Some(redirect))).await.unwrap();
+
+        let expected_disconnect = || DisconnectPacket {
+            reason_code: DisconnectReason::UseAnotherServer,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: vec![],
+            server_reference: Some(ServerReference("mqtt.example.com:1883".to_string())),
+        };
+        assert_eq!(client.recv().await.unwrap(), ClientMessage::Disconnect(expected_disconnect()));
+
+        // New connections are redirected as well.
+        let (sender, mut receiver) = mpsc::channel(5);
+        let connect = BrokerMessage::Connect(1, Box::new(connect_packet("OTHER")), sender);
+        broker_tx.send(connect).await.unwrap();
+
+        match receiver.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::ConnectAck(ack)) => {
+                assert_eq!(ack.reason_code, ConnectReason::UseAnotherServer);
+                assert_eq!(
+                    ack.server_reference,
+                    Some(ServerReference("mqtt.example.com:1883".to_string()))
+                );
+            },
+            msg => panic!("Expected CONNACK, got {:?}", msg),
+        }
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            ClientMessage::Disconnect(expected_disconnect())
+        );
+
+        broker_tx.send(BrokerMessage::Redirect(None)).await.unwrap();
+        let _client = connect_client(&broker_tx, 2, "OTHER").await;
+    }
+
+    #[test]
+    fn redirect_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_redirect(sender));
+    }
+
+    #[test]
+    fn strip_problem_information_test() {
+        let subscribe_ack = || {
+            Packet::SubscribeAck(SubscribeAckPacket {
+                packet_id: 1,
+                reason_string: Some(ReasonString("Not allowed".to_string())),
+                user_properties: vec![UserProperty("key".into(), "value".into())],
+                reason_codes: vec![SubscribeAckReason::NotAuthorized],
+            })
+        };
+
+        let mut message = ClientMessage::Packets(vec![subscribe_ack()]);
+        strip_problem_information(&mut message);
+        assert_eq!(
+            message,
+            ClientMessage::Packets(vec![Packet::SubscribeAck(SubscribeAckPacket {
+                packet_id: 1,
+                reason_string: None,
+                user_properties: vec![],
+                reason_codes: vec![SubscribeAckReason::NotAuthorized],
+            })])
+        );
+
+        // Disconnect packets keep their reason string.
+        let disconnect =
+            || ClientMessage::disconnect(DisconnectReason::ProtocolError, "Bad packet");
+        let mut message = disconnect();
+        strip_problem_information(&mut message);
+        assert_eq!(message, disconnect());
+    }
+
+    async fn run_problem_information(broker_tx: Sender<BrokerMessage>) {
+        let mut verbose = connect_client(&broker_tx, 0, "VERBOSE").await;
+
+        let connect_packet = ConnectPacket {
+            request_problem_information: Some(RequestProblemInformation(0)),
+            ..connect_packet("QUIET")
+        };
+        let mut quiet = connect_client_with(&broker_tx, 1, connect_packet).await;
+
+        for (connection_id, client_id, receiver, has_reason_string) in
+            [(0, "VERBOSE", &mut verbose, true), (1, "QUIET", &mut quiet, false)]
+        {
+            broker_tx
+                .send(BrokerMessage::Subscribe(
+                    connection_id,
+                    client_id.to_string(),
+                    SubscribePacket {
+                        packet_id: 1,
+                        subscription_identifier: None,
+                        user_properties: vec![],
+                        subscription_topics: vec![SubscriptionTopic {
+                            topic_filter: "$response/#".parse().unwrap(),
+                            maximum_qos: QoS::AtMostOnce,
+                            no_local: false,
+                            retain_as_published: false,
+                            retain_handling: RetainHandling::DoNotSend,
+                        }],
+                    },
+                ))
+                .await
+                .unwrap();
+
+            match receiver.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::SubscribeAck(ack)) => {
+                    assert_eq!(ack.reason_codes, vec![SubscribeAckReason::NotAuthorized]);
+                    assert_eq!(ack.reason_string.is_some(), has_reason_string);
+                },
+                msg => panic!("Expected SUBACK, got {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn problem_information_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_problem_information(sender));
+    }
+
+    async fn run_session_expiry(broker_tx: Sender<BrokerMessage>) {
+        let mut watcher = connect_client(&broker_tx, 0, "WATCHER").await;
+        subscribe(&broker_tx, &mut watcher, 0, "WATCHER", "wills/+", RetainHandling::DoNotSend)
+            .await;
+
+        let client_connect = || ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(1)),
+            will: Some(FinalWill {
+                topic: "wills/CLIENT".parse().unwrap(),
+                payload: Bytes::from_static(b"gone"),
+                qos: QoS::AtMostOnce,
+                should_retain: false,
+                will_delay_interval: Some(WillDelayInterval(10)),
+                payload_format_indicator: None,
+                message_expiry_interval: None,
+                content_type: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: vec![],
+            }),
+            ..connect_packet("CLIENT")
+        };
+
+        let mut client = connect_client_with(&broker_tx, 1, client_connect()).await;
+        subscribe(&broker_tx, &mut client, 1, "CLIENT", "news", RetainHandling::DoNotSend).await;
+
+        let disconnect =
+            BrokerMessage::Disconnect(1, "CLIENT".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        // Reconnecting before the session expires cancels its expiry.
+        time::sleep(Duration::from_millis(500)).await;
+        let _client = connect_client_with(&broker_tx, 2, client_connect()).await;
+
+        let disconnect =
+            BrokerMessage::Disconnect(2, "CLIENT".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        let no_will = time::timeout(Duration::from_millis(700), watcher.recv()).await;
+        assert!(no_will.is_err(), "Expected no will yet, got {:?}", no_will);
+
+        // The will delay is longer than the session expiry, so the will is published when the
+        // session ends.
+        let packet = expect_publish(&mut watcher).await;
+        assert_eq!(packet.topic.topic_name(), "wills/CLIENT");
+        assert_eq!(packet.payload, Bytes::from_static(b"gone"));
+
+        // The session and its subscriptions are gone.
+        publish(&broker_tx, 0, "WATCHER", "news", b"1", false).await;
+
+        let (sender, mut receiver) = mpsc::channel(5);
+        let connect = BrokerMessage::Connect(3, Box::new(client_connect()), sender);
+        broker_tx.send(connect).await.unwrap();
+
+        match receiver.recv().await.unwrap() {
+            ClientMessage::Packet(Packet::ConnectAck(ack)) => assert!(!ack.session_present),
+            msg => panic!("Expected CONNACK, got {:?}", msg),
+        }
+
+        let no_publish = time::timeout(Duration::from_millis(100), receiver.recv()).await;
+        assert!(no_publish.is_err(), "Expected no PUBLISH, got {:?}", no_publish);
+    }
+
+    #[test]
+    fn session_expiry_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_session_expiry(sender));
+    }
+
+    #[test]
+    fn message_expiry_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime =
+            Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_message_expiry(sender));
+    }
+
+    async fn run_in_flight_message_expiry(broker_tx: Sender<BrokerMessage>) {
+        let mut publisher = connect_client(&broker_tx, 0, "PUB").await;
+
+        let subscriber_connect = || ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(60)),
+            ..connect_packet("SUB")
+        };
+        let mut subscriber = connect_client_with(&broker_tx, 1, subscriber_connect()).await;
+
+        let subscription_topic = SubscriptionTopic {
+            topic_filter: "alarms/+".parse().unwrap(),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        subscribe_with(&broker_tx, &mut subscriber, 1, "SUB", subscription_topic).await;
+
+        // Sent to the subscriber, which disconnects without acknowledging them.
+        for (packet_id, topic, expiry_interval) in
+            [(1, "alarms/fire", Some(1)), (2, "alarms/smoke", None)]
+        {
+            let packet = PublishPacket {
+                qos: QoS::AtLeastOnce,
+                packet_id: Some(packet_id),
+                message_expiry_interval: expiry_interval.map(MessageExpiryInterval),
+                ..publish_packet(topic, b"1")
+            };
+            publish_with(&broker_tx, 0, "PUB", packet).await;
+
+            match publisher.recv().await.unwrap() {
+                ClientMessage::Packet(Packet::PublishAck(_)) => {},
+                msg => panic!("Expected PUBACK, got {:?}", msg),
+            }
+
+            assert_eq!(expect_publish(&mut subscriber).await.topic.topic_name(), topic);
+        }
+
+        let disconnect = BrokerMessage::Disconnect(1, "SUB".to_string(), WillDisconnectLogic::Send);
+        broker_tx.send(disconnect).await.unwrap();
+
+        time::sleep(Duration::from_millis(1100)).await;
+
+        // Only the publish which didn't expire is resent.
+        let mut subscriber = connect_client_with(&broker_tx, 2, subscriber_connect()).await;
+        let packet = expect_publish(&mut subscriber).await;
+        assert_eq!(packet.topic.topic_name(), "alarms/smoke");
+        assert!(packet.is_duplicate);
+
+        let no_publish = time::timeout(Duration::from_millis(100), subscriber.recv()).await;
+        assert!(no_publish.is_err(), "Expected no PUBLISH, got {:?}", no_publish);
+    }
+
+    #[test]
+    fn in_flight_message_expiry_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime =
+            Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_in_flight_message_expiry(sender));
+    }
+
+    #[test]
+    fn maximum_packet_size_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_maximum_packet_size(sender));
+    }
+
+    #[test]
+    fn maximum_packet_size_boundary_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_maximum_packet_size_boundary(sender));
+    }
+
+    #[test]
+    fn receive_maximum_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_receive_maximum(sender));
+    }
+
+    #[test]
+    fn receive_maximum_zero_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_receive_maximum_zero(sender));
+    }
+
+    #[test]
+    fn receive_maximum_exceeded_test() {
+        let config = BrokerConfig { receive_maximum: 1, ..BrokerConfig::default() };
+        let broker = Broker::with_plugin_and_config(Noop, config);
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_receive_maximum_exceeded(sender));
+    }
+
+    #[test]
+    fn outgoing_topic_aliases_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_outgoing_topic_aliases(sender));
+    }
+
+    #[test]
+    fn shared_subscriptions_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_shared_subscriptions(sender));
+    }
+
+    #[test]
+    fn sticky_shared_subscriptions_test() {
+        let config = BrokerConfig {
+            shared_subscription_strategy: SharedSubscriptionStrategy::StickyByClient,
+            ..BrokerConfig::default()
+        };
+        let broker = Broker::with_plugin_and_config(Noop, config);
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_sticky_shared_subscriptions(sender));
+    }
+
+    #[test]
+    fn retained_messages_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_retained_messages(sender));
+    }
+
+    async fn run_retained_message_detached(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut subscriber = connect_client(&broker_tx, 1, "SUB").await;
+
+        // The payload is a slice of a larger buffer, like a packet decoded from a read buffer.
+        let read_buffer = Bytes::from(vec![7; 4096]);
+        let packet = PublishPacket {
+            retain: true,
+            payload: read_buffer.slice(..2),
+            ..publish_packet("home/kitchen/temperature", b"")
+        };
+        publish_with(&broker_tx, 0, "PUB", packet).await;
+
+        let handling = RetainHandling::SendAtSubscribeTime;
+        subscribe(&broker_tx, &mut subscriber, 1, "SUB", "home/#", handling).await;
+
+        // The retained message has its own copy of the payload.
+        let retained = expect_publish(&mut subscriber).await;
+        assert_eq!(retained.payload, read_buffer.slice(..2));
+        assert!(!read_buffer.as_ptr_range().contains(&retained.payload.as_ptr()));
+    }
+
+    #[test]
+    fn retained_message_detached_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_retained_message_detached(sender));
+    }
+
+    async fn run_shared_message_fan_out(broker_tx: Sender<BrokerMessage>) {
+        let _publisher = connect_client(&broker_tx, 0, "PUB").await;
+        let mut first = connect_client(&broker_tx, 1, "SUB1").await;
+        let mut second = connect_client(&broker_tx, 2, "SUB2").await;
+        let legacy_connect =
+            ConnectPacket { protocol_version: ProtocolVersion::V311, ..connect_packet("SUB3") };
+        let mut legacy = connect_client_with(&broker_tx, 3, legacy_connect).await;
+
+        let handling = RetainHandling::DoNotSend;
+        subscribe(&broker_tx, &mut first, 1, "SUB1", "sensors/+", handling).await;
+        subscribe(&broker_tx, &mut second, 2, "SUB2", "sensors/#", handling).await;
+        subscribe(&broker_tx, &mut legacy, 3, "SUB3", "sensors/humidity", handling).await;
+
+        publish(&broker_tx, 0, "PUB", "sensors/humidity", b"40", false).await;
+
+        // Subscribers with the same protocol version share the encoded topic,
+        // properties and payload, only their headers are encoded separately.
+        let first_publish = expect_encoded_publish(&mut first).await;
+        let second_publish = expect_encoded_publish(&mut second).await;
+        let legacy_publish = expect_encoded_publish(&mut legacy).await;
+        assert_eq!(first_publish.body.as_ptr(), second_publish.body.as_ptr());
+        assert_ne!(first_publish.body.as_ptr(), legacy_publish.body.as_ptr());
+
+        for (publish, protocol_version) in [
+            (first_publish, ProtocolVersion::V500),
+            (second_publish, ProtocolVersion::V500),
+            (legacy_publish, ProtocolVersion::V311),
+        ] {
+            let packet = decode_publish(&publish, protocol_version);
+            assert_eq!(packet.topic.topic_name(), "sensors/humidity");
+            assert_eq!(packet.payload, Bytes::from_static(b"40"));
+        }
+    }
+
+    #[test]
+    fn shared_message_fan_out_test() {
+        let broker = Broker::<Noop>::new();
+        let sender = broker.sender();
+
+        let runtime = Runtime::new().unwrap();
+
+        runtime.spawn(broker.run());
+        runtime.block_on(run_shared_message_fan_out(sender));
+    }
+
     #[test]
     fn simple_client_test() {
         let broker = Broker::<Noop>::new();
@@ -1,4 +1,7 @@
-use crate::broker::{BrokerMessage, ConnectionId, WillDisconnectLogic};
+use crate::broker::{
+    failed_connect_ack, BrokerConfig, BrokerMessage, ConnectionId, WillDisconnectLogic,
+};
+use bytes::{Bytes, BytesMut};
 use futures::{
     future::{self, Either},
     stream, Sink, SinkExt, Stream, StreamExt,
@@ -6,28 +9,24 @@ use futures::{
 use log::{debug, info, trace, warn};
 use mqtt_v5::{
     codec::MqttCodec,
+    topic::Topic,
     types::{
-        DecodeError, DisconnectPacket, DisconnectReason, EncodeError, Packet, ProtocolError,
-        ProtocolVersion, QoS,
+        properties::{ReasonString, TopicAlias},
+        ConnectReason, DecodeError, DisconnectPacket, DisconnectReason, EncodeError, Packet,
+        ProtocolError, ProtocolVersion, PublishPacket, QoS,
     },
+    MAX_V310_CLIENT_ID_LEN,
 };
 use nanoid::nanoid;
-use std::{marker::Unpin, sync::atomic::AtomicU64, time::Duration};
+use std::{collections::HashMap, marker::Unpin, sync::atomic::AtomicU64, time::Duration};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::mpsc::{self, Receiver, Sender},
     task, time,
 };
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Encoder, Framed};
 
-use bytes::BytesMut;
-use mqtt_v5::{
-    encoder,
-    websocket::{
-        codec::{Message, MessageCodec as WsMessageCodec, Opcode},
-        WsUpgraderCodec,
-    },
-};
+use mqtt_v5::websocket::{WsMqttCodec, WsUpgraderCodec};
 use std::sync::atomic::Ordering;
 
 /// Generate a new unique connection id
@@ -42,13 +41,43 @@ type PacketResult = Result<Packet, DecodeError>;
 /// Timeout when writing to a client sink
 const SINK_SEND_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// The highest topic alias a client may use for its publish packets.
+/// Advertised to clients in the CONNACK packet.
+pub const TOPIC_ALIAS_MAXIMUM: u16 = 64;
+
+/// Resolve the topic of an incoming publish packet with the topic aliases of the connection.
+/// A publish with a topic name and an alias maps the alias to that topic, a publish with an
+/// empty topic name uses the topic previously mapped to its alias. The alias is removed from
+/// the packet, as it is only valid for this connection.
+fn resolve_topic_alias(
+    topic_aliases: &mut HashMap<u16, Topic>,
+    packet: &mut PublishPacket,
+) -> Result<(), DisconnectReason> {
+    if let Some(TopicAlias(alias)) = packet.topic_alias.take() {
+        if alias == 0 || alias > TOPIC_ALIAS_MAXIMUM {
+            return Err(DisconnectReason::TopicAliasInvalid);
+        }
+
+        if packet.topic.is_empty() {
+            packet.topic =
+                topic_aliases.get(&alias).cloned().ok_or(DisconnectReason::TopicAliasInvalid)?;
+        } else {
+            topic_aliases.insert(alias, packet.topic.clone());
+        }
+    }
+
+    Ok(())
+}
+
 /// Process MQTT connect on `stream` and spawn a task for this connection
 /// TOOD(flxo): Move to dedicated module `io`?
-pub fn spawn<S>(stream: S, broker_tx: Sender<BrokerMessage>)
+pub fn spawn<S>(stream: S, broker_tx: Sender<BrokerMessage>, config: &BrokerConfig)
 where
     S: AsyncRead + AsyncWrite + Send + Sync + 'static,
 {
-    let (packet_sink, packet_stream) = Framed::new(stream, MqttCodec::new()).split();
+    let mut codec = MqttCodec::with_maximum_packet_size(config.maximum_packet_size);
+    codec.set_decode_mode(config.decode_mode);
+    let (packet_sink, packet_stream) = Framed::new(stream, codec).split();
     spawn_framed(packet_stream, packet_sink, broker_tx);
 }
 
@@ -56,7 +85,7 @@ where
 pub fn spawn_framed<ST, SI>(packet_stream: ST, packet_sink: SI, broker_tx: Sender<BrokerMessage>)
 where
     ST: Stream<Item = PacketResult> + Unpin + Send + Sync + 'static,
-    SI: Sink<Packet, Error = EncodeError> + Unpin + Send + Sync + 'static,
+    SI: Sink<OutgoingData, Error = EncodeError> + Unpin + Send + Sync + 'static,
 {
     task::spawn(async move {
         let unconnected_client = UnconnectedClient::new(packet_stream, packet_sink, broker_tx);
@@ -68,7 +97,7 @@ where
 }
 
 /// TOOD(flxo): Move to dedicated module `io`?
-async fn upgrade_ws_stream<S>(stream: S) -> Framed<S, WsMessageCodec>
+async fn upgrade_ws_stream<S>(stream: S, config: &BrokerConfig) -> Framed<S, WsMqttCodec>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
 {
@@ -81,8 +110,9 @@ where
     }
 
     let old_parts = upgrade_framed.into_parts();
-    let mut new_parts =
-        Framed::new(old_parts.io, WsMessageCodec::with_masked_encode(false)).into_parts();
+    let mut codec = WsMqttCodec::with_maximum_packet_size(config.maximum_packet_size);
+    codec.set_decode_mode(config.decode_mode);
+    let mut new_parts = Framed::new(old_parts.io, codec).into_parts();
     new_parts.read_buf = old_parts.read_buf;
     new_parts.write_buf = old_parts.write_buf;
 
@@ -90,107 +120,29 @@ where
 }
 
 /// TOOD(flxo): Move to dedicated module `io`?
-pub async fn spawn_websocket<S>(stream: S, broker_tx: Sender<BrokerMessage>)
+pub async fn spawn_websocket<S>(stream: S, broker_tx: Sender<BrokerMessage>, config: &BrokerConfig)
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
 {
-    let ws_framed = upgrade_ws_stream(stream).await;
-
-    let (sink, ws_stream) = ws_framed.split();
-    let sink = sink.with(|packet: Packet| {
-        let mut payload_bytes = BytesMut::new();
-        // TODO(bschwind) - Support MQTTv5 here. With a stateful Framed object we can store
-        //                  the version on a successful Connect decode, but in this code structure
-        //                  we can't pass state from the stream to the sink.
-        encoder::encode_mqtt(&packet, &mut payload_bytes, ProtocolVersion::V311);
-
-        async {
-            let result: Result<Message, EncodeError> = Ok(Message::binary(payload_bytes.freeze()));
-            result
-        }
-    });
-
-    let read_buf = BytesMut::with_capacity(4096);
-
-    let stream = stream::unfold(
-        (ws_stream, read_buf, ProtocolVersion::V311),
-        |(mut ws_stream, mut read_buf, mut protocol_version)| {
-            async move {
-                // Loop until we've built up enough data from the WebSocket stream
-                // to decode a new MQTT packet
-                loop {
-                    // Try to read an MQTT packet from the read buffer
-                    match mqtt_v5::decoder::decode_mqtt(&mut read_buf, protocol_version) {
-                        Ok(Some(packet)) => {
-                            if let Packet::Connect(packet) = &packet {
-                                protocol_version = packet.protocol_version;
-                            }
-
-                            // If we got one, return it
-                            return Some((Ok(packet), (ws_stream, read_buf, protocol_version)));
-                        },
-                        Err(e) => {
-                            // If we had a decode error, propagate the error along the stream
-                            return Some((Err(e), (ws_stream, read_buf, protocol_version)));
-                        },
-                        Ok(None) => {
-                            // Otherwise we need more binary data from the WebSocket stream
-                        },
-                    }
-
-                    let ws_frame = ws_stream.next().await;
-
-                    match ws_frame {
-                        Some(Ok(message)) => {
-                            if message.opcode() == Opcode::Close {
-                                return None;
-                            }
-
-                            if message.opcode() == Opcode::Ping {
-                                trace!("Got a websocket ping");
-                            }
-
-                            if message.opcode() != Opcode::Binary {
-                                // MQTT Control Packets MUST be sent in WebSocket binary data frames
-                                return Some((
-                                    Err(DecodeError::BadTransport),
-                                    (ws_stream, read_buf, protocol_version),
-                                ));
-                            }
-
-                            read_buf.extend_from_slice(&message.into_data());
-                        },
-                        Some(Err(e)) => {
-                            debug!("Error while reading from WebSocket stream: {:?}", e);
-                            // If we had a decode error in the WebSocket layer,
-                            // propagate the it along the stream
-                            return Some((
-                                Err(DecodeError::BadTransport),
-                                (ws_stream, read_buf, protocol_version),
-                            ));
-                        },
-                        None => {
-                            // The WebSocket stream is over, so we are too
-                            return None;
-                        },
-                    }
-                }
-            }
-        },
-    );
-
-    spawn_framed(Box::pin(stream), Box::pin(sink), broker_tx);
+    let ws_framed = upgrade_ws_stream(stream, config).await;
+    let (packet_sink, packet_stream) = ws_framed.split();
+    spawn_framed(packet_stream, packet_sink, broker_tx);
 }
 
-struct UnconnectedClient<ST: Stream<Item = PacketResult>, SI: Sink<Packet, Error = EncodeError>> {
+struct UnconnectedClient<
+    ST: Stream<Item = PacketResult>,
+    SI: Sink<OutgoingData, Error = EncodeError>,
+> {
     connection_id: ConnectionId,
     packet_stream: ST,
     packet_sink: SI,
     broker_tx: Sender<BrokerMessage>,
 }
 
-impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeError>>
-    UnconnectedClient<ST, SI>
+impl<
+        ST: Stream<Item = PacketResult> + Unpin,
+        SI: Sink<OutgoingData, Error = EncodeError> + Unpin,
+    > UnconnectedClient<ST, SI>
 {
     pub fn new(packet_stream: ST, packet_sink: SI, broker_tx: Sender<BrokerMessage>) -> Self {
         let connection_id = next_connection_id();
@@ -208,13 +160,16 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
             Some(Ok(Packet::Connect(mut connect_packet))) => {
                 let protocol_version = connect_packet.protocol_version;
 
-                if connect_packet.protocol_name != "MQTT" {
-                    if protocol_version == ProtocolVersion::V500 {
-                        // TODO(bschwind) - Respond to the client with
-                        //                  0x84 (Unsupported Protocol Version)
-                    }
+                if connect_packet.protocol_name != protocol_version.protocol_name() {
+                    return self.refuse(ProtocolError::InvalidProtocolName).await;
+                }
 
-                    return Err(ProtocolError::InvalidProtocolName);
+                // MQTT 3.1 requires client identifiers of 1 to 23 characters.
+                if protocol_version == ProtocolVersion::V310
+                    && !(1..=MAX_V310_CLIENT_ID_LEN)
+                        .contains(&connect_packet.client_id.chars().count())
+                {
+                    return self.refuse(ProtocolError::InvalidClientId).await;
                 }
 
                 let (sender, receiver) = mpsc::channel(5);
@@ -256,13 +211,89 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
                 ))
             },
             Some(Ok(_)) => Err(ProtocolError::FirstPacketNotConnect),
-            Some(Err(e)) => Err(ProtocolError::MalformedPacket(e)),
+            Some(Err(e)) => self.refuse(ProtocolError::MalformedPacket(e)).await,
             None => {
                 // TODO(bschwind) - Technically end of stream?
                 Err(ProtocolError::FirstPacketNotConnect)
             },
         }
     }
+
+    /// Refuse the connection with a CONNACK packet carrying the reason code of the
+    /// error, if it has one, before the connection is closed.
+    async fn refuse(mut self, err: ProtocolError) -> Result<Client<ST, SI>, ProtocolError> {
+        if let Some(reason_code) = err.connect_reason() {
+            let connect_ack = failed_connect_ack(reason_code, None, None);
+            let send = self.packet_sink.send(Packet::ConnectAck(connect_ack).into());
+
+            if time::timeout(SINK_SEND_TIMEOUT, send).await.is_err() {
+                debug!("Timeout refusing connection {}", self.connection_id);
+            }
+        }
+
+        Err(err)
+    }
+}
+
+/// Returns false if a write to the socket failed or timed out, which ends the connection.
+fn write_succeeded(result: Result<Result<(), EncodeError>, time::error::Elapsed>) -> bool {
+    match result {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("Failed to write to client client socket: {:?}", e);
+            false
+        },
+        Err(_) => {
+            warn!("Timeout during client socket write. Disconnecting");
+            false
+        },
+    }
+}
+
+/// Data written to the socket of a client, either a packet to encode or bytes
+/// which the broker already encoded for the protocol version of the client.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Eq, PartialEq)]
+pub enum OutgoingData {
+    Packet(Packet),
+    Encoded(Bytes),
+}
+
+impl From<Packet> for OutgoingData {
+    fn from(packet: Packet) -> Self {
+        OutgoingData::Packet(packet)
+    }
+}
+
+impl Encoder<OutgoingData> for MqttCodec {
+    type Error = EncodeError;
+
+    fn encode(&mut self, data: OutgoingData, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+        match data {
+            OutgoingData::Packet(packet) => Encoder::<Packet>::encode(self, packet, bytes),
+            OutgoingData::Encoded(encoded) => Encoder::<Bytes>::encode(self, encoded, bytes),
+        }
+    }
+}
+
+impl Encoder<OutgoingData> for WsMqttCodec {
+    type Error = EncodeError;
+
+    fn encode(&mut self, data: OutgoingData, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+        match data {
+            OutgoingData::Packet(packet) => Encoder::<Packet>::encode(self, packet, bytes),
+            OutgoingData::Encoded(encoded) => Encoder::<Bytes>::encode(self, encoded, bytes),
+        }
+    }
+}
+
+/// A PUBLISH packet the broker encoded for the protocol version of a client. The header
+/// holds the parts which differ for each receiver of the message, like the packet ID
+/// and topic alias, while the body is the same buffer for all of them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EncodedPublish {
+    pub header: Bytes,
+    pub body: Bytes,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -270,13 +301,24 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
 pub enum ClientMessage {
     Packet(Packet),
     Packets(Vec<Packet>),
-    Disconnect(DisconnectReason),
+    Publish(EncodedPublish),
+    Disconnect(DisconnectPacket),
 }
 
-pub struct Client<ST: Stream<Item = PacketResult>, SI: Sink<Packet, Error = EncodeError>> {
+impl ClientMessage {
+    /// Disconnect the client, telling it why in a human-readable reason string.
+    pub fn disconnect(reason_code: DisconnectReason, reason_string: &str) -> Self {
+        ClientMessage::Disconnect(DisconnectPacket {
+            reason_string: Some(ReasonString(reason_string.to_string())),
+            ..reason_code.into()
+        })
+    }
+}
+
+pub struct Client<ST: Stream<Item = PacketResult>, SI: Sink<OutgoingData, Error = EncodeError>> {
     connection_id: ConnectionId,
     client_id: String,
-    _protocol_version: ProtocolVersion,
+    protocol_version: ProtocolVersion,
     keepalive_seconds: Option<u16>,
     packet_stream: ST,
     packet_sink: SI,
@@ -285,7 +327,7 @@ pub struct Client<ST: Stream<Item = PacketResult>, SI: Sink<Packet, Error = Enco
     self_tx: Sender<ClientMessage>,
 }
 
-impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeError>>
+impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<OutgoingData, Error = EncodeError>>
     Client<ST, SI>
 {
     #[allow(clippy::too_many_arguments)]
@@ -303,7 +345,7 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
         Self {
             connection_id,
             client_id,
-            _protocol_version: protocol_version,
+            protocol_version,
             keepalive_seconds,
             packet_stream,
             packet_sink,
@@ -328,6 +370,8 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
             .map(Duration::from_secs)
             .next();
 
+        let mut topic_aliases = HashMap::new();
+
         loop {
             let next_packet = {
                 if let Some(keepalive_duration) = keepalive_duration {
@@ -336,6 +380,12 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
                         .map_err(|_| ProtocolError::KeepAliveTimeout);
 
                     if let Err(ProtocolError::KeepAliveTimeout) = next_packet {
+                        warn!("Keepalive timeout of client {}", client_id);
+                        let message = ClientMessage::disconnect(
+                            DisconnectReason::KeepAliveTimeout,
+                            "No packet received within the keepalive",
+                        );
+                        self_tx.send(message).await.ok();
                         break;
                     }
 
@@ -368,15 +418,25 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
                                 .await
                                 .expect("Couldn't send Unsubscribe message to broker");
                         },
-                        Packet::Publish(packet) => {
-                            match packet.qos {
-                                QoS::AtMostOnce => {},
-                                QoS::AtLeastOnce | QoS::ExactlyOnce => {
-                                    assert!(
-                                        packet.packet_id.is_some(),
-                                        "Packets with QoS 1&2 need packet identifiers"
-                                    );
-                                },
+                        Packet::Publish(mut packet) => {
+                            if let Err(reason) =
+                                resolve_topic_alias(&mut topic_aliases, &mut packet)
+                            {
+                                warn!("Invalid topic alias from client {}", client_id);
+                                let message =
+                                    ClientMessage::disconnect(reason, "Invalid topic alias");
+                                self_tx.send(message).await.ok();
+                                break;
+                            }
+
+                            if packet.qos != QoS::AtMostOnce && packet.packet_id.is_none() {
+                                warn!("Publish without packet id from client {}", client_id);
+                                let message = ClientMessage::disconnect(
+                                    DisconnectReason::ProtocolError,
+                                    "Packets with QoS 1&2 need packet identifiers",
+                                );
+                                self_tx.send(message).await.ok();
+                                break;
                             }
 
                             broker_tx
@@ -463,10 +523,30 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
                                 .await
                                 .expect("Couldn't send Authentivate message to self");
                         },
-                        _ => {},
+                        packet => {
+                            warn!("Unexpected {:?} packet from client {}", packet, client_id);
+                            let message = ClientMessage::disconnect(
+                                DisconnectReason::ProtocolError,
+                                "Packet type is not allowed from a client",
+                            );
+                            self_tx.send(message).await.ok();
+                            break;
+                        },
                     },
                     Err(err) => {
                         warn!("Error while reading frame: {:?}", err);
+
+                        if let Some(reason_code) = err.disconnect_reason() {
+                            let reason_string = match err {
+                                DecodeError::PacketTooLarge => {
+                                    "Packet exceeds the maximum packet size"
+                                },
+                                _ => "Packet could not be decoded",
+                            };
+                            let message = ClientMessage::disconnect(reason_code, reason_string);
+                            self_tx.send(message).await.ok();
+                        }
+
                         break;
                     },
                 }
@@ -485,23 +565,40 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
             .expect("Couldn't send Disconnect message to broker");
     }
 
-    async fn handle_socket_writes(sink: SI, mut broker_rx: Receiver<ClientMessage>) {
+    async fn handle_socket_writes(
+        sink: SI,
+        mut broker_rx: Receiver<ClientMessage>,
+        protocol_version: ProtocolVersion,
+    ) {
         tokio::pin!(sink);
 
         while let Some(frame) = broker_rx.recv().await {
             let mut packets = match frame {
                 ClientMessage::Packets(packets) => Either::Left(stream::iter(packets)),
                 ClientMessage::Packet(packet) => Either::Right(stream::once(future::ready(packet))),
-                ClientMessage::Disconnect(reason_code) => {
-                    let disconnect_packet = DisconnectPacket {
-                        reason_code,
-                        session_expiry_interval: None,
-                        reason_string: None,
-                        user_properties: vec![],
-                        server_reference: None,
+                // The header of a publish is written first, followed by the body
+                // it shares with the other receivers of the message.
+                ClientMessage::Publish(publish) => {
+                    let send = async {
+                        sink.feed(OutgoingData::Encoded(publish.header)).await?;
+                        sink.send(OutgoingData::Encoded(publish.body)).await
                     };
 
-                    if let Err(e) = sink.send(Packet::Disconnect(disconnect_packet)).await {
+                    if !write_succeeded(time::timeout(SINK_SEND_TIMEOUT, send).await) {
+                        return;
+                    }
+
+                    continue;
+                },
+                // Servers only send DISCONNECT packets from MQTT 5 on, older
+                // clients just get their connection closed.
+                ClientMessage::Disconnect(_) if protocol_version != ProtocolVersion::V500 => {
+                    info!("Broker told the client to disconnect");
+
+                    return;
+                },
+                ClientMessage::Disconnect(disconnect_packet) => {
+                    if let Err(e) = sink.send(Packet::Disconnect(disconnect_packet).into()).await {
                         warn!("Failed to send disconnect packet to framed socket: {:?}", e);
                     }
 
@@ -513,17 +610,20 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
 
             // Process each packet in a dedicated timeout to be fair
             while let Some(packet) = packets.next().await {
-                let send = sink.send(packet);
-                match tokio::time::timeout(SINK_SEND_TIMEOUT, send).await {
-                    Ok(Ok(())) => (),
-                    Ok(Err(e)) => {
-                        warn!("Failed to write to client client socket: {:?}", e);
-                        return;
-                    },
-                    Err(_) => {
-                        warn!("Timeout during client socket write. Disconnecting");
-                        return;
-                    },
+                // A failed CONNACK ends the connection [MQTT-3.2.2-7].
+                let refused = matches!(
+                    &packet,
+                    Packet::ConnectAck(connect_ack) if connect_ack.reason_code != ConnectReason::Success
+                );
+
+                let send = sink.send(packet.into());
+                if !write_succeeded(time::timeout(SINK_SEND_TIMEOUT, send).await) {
+                    return;
+                }
+
+                if refused {
+                    info!("Connection refused, closing it");
+                    return;
                 }
             }
         }
@@ -538,7 +638,8 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
             self.broker_tx,
             self.self_tx,
         );
-        let task_tx = Self::handle_socket_writes(self.packet_sink, self.broker_rx);
+        let task_tx =
+            Self::handle_socket_writes(self.packet_sink, self.broker_rx, self.protocol_version);
 
         // Note:
         // https://docs.rs/tokio/1.7.0/tokio/macro.select.html#runtime-characteristics
@@ -551,3 +652,232 @@ impl<ST: Stream<Item = PacketResult> + Unpin, SI: Sink<Packet, Error = EncodeErr
         debug!("Client ID {} task exit", self.client_id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{
+        resolve_topic_alias, Client, OutgoingData, UnconnectedClient, TOPIC_ALIAS_MAXIMUM,
+    };
+    use bytes::BytesMut;
+    use futures::{channel::mpsc, stream, SinkExt, StreamExt};
+    use mqtt_v5::{
+        codec::MqttCodec,
+        topic::Topic,
+        types::{
+            properties::TopicAlias, ConnectPacket, ConnectReason, DecodeError, DisconnectReason,
+            EncodeError, Packet, ProtocolVersion, PublishPacket, QoS,
+        },
+    };
+    use std::collections::HashMap;
+    use tokio::runtime::Runtime;
+    use tokio_util::codec::Encoder;
+
+    fn publish_packet(topic: Topic, topic_alias: Option<u16>) -> PublishPacket {
+        PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+
+            topic,
+            packet_id: None,
+
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: topic_alias.map(TopicAlias),
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            subscription_identifiers: vec![],
+            content_type: None,
+
+            payload: vec![].into(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_topic_alias() {
+        let mut topic_aliases = HashMap::new();
+        let topic: Topic = "home/kitchen/temperature".parse().unwrap();
+
+        // An unmapped alias with an empty topic is invalid.
+        let mut packet = publish_packet(Topic::empty(), Some(1));
+        assert_eq!(
+            resolve_topic_alias(&mut topic_aliases, &mut packet),
+            Err(DisconnectReason::TopicAliasInvalid)
+        );
+
+        // Map the alias, then use it with an empty topic.
+        let mut packet = publish_packet(topic.clone(), Some(1));
+        assert_eq!(resolve_topic_alias(&mut topic_aliases, &mut packet), Ok(()));
+        assert_eq!(packet.topic_alias, None);
+
+        let mut packet = publish_packet(Topic::empty(), Some(1));
+        assert_eq!(resolve_topic_alias(&mut topic_aliases, &mut packet), Ok(()));
+        assert_eq!(packet.topic, topic);
+
+        // Aliases can be remapped.
+        let other_topic: Topic = "home/kitchen/humidity".parse().unwrap();
+        let mut packet = publish_packet(other_topic.clone(), Some(1));
+        assert_eq!(resolve_topic_alias(&mut topic_aliases, &mut packet), Ok(()));
+
+        let mut packet = publish_packet(Topic::empty(), Some(1));
+        assert_eq!(resolve_topic_alias(&mut topic_aliases, &mut packet), Ok(()));
+        assert_eq!(packet.topic, other_topic);
+
+        // Aliases must be between 1 and the advertised maximum.
+        for alias in [0, TOPIC_ALIAS_MAXIMUM + 1] {
+            let mut packet = publish_packet(topic.clone(), Some(alias));
+            assert_eq!(
+                resolve_topic_alias(&mut topic_aliases, &mut packet),
+                Err(DisconnectReason::TopicAliasInvalid)
+            );
+        }
+
+        // Packets without an alias are left untouched.
+        let mut packet = publish_packet(topic.clone(), None);
+        assert_eq!(resolve_topic_alias(&mut topic_aliases, &mut packet), Ok(()));
+        assert_eq!(packet.topic, topic);
+    }
+
+    #[test]
+    fn test_encode_outgoing_data() {
+        let mut codec = MqttCodec::new();
+        let mut bytes = BytesMut::new();
+
+        // A packet encoded by the codec followed by the two halves of an encoded one.
+        let packet = publish_packet("sensors/temperature".parse().unwrap(), None);
+        let data = OutgoingData::Packet(Packet::Publish(packet.clone()));
+        Encoder::encode(&mut codec, data, &mut bytes).unwrap();
+        let encoded = bytes.clone().freeze();
+        Encoder::encode(&mut codec, OutgoingData::Encoded(encoded.slice(..2)), &mut bytes).unwrap();
+        Encoder::encode(&mut codec, OutgoingData::Encoded(encoded.slice(2..)), &mut bytes).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(codec.decode(&mut bytes).unwrap(), Some(Packet::Publish(packet.clone())));
+        }
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_handshake_refused() {
+        let packet_stream = stream::iter(vec![Err(DecodeError::InvalidProtocolVersion)]);
+        let (packet_sink, mut sent_packets) = mpsc::unbounded::<OutgoingData>();
+        let packet_sink = packet_sink.sink_map_err(|_| EncodeError::BadTransport);
+        let (broker_tx, _broker_rx) = tokio::sync::mpsc::channel(1);
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = UnconnectedClient::new(packet_stream, packet_sink, broker_tx);
+            assert!(client.handshake().await.is_err());
+
+            match sent_packets.next().await {
+                Some(OutgoingData::Packet(Packet::ConnectAck(packet))) => {
+                    assert_eq!(packet.reason_code, ConnectReason::UnsupportedProtocolVersion)
+                },
+                packet => panic!("Expected a CONNACK packet, got {:?}", packet),
+            }
+        });
+    }
+
+    #[test]
+    fn test_decode_error_disconnect() {
+        let runtime = Runtime::new().unwrap();
+
+        for protocol_version in [ProtocolVersion::V311, ProtocolVersion::V500] {
+            let packet_stream = stream::iter(vec![Err(DecodeError::InvalidUtf8)]);
+            let (packet_sink, sent_packets) = mpsc::unbounded::<OutgoingData>();
+            let packet_sink = packet_sink.sink_map_err(|_| EncodeError::BadTransport);
+            let (broker_tx, _broker_rx) = tokio::sync::mpsc::channel(1);
+            let (self_tx, client_rx) = tokio::sync::mpsc::channel(1);
+
+            let sent_packets: Vec<_> = runtime.block_on(async {
+                let client = Client::new(
+                    0,
+                    "client_1".to_string(),
+                    protocol_version,
+                    None,
+                    packet_stream,
+                    packet_sink,
+                    broker_tx,
+                    client_rx,
+                    self_tx,
+                );
+                client.run().await;
+
+                sent_packets.collect().await
+            });
+
+            match protocol_version {
+                // Only MQTT 5 servers send DISCONNECT packets.
+                ProtocolVersion::V500 => match &sent_packets[..] {
+                    [OutgoingData::Packet(Packet::Disconnect(packet))] => {
+                        assert_eq!(packet.reason_code, DisconnectReason::MalformedPacket)
+                    },
+                    packets => panic!("Expected a DISCONNECT packet, got {:?}", packets),
+                },
+                _ => assert!(sent_packets.is_empty()),
+            }
+        }
+    }
+
+    fn connect_packet(protocol_version: ProtocolVersion, client_id: &str) -> ConnectPacket {
+        ConnectPacket {
+            protocol_name: protocol_version.protocol_name().to_string(),
+            protocol_version,
+            clean_start: true,
+            keep_alive: 0,
+
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            user_properties: vec![],
+            authentication_method: None,
+            authentication_data: None,
+
+            client_id: client_id.to_string(),
+            will: None,
+            user_name: None,
+            password: None,
+        }
+    }
+
+    #[test]
+    fn test_handshake_v310_client_id() {
+        let runtime = Runtime::new().unwrap();
+
+        for client_id in ["", "a_client_id_longer_than_23"] {
+            let connect_packet = connect_packet(ProtocolVersion::V310, client_id);
+            let packet_stream = stream::iter(vec![Ok(Packet::Connect(connect_packet))]);
+            let (packet_sink, mut sent_packets) = mpsc::unbounded::<OutgoingData>();
+            let packet_sink = packet_sink.sink_map_err(|_| EncodeError::BadTransport);
+            let (broker_tx, _broker_rx) = tokio::sync::mpsc::channel(1);
+
+            runtime.block_on(async {
+                let client = UnconnectedClient::new(packet_stream, packet_sink, broker_tx);
+                assert!(client.handshake().await.is_err());
+
+                match sent_packets.next().await {
+                    Some(OutgoingData::Packet(Packet::ConnectAck(packet))) => {
+                        assert_eq!(packet.reason_code, ConnectReason::ClientIdentifierNotValid)
+                    },
+                    packet => panic!("Expected a CONNACK packet, got {:?}", packet),
+                }
+            });
+        }
+
+        // Legacy clients with a valid client identifier are accepted.
+        let connect_packet = connect_packet(ProtocolVersion::V310, "legacy_gateway");
+        let packet_stream = stream::iter(vec![Ok(Packet::Connect(connect_packet))]);
+        let (packet_sink, _sent_packets) = mpsc::unbounded::<OutgoingData>();
+        let packet_sink = packet_sink.sink_map_err(|_| EncodeError::BadTransport);
+        let (broker_tx, _broker_rx) = tokio::sync::mpsc::channel(1);
+
+        runtime.block_on(async {
+            let client = UnconnectedClient::new(packet_stream, packet_sink, broker_tx);
+            assert!(client.handshake().await.is_ok());
+        });
+    }
+}
@@ -3,8 +3,9 @@ use std::{env, io};
 use futures::future::try_join_all;
 use log::{debug, info};
 use mqtt_v5_broker::{
-    broker::{Broker, BrokerMessage},
+    broker::{Broker, BrokerConfig, BrokerMessage},
     client,
+    plugin::Noop,
 };
 use tokio::{net::TcpListener, sync::mpsc::Sender, task};
 
@@ -14,25 +15,28 @@ const TCP_LISTENER_ADDR: &str = "0.0.0.0:1883";
 /// Websocket tcp address TODO: make this configurable
 const WEBSOCKET_TCP_LISTENER_ADDR: &str = "0.0.0.0:8080";
 
-async fn tcp_server_loop(broker_tx: Sender<BrokerMessage>) -> io::Result<()> {
+async fn tcp_server_loop(broker_tx: Sender<BrokerMessage>, config: BrokerConfig) -> io::Result<()> {
     info!("Listening on {}", TCP_LISTENER_ADDR);
     let listener = TcpListener::bind(TCP_LISTENER_ADDR).await?;
 
     loop {
         let (stream, addr) = listener.accept().await?;
         debug!("Client {} connected (tcp)", addr);
-        client::spawn(stream, broker_tx.clone());
+        client::spawn(stream, broker_tx.clone(), &config);
     }
 }
 
-async fn websocket_server_loop(broker_tx: Sender<BrokerMessage>) -> io::Result<()> {
+async fn websocket_server_loop(
+    broker_tx: Sender<BrokerMessage>,
+    config: BrokerConfig,
+) -> io::Result<()> {
     info!("Listening on {}", WEBSOCKET_TCP_LISTENER_ADDR);
     let listener = TcpListener::bind(WEBSOCKET_TCP_LISTENER_ADDR).await?;
 
     loop {
         let (socket, addr) = listener.accept().await?;
         debug!("Client {} connected (websocket)", addr);
-        client::spawn_websocket(socket, broker_tx.clone()).await;
+        client::spawn_websocket(socket, broker_tx.clone(), &config).await;
     }
 }
 
@@ -48,15 +52,16 @@ fn init_logging() {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     init_logging();
 
-    let broker = Broker::default();
+    let config = BrokerConfig::default();
+    let broker = Broker::with_plugin_and_config(Noop, config.clone());
     let broker_tx = broker.sender();
     let broker = task::spawn(async {
         broker.run().await;
         Ok(())
     });
 
-    let tcp_listener = task::spawn(tcp_server_loop(broker_tx.clone()));
-    let websocket_listener = task::spawn(websocket_server_loop(broker_tx));
+    let tcp_listener = task::spawn(tcp_server_loop(broker_tx.clone(), config.clone()));
+    let websocket_listener = task::spawn(websocket_server_loop(broker_tx, config));
 
     try_join_all([broker, tcp_listener, websocket_listener]).await?;
 
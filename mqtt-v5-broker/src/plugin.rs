@@ -1,3 +1,4 @@
+use crate::broker::Redirect;
 use log::{trace, warn};
 use mqtt_v5::types::{
     AuthenticatePacket, ConnectPacket, ConnectReason, PublishAckPacket, PublishAckReason,
@@ -9,10 +10,13 @@ pub struct Noop;
 
 /// Result of a authentication attempt
 pub enum AuthentificationResult {
-    /// Authentification reason
-    Reason(ConnectReason),
+    /// Authentification reason, with an optional human-readable reason string
+    /// for the client if the connection is refused.
+    Reason(ConnectReason, Option<String>),
     /// Send this auth packet to the client and wait for the response.
     Packet(AuthenticatePacket),
+    /// Refuse the connection and point the client to another server.
+    Redirect(Redirect),
 }
 
 /// Broker plugin
@@ -33,7 +37,6 @@ pub trait Plugin {
     fn on_publish_received_qos0(&mut self, packet: &PublishPacket) -> bool;
     /// Called on publish packets reception for QoS 1. Return if the packet should be published to the clients and
     /// the publish ack packet to be sent to the publisher.
-
     fn on_publish_received_qos1(
         &mut self,
         packet: &PublishPacket,
@@ -51,17 +54,18 @@ pub trait Plugin {
 impl Plugin for Noop {
     fn on_connect(&mut self, packet: &ConnectPacket) -> AuthentificationResult {
         // Just a hacky test...
-        if packet.user_name.is_some() && packet.user_name == packet.password {
-            AuthentificationResult::Reason(ConnectReason::Success)
-        } else {
-            AuthentificationResult::Reason(ConnectReason::BadUserNameOrPassword)
+        match (&packet.user_name, &packet.password) {
+            (Some(user_name), Some(password)) if user_name.as_bytes() == password => {
+                AuthentificationResult::Reason(ConnectReason::Success, None)
+            },
+            _ => AuthentificationResult::Reason(ConnectReason::BadUserNameOrPassword, None),
         }
     }
 
     fn on_disconnect(&mut self, _: &str) {}
 
     fn on_authenticate(&mut self, _: &AuthenticatePacket) -> AuthentificationResult {
-        AuthentificationResult::Reason(ConnectReason::Success)
+        AuthentificationResult::Reason(ConnectReason::Success, None)
     }
 
     fn on_subscribe(&mut self, packet: &SubscribePacket) -> SubscribeAckPacket {
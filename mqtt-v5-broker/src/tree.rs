@@ -1,7 +1,14 @@
-use mqtt_v5::topic::{Topic, TopicFilter, TopicLevel};
-use std::collections::{hash_map::Entry, HashMap};
-
-// TODO(bschwind) - Support shared subscriptions
+use mqtt_v5::{
+    topic::{Topic, TopicFilter, TopicLevel},
+    TOPIC_SEPARATOR,
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    hash::Hash,
+};
+
+// Shared subscriptions are stored under the filter which follows the share name,
+// picking a single receiver for each group is left to the broker.
 
 #[derive(Debug)]
 pub struct SubscriptionTreeNode<T> {
@@ -34,6 +41,32 @@ impl<T: std::fmt::Debug> SubscriptionTree<T> {
         self.root.matching_subscribers(topic)
     }
 
+    /// Like `matching_subscribers`, but groups the subscribers by `key`, so
+    /// that overlapping subscriptions of e.g. the same client end up together.
+    pub fn grouped_matching_subscribers<'a, K, F>(
+        &'a self,
+        topic: &Topic,
+        key: F,
+    ) -> HashMap<K, Vec<&'a T>>
+    where
+        K: Hash + Eq,
+        F: Fn(&'a T) -> K,
+    {
+        let mut groups: HashMap<K, Vec<&'a T>> = HashMap::new();
+
+        for subscriber in self.matching_subscribers(topic) {
+            groups.entry(key(subscriber)).or_default().push(subscriber);
+        }
+
+        groups
+    }
+
+    /// The subscribers inserted with exactly `topic_filter`, in contrast to all
+    /// subscribers matching a topic.
+    pub fn exact_subscribers(&self, topic_filter: &TopicFilter) -> impl Iterator<Item = &T> {
+        self.root.exact_subscribers(topic_filter)
+    }
+
     pub fn remove(&mut self, topic_filter: &TopicFilter, counter: u64) -> Option<T> {
         self.root.remove(topic_filter, counter)
     }
@@ -161,7 +194,7 @@ impl<T: std::fmt::Debug> SubscriptionTreeNode<T> {
 
         // Go up the stack, cleaning up empty nodes
         while let Some((stack_val, level_index)) = stack.pop() {
-            let mut tree = unsafe { &mut *stack_val };
+            let tree = unsafe { &mut *stack_val };
 
             let level = &levels[level_index];
 
@@ -189,13 +222,39 @@ impl<T: std::fmt::Debug> SubscriptionTreeNode<T> {
         return_val.map(|(_, val)| val)
     }
 
+    fn exact_subscribers(&self, topic_filter: &TopicFilter) -> impl Iterator<Item = &T> {
+        let mut current_tree = Some(self);
+        let mut multi_level = false;
+
+        for level in topic_filter.levels() {
+            current_tree = match level {
+                TopicLevel::SingleLevelWildcard => {
+                    current_tree.and_then(|tree| tree.single_level_wildcards.as_deref())
+                },
+                TopicLevel::MultiLevelWildcard => {
+                    multi_level = true;
+                    break;
+                },
+                TopicLevel::Concrete(concrete_topic_level) => current_tree
+                    .and_then(|tree| tree.concrete_topic_levels.get(concrete_topic_level)),
+            };
+        }
+
+        let subscribers = match current_tree {
+            Some(tree) if multi_level => &tree.multi_level_wildcards[..],
+            Some(tree) => &tree.subscribers[..],
+            None => &[],
+        };
+
+        subscribers.iter().map(|(_, subscriber)| subscriber)
+    }
+
     fn matching_subscribers(&self, topic: &Topic) -> impl Iterator<Item = &T> {
         let mut subscriptions = Vec::new();
         let mut tree_stack = vec![(self, 0)];
         let levels: Vec<TopicLevel> = topic.levels().collect();
 
-        while !tree_stack.is_empty() {
-            let (current_tree, current_level) = tree_stack.pop().unwrap();
+        while let Some((current_tree, current_level)) = tree_stack.pop() {
             let level = &levels[current_level];
 
             // Don't allow wildcard subscribers to receive messages
@@ -242,9 +301,131 @@ impl<T: std::fmt::Debug> SubscriptionTreeNode<T> {
     }
 }
 
+/// Values stored under a topic and looked up by topic filter, the reverse of a
+/// `SubscriptionTree`. Used for retained messages, so a subscription only visits
+/// the topics its filter matches.
+#[derive(Debug)]
+pub struct RetainedTree<T> {
+    root: RetainedTreeNode<T>,
+}
+
+#[derive(Debug)]
+struct RetainedTreeNode<T> {
+    value: Option<T>,
+    topic_levels: HashMap<String, RetainedTreeNode<T>>,
+}
+
+impl<T> RetainedTree<T> {
+    pub fn new() -> Self {
+        Self { root: RetainedTreeNode::new() }
+    }
+
+    pub fn insert(&mut self, topic: &Topic, value: T) -> Option<T> {
+        let mut current_tree = &mut self.root;
+
+        for level in topic.topic_name().split(TOPIC_SEPARATOR) {
+            current_tree = current_tree
+                .topic_levels
+                .entry(level.to_string())
+                .or_insert_with(RetainedTreeNode::new);
+        }
+
+        current_tree.value.replace(value)
+    }
+
+    pub fn remove(&mut self, topic: &Topic) -> Option<T> {
+        let levels: Vec<&str> = topic.topic_name().split(TOPIC_SEPARATOR).collect();
+        self.root.remove(&levels)
+    }
+
+    /// The values of all topics matching `topic_filter`.
+    pub fn matching_values(&self, topic_filter: &TopicFilter) -> Vec<&T> {
+        let levels: Vec<TopicLevel> = topic_filter.levels().collect();
+        let mut values = vec![];
+        self.root.matching_values(&levels, true, &mut values);
+
+        values
+    }
+}
+
+impl<T> RetainedTreeNode<T> {
+    fn new() -> Self {
+        Self { value: None, topic_levels: HashMap::new() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.topic_levels.is_empty()
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<T> {
+        let Some((level, remaining_levels)) = levels.split_first() else {
+            return self.value.take();
+        };
+
+        let sub_tree = self.topic_levels.get_mut(*level)?;
+        let value = sub_tree.remove(remaining_levels);
+
+        // Clean up empty nodes on the way back up.
+        if sub_tree.is_empty() {
+            self.topic_levels.remove(*level);
+        }
+
+        value
+    }
+
+    fn matching_values<'a>(
+        &'a self,
+        levels: &[TopicLevel],
+        first_level: bool,
+        values: &mut Vec<&'a T>,
+    ) {
+        let Some((level, remaining_levels)) = levels.split_first() else {
+            values.extend(&self.value);
+            return;
+        };
+
+        // Don't allow wildcards to match topics with leading dollar signs, like '$SYS/stats'
+        let wildcard_levels = self
+            .topic_levels
+            .iter()
+            .filter(|(topic_level, _)| !first_level || !topic_level.starts_with('$'))
+            .map(|(_, sub_tree)| sub_tree);
+
+        match level {
+            TopicLevel::Concrete(concrete_topic_level) => {
+                if let Some(sub_tree) = self.topic_levels.get(*concrete_topic_level) {
+                    sub_tree.matching_values(remaining_levels, false, values);
+                }
+            },
+            TopicLevel::SingleLevelWildcard => {
+                for sub_tree in wildcard_levels {
+                    sub_tree.matching_values(remaining_levels, false, values);
+                }
+            },
+            TopicLevel::MultiLevelWildcard => {
+                // The multi-level wildcard also matches the parent level.
+                values.extend(&self.value);
+
+                for sub_tree in wildcard_levels {
+                    sub_tree.all_values(values);
+                }
+            },
+        }
+    }
+
+    fn all_values<'a>(&'a self, values: &mut Vec<&'a T>) {
+        values.extend(&self.value);
+
+        for sub_tree in self.topic_levels.values() {
+            sub_tree.all_values(values);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::tree::SubscriptionTree;
+    use crate::tree::{RetainedTree, SubscriptionTree};
+    use mqtt_v5::topic::{Topic, TopicFilter};
     use std::{collections::HashSet, fmt::Debug, hash::Hash};
 
     fn assert_subscribers<T: Debug + Hash + Eq + Clone>(
@@ -293,6 +474,62 @@ mod tests {
         assert_subscribers(&sub_tree, "sport/tennis/player1/ranking", &[12]);
     }
 
+    #[test]
+    fn test_grouped_matching_subscribers() {
+        let mut sub_tree = SubscriptionTree::new();
+        sub_tree.insert(&"home/#".parse().unwrap(), ("client_1", 1));
+        sub_tree.insert(&"home/+/temperature".parse().unwrap(), ("client_1", 2));
+        sub_tree.insert(&"home/kitchen/temperature".parse().unwrap(), ("client_2", 3));
+        sub_tree.insert(&"office/#".parse().unwrap(), ("client_1", 4));
+
+        let groups = sub_tree.grouped_matching_subscribers(
+            &"home/kitchen/temperature".parse().unwrap(),
+            |(client_id, _)| *client_id,
+        );
+
+        assert_eq!(groups.len(), 2);
+
+        let mut client_1: Vec<_> = groups["client_1"].iter().map(|(_, id)| *id).collect();
+        client_1.sort_unstable();
+        assert_eq!(client_1, vec![1, 2]);
+
+        let client_2: Vec<_> = groups["client_2"].iter().map(|(_, id)| *id).collect();
+        assert_eq!(client_2, vec![3]);
+    }
+
+    #[test]
+    fn test_exact_subscribers() {
+        let mut sub_tree = SubscriptionTree::new();
+        sub_tree.insert(&"home/+/temperature".parse().unwrap(), 1);
+        sub_tree.insert(&"home/kitchen/temperature".parse().unwrap(), 2);
+        sub_tree.insert(&"home/#".parse().unwrap(), 3);
+        sub_tree.insert(&"$share/group_a/home/#".parse().unwrap(), 4);
+
+        let exact = |topic_filter: &str| -> Vec<i32> {
+            sub_tree.exact_subscribers(&topic_filter.parse().unwrap()).copied().collect()
+        };
+
+        assert_eq!(exact("home/+/temperature"), vec![1]);
+        assert_eq!(exact("home/kitchen/temperature"), vec![2]);
+        assert_eq!(exact("home/#"), vec![3, 4]);
+        assert_eq!(exact("home/+"), Vec::<i32>::new());
+        assert_eq!(exact("office/#"), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_shared_subscribers() {
+        let mut sub_tree = SubscriptionTree::new();
+        sub_tree.insert(&"$share/group_a/home/kitchen".parse().unwrap(), "sub_1");
+        sub_tree.insert(&"$share/group_b/home/+".parse().unwrap(), "sub_2");
+        sub_tree.insert(&"$share/group_a/home/#".parse().unwrap(), "sub_3");
+        sub_tree.insert(&"home/kitchen".parse().unwrap(), "sub_4");
+
+        assert_subscribers(&sub_tree, "home/kitchen", &["sub_1", "sub_2", "sub_3", "sub_4"]);
+        assert_subscribers(&sub_tree, "home/garage", &["sub_2", "sub_3"]);
+        assert_subscribers(&sub_tree, "home", &["sub_3"]);
+        assert_subscribers(&sub_tree, "office", &[]);
+    }
+
     #[test]
     fn test_remove() {
         let mut sub_tree = SubscriptionTree::new();
@@ -358,4 +595,71 @@ mod tests {
         assert_subscribers(&sub_tree, "$SYS/num-connections", &["sub_2", "sub_3", "sub_10"]);
         assert_subscribers(&sub_tree, "$SYS/server/stats", &["sub_3", "sub_3_1", "sub_10"]);
     }
+
+    #[test]
+    fn test_retained_matching_values() {
+        let topics = [
+            "home",
+            "home/kitchen",
+            "home/kitchen/temperature",
+            "home/bedroom/temperature",
+            "home//temperature",
+            "office/stairwell/temperature",
+            "$SYS/stats",
+            "$SYS/stats/uptime",
+        ];
+
+        let mut retained = RetainedTree::new();
+        for topic in &topics {
+            retained.insert(&topic.parse().unwrap(), *topic);
+        }
+
+        // The tree agrees with matching each topic against the filter.
+        for filter in &[
+            "#",
+            "+",
+            "home/#",
+            "home/+",
+            "home/+/temperature",
+            "+/+/temperature",
+            "home/kitchen",
+            "home/kitchen/#",
+            "office",
+            "$SYS/#",
+            "$SYS/+",
+            "+/stats",
+        ] {
+            let topic_filter: TopicFilter = filter.parse().unwrap();
+            let mut expected: Vec<_> = topics
+                .iter()
+                .copied()
+                .filter(|topic| topic_filter.matches(&topic.parse().unwrap()))
+                .collect();
+            expected.sort_unstable();
+
+            let mut actual: Vec<_> =
+                retained.matching_values(&topic_filter).into_iter().copied().collect();
+            actual.sort_unstable();
+
+            assert_eq!(actual, expected, "Topic filter {}", filter);
+        }
+    }
+
+    #[test]
+    fn test_retained_remove() {
+        let mut retained = RetainedTree::new();
+        let kitchen: Topic = "home/kitchen".parse().unwrap();
+        let temperature: Topic = "home/kitchen/temperature".parse().unwrap();
+
+        assert_eq!(retained.insert(&kitchen, 1), None);
+        assert_eq!(retained.insert(&temperature, 2), None);
+        assert_eq!(retained.insert(&temperature, 3), Some(2));
+
+        assert_eq!(retained.remove(&kitchen), Some(1));
+        assert_eq!(retained.remove(&kitchen), None);
+        assert_eq!(retained.matching_values(&"home/#".parse().unwrap()), vec![&3]);
+
+        assert_eq!(retained.remove(&temperature), Some(3));
+        assert!(retained.root.is_empty());
+    }
 }
@@ -1,15 +1,38 @@
-use crate::types::{
-    properties::*, AuthenticatePacket, AuthenticateReason, ConnectAckPacket, ConnectPacket,
-    ConnectReason, DecodeError, DisconnectPacket, DisconnectReason, FinalWill, Packet, PacketType,
-    ProtocolVersion, PublishAckPacket, PublishAckReason, PublishCompletePacket,
-    PublishCompleteReason, PublishPacket, PublishReceivedPacket, PublishReceivedReason,
-    PublishReleasePacket, PublishReleaseReason, QoS, RetainHandling, SubscribeAckPacket,
-    SubscribeAckReason, SubscribePacket, SubscriptionTopic, UnsubscribeAckPacket,
-    UnsubscribeAckReason, UnsubscribePacket, VariableByteInt,
+use crate::{
+    topic::Topic,
+    types::{
+        properties::*, AuthenticatePacket, AuthenticateReason, ByteStr, ConnectAckPacket,
+        ConnectPacket, ConnectReason, DecodeError, DisconnectPacket, DisconnectReason, FinalWill,
+        Packet, PacketType, ProtocolVersion, PublishAckPacket, PublishAckReason,
+        PublishCompletePacket, PublishCompleteReason, PublishPacket, PublishReceivedPacket,
+        PublishReceivedReason, PublishReleasePacket, PublishReleaseReason, QoS, RetainHandling,
+        SubscribeAckPacket, SubscribeAckReason, SubscribePacket, SubscriptionTopic,
+        UnsubscribeAckPacket, UnsubscribeAckReason, UnsubscribePacket, VariableByteInt,
+    },
 };
 use bytes::{Buf, Bytes, BytesMut};
 use std::{convert::TryFrom, io::Cursor};
 
+/// How strictly the decoder validates packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Properties which aren't allowed in a packet are mostly ignored, a repeated
+    /// property replaces the previous one, and reserved fixed header flags aren't checked.
+    Lenient,
+    /// Reject repeated properties, properties which aren't allowed in a packet and
+    /// invalid fixed header flags as protocol errors. Strings containing the null
+    /// character and will payloads which don't match their payload format indicator
+    /// are rejected as well.
+    Strict,
+}
+
+/// The packet, or the will of a CONNECT packet, which properties are decoded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PropertyContext {
+    Packet(PacketType),
+    Will,
+}
+
 macro_rules! return_if_none {
     ($x: expr) => {{
         let string_opt = $x;
@@ -66,8 +89,14 @@ macro_rules! read_variable_int {
 }
 
 macro_rules! read_string {
-    ($bytes: expr) => {{
-        return_if_none!(decode_string($bytes)?)
+    ($bytes: expr, $mode: expr) => {{
+        return_if_none!(decode_string($bytes, $mode)?)
+    }};
+}
+
+macro_rules! read_byte_str {
+    ($bytes: expr, $mode: expr) => {{
+        return_if_none!(decode_byte_str($bytes, $mode)?)
     }};
 }
 
@@ -78,22 +107,15 @@ macro_rules! read_binary_data {
 }
 
 macro_rules! read_string_pair {
-    ($bytes: expr) => {{
-        let string_key = read_string!($bytes);
-        let string_value = read_string!($bytes);
+    ($bytes: expr, $mode: expr) => {{
+        let string_key = read_byte_str!($bytes, $mode);
+        let string_value = read_byte_str!($bytes, $mode);
 
         (string_key, string_value)
     }};
 }
 
-macro_rules! read_property {
-    ($bytes: expr) => {{
-        let property_id = read_variable_int!($bytes);
-        return_if_none!(decode_property(property_id, $bytes)?)
-    }};
-}
-
-fn decode_variable_int(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<u32>, DecodeError> {
+fn decode_variable_int<B: Buf>(bytes: &mut B) -> Result<Option<u32>, DecodeError> {
     let mut multiplier = 1;
     let mut value: u32 = 0;
 
@@ -118,58 +140,54 @@ fn decode_variable_int(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<u32>,
     Ok(Some(value))
 }
 
-fn decode_string(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<String>, DecodeError> {
-    let str_size_bytes = read_u16!(bytes) as usize;
-
-    require_length!(bytes, str_size_bytes);
-
-    let position = bytes.position() as usize;
+fn decode_string(
+    bytes: &mut Cursor<Bytes>,
+    mode: DecodeMode,
+) -> Result<Option<String>, DecodeError> {
+    Ok(decode_byte_str(bytes, mode)?.map(String::from))
+}
 
-    // TODO - Use Cow<str> and from_utf8_lossy later for less copying
-    match String::from_utf8(bytes.get_ref()[position..(position + str_size_bytes)].into()) {
-        Ok(string) => {
-            bytes.advance(str_size_bytes);
-            Ok(Some(string))
-        },
-        Err(_) => Err(DecodeError::InvalidUtf8),
+/// Decode a string as a slice of the packet, without copying it. Strings must be
+/// well-formed UTF-8, which excludes the surrogate code points [MQTT-1.5.4-1].
+/// With `DecodeMode::Strict`, they must not contain the null character [MQTT-1.5.4-2].
+fn decode_byte_str(
+    bytes: &mut Cursor<Bytes>,
+    mode: DecodeMode,
+) -> Result<Option<ByteStr>, DecodeError> {
+    let str_bytes = read_binary_data!(bytes);
+
+    // In UTF-8, a zero byte only ever encodes U+0000.
+    if mode == DecodeMode::Strict && str_bytes.contains(&0) {
+        return Err(DecodeError::InvalidUtf8);
     }
+
+    ByteStr::from_utf8(str_bytes).map(Some).map_err(|_| DecodeError::InvalidUtf8)
 }
 
-fn decode_binary_data(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Bytes>, DecodeError> {
+fn decode_binary_data(bytes: &mut Cursor<Bytes>) -> Result<Option<Bytes>, DecodeError> {
     let data_size_bytes = read_u16!(bytes) as usize;
-    require_length!(bytes, data_size_bytes);
-
-    let position = bytes.position() as usize;
-
-    let payload_bytes =
-        BytesMut::from(&bytes.get_ref()[position..(position + data_size_bytes)]).freeze();
-    let result = Ok(Some(payload_bytes));
-    bytes.advance(data_size_bytes);
-
-    result
+    decode_binary_data_with_size(bytes, data_size_bytes)
 }
 
+/// Decode `size` bytes as a slice of the packet, without copying them.
 fn decode_binary_data_with_size(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     size: usize,
 ) -> Result<Option<Bytes>, DecodeError> {
     require_length!(bytes, size);
 
     let position = bytes.position() as usize;
-    let payload_bytes = BytesMut::from(&bytes.get_ref()[position..(position + size)]).freeze();
-    let result = Ok(Some(payload_bytes));
+    let payload_bytes = bytes.get_ref().slice(position..(position + size));
     bytes.advance(size);
 
-    result
+    Ok(Some(payload_bytes))
 }
 
 fn decode_property(
-    property_id: u32,
-    bytes: &mut Cursor<&mut BytesMut>,
+    property_type: PropertyType,
+    bytes: &mut Cursor<Bytes>,
+    mode: DecodeMode,
 ) -> Result<Option<Property>, DecodeError> {
-    let property_type =
-        PropertyType::try_from(property_id).map_err(|_| DecodeError::InvalidPropertyId)?;
-
     match property_type {
         PropertyType::PayloadFormatIndicator => {
             let format_indicator = read_u8!(bytes);
@@ -182,11 +200,11 @@ fn decode_property(
             ))))
         },
         PropertyType::ContentType => {
-            let content_type = read_string!(bytes);
+            let content_type = read_byte_str!(bytes, mode);
             Ok(Some(Property::ContentType(ContentType(content_type))))
         },
         PropertyType::ResponseTopic => {
-            let response_topic = read_string!(bytes);
+            let response_topic = read_byte_str!(bytes, mode);
             Ok(Some(Property::ResponseTopic(ResponseTopic(response_topic))))
         },
         PropertyType::CorrelationData => {
@@ -206,7 +224,7 @@ fn decode_property(
             ))))
         },
         PropertyType::AssignedClientIdentifier => {
-            let assigned_client_identifier = read_string!(bytes);
+            let assigned_client_identifier = read_string!(bytes, mode);
             Ok(Some(Property::AssignedClientIdentifier(AssignedClientIdentifier(
                 assigned_client_identifier,
             ))))
@@ -216,7 +234,7 @@ fn decode_property(
             Ok(Some(Property::ServerKeepAlive(ServerKeepAlive(server_keep_alive))))
         },
         PropertyType::AuthenticationMethod => {
-            let authentication_method = read_string!(bytes);
+            let authentication_method = read_string!(bytes, mode);
             Ok(Some(Property::AuthenticationMethod(AuthenticationMethod(authentication_method))))
         },
         PropertyType::AuthenticationData => {
@@ -240,15 +258,15 @@ fn decode_property(
             ))))
         },
         PropertyType::ResponseInformation => {
-            let response_information = read_string!(bytes);
+            let response_information = read_string!(bytes, mode);
             Ok(Some(Property::ResponseInformation(ResponseInformation(response_information))))
         },
         PropertyType::ServerReference => {
-            let server_reference = read_string!(bytes);
+            let server_reference = read_string!(bytes, mode);
             Ok(Some(Property::ServerReference(ServerReference(server_reference))))
         },
         PropertyType::ReasonString => {
-            let reason_string = read_string!(bytes);
+            let reason_string = read_string!(bytes, mode);
             Ok(Some(Property::ReasonString(ReasonString(reason_string))))
         },
         PropertyType::ReceiveMaximum => {
@@ -274,7 +292,7 @@ fn decode_property(
             Ok(Some(Property::RetainAvailable(RetainAvailable(retain_available))))
         },
         PropertyType::UserProperty => {
-            let (key, value) = read_string_pair!(bytes);
+            let (key, value) = read_string_pair!(bytes, mode);
             Ok(Some(Property::UserProperty(UserProperty(key, value))))
         },
         PropertyType::MaximumPacketSize => {
@@ -302,18 +320,89 @@ fn decode_property(
     }
 }
 
+/// Returns true if a property may be included in the given packet or will,
+/// as listed in section 2.2.2.2 of the MQTT 5 specification.
+fn is_property_allowed(property_type: PropertyType, context: PropertyContext) -> bool {
+    use PacketType::*;
+    use PropertyContext::{Packet, Will};
+
+    match property_type {
+        PropertyType::PayloadFormatIndicator
+        | PropertyType::MessageExpiryInterval
+        | PropertyType::ContentType
+        | PropertyType::ResponseTopic
+        | PropertyType::CorrelationData => matches!(context, Packet(Publish) | Will),
+        PropertyType::SubscriptionIdentifier => {
+            matches!(context, Packet(Publish) | Packet(Subscribe))
+        },
+        PropertyType::SessionExpiryInterval => {
+            matches!(context, Packet(Connect) | Packet(ConnectAck) | Packet(Disconnect))
+        },
+        PropertyType::AssignedClientIdentifier
+        | PropertyType::ServerKeepAlive
+        | PropertyType::ResponseInformation
+        | PropertyType::MaximumQos
+        | PropertyType::RetainAvailable
+        | PropertyType::WildcardSubscriptionAvailable
+        | PropertyType::SubscriptionIdentifierAvailable
+        | PropertyType::SharedSubscriptionAvailable => matches!(context, Packet(ConnectAck)),
+        PropertyType::AuthenticationMethod | PropertyType::AuthenticationData => {
+            matches!(context, Packet(Connect) | Packet(ConnectAck) | Packet(Authenticate))
+        },
+        PropertyType::RequestProblemInformation | PropertyType::RequestResponseInformation => {
+            matches!(context, Packet(Connect))
+        },
+        PropertyType::WillDelayInterval => matches!(context, Will),
+        PropertyType::ServerReference => matches!(context, Packet(ConnectAck) | Packet(Disconnect)),
+        PropertyType::ReasonString => matches!(
+            context,
+            Packet(ConnectAck)
+                | Packet(PublishAck)
+                | Packet(PublishReceived)
+                | Packet(PublishRelease)
+                | Packet(PublishComplete)
+                | Packet(SubscribeAck)
+                | Packet(UnsubscribeAck)
+                | Packet(Disconnect)
+                | Packet(Authenticate)
+        ),
+        PropertyType::ReceiveMaximum
+        | PropertyType::TopicAliasMaximum
+        | PropertyType::MaximumPacketSize => {
+            matches!(context, Packet(Connect) | Packet(ConnectAck))
+        },
+        PropertyType::TopicAlias => matches!(context, Packet(Publish)),
+        PropertyType::UserProperty => true,
+    }
+}
+
+/// Returns true if a property may be included more than once in the given packet or will.
+fn is_property_repeatable(property_type: PropertyType, context: PropertyContext) -> bool {
+    match property_type {
+        PropertyType::UserProperty => true,
+        PropertyType::SubscriptionIdentifier => {
+            context == PropertyContext::Packet(PacketType::Publish)
+        },
+        _ => false,
+    }
+}
+
 fn decode_properties<F: FnMut(Property)>(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
+    mode: DecodeMode,
+    context: PropertyContext,
     mut closure: F,
 ) -> Result<Option<()>, DecodeError> {
-    try_decode_properties(bytes, |property| {
+    try_decode_properties(bytes, mode, context, |property| {
         closure(property);
         Ok(())
     })
 }
 
 fn try_decode_properties<F: FnMut(Property) -> Result<(), DecodeError>>(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
+    mode: DecodeMode,
+    context: PropertyContext,
     mut closure: F,
 ) -> Result<Option<()>, DecodeError> {
     let property_length = read_variable_int!(bytes);
@@ -325,6 +414,8 @@ fn try_decode_properties<F: FnMut(Property) -> Result<(), DecodeError>>(
     require_length!(bytes, property_length as usize);
 
     let start_cursor_pos = bytes.position();
+    // A bit for each property identifier that was decoded already.
+    let mut decoded_properties: u64 = 0;
 
     loop {
         let cursor_pos = bytes.position();
@@ -333,15 +424,36 @@ fn try_decode_properties<F: FnMut(Property) -> Result<(), DecodeError>>(
             break;
         }
 
-        let property = read_property!(bytes);
+        let property_id = read_variable_int!(bytes);
+        let property_type =
+            PropertyType::try_from(property_id).map_err(|_| DecodeError::InvalidPropertyId)?;
+
+        if mode == DecodeMode::Strict {
+            if !is_property_allowed(property_type, context) {
+                return Err(DecodeError::InvalidPropertyForPacket);
+            }
+
+            let property_bit = 1 << property_id;
+            if decoded_properties & property_bit != 0
+                && !is_property_repeatable(property_type, context)
+            {
+                return Err(DecodeError::DuplicateProperty);
+            }
+            decoded_properties |= property_bit;
+        }
+
+        let property = return_if_none!(decode_property(property_type, bytes, mode)?);
         closure(property)?;
     }
 
     Ok(Some(()))
 }
 
-fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, DecodeError> {
-    let protocol_name = read_string!(bytes);
+fn decode_connect(
+    bytes: &mut Cursor<Bytes>,
+    mode: DecodeMode,
+) -> Result<Option<Packet>, DecodeError> {
+    let protocol_name = read_string!(bytes, mode);
     let protocol_level = read_u8!(bytes);
     let connect_flags = read_u8!(bytes);
     let keep_alive = read_u16!(bytes);
@@ -360,20 +472,27 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
     let mut authentication_data = None;
 
     if protocol_version == ProtocolVersion::V500 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::SessionExpiryInterval(p) => session_expiry_interval = Some(p),
-                Property::ReceiveMaximum(p) => receive_maximum = Some(p),
-                Property::MaximumPacketSize(p) => maximum_packet_size = Some(p),
-                Property::TopicAliasMaximum(p) => topic_alias_maximum = Some(p),
-                Property::RequestResponseInformation(p) => request_response_information = Some(p),
-                Property::RequestProblemInformation(p) => request_problem_information = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                Property::AuthenticationMethod(p) => authentication_method = Some(p),
-                Property::AuthenticationData(p) => authentication_data = Some(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::Connect),
+            |property| {
+                match property {
+                    Property::SessionExpiryInterval(p) => session_expiry_interval = Some(p),
+                    Property::ReceiveMaximum(p) => receive_maximum = Some(p),
+                    Property::MaximumPacketSize(p) => maximum_packet_size = Some(p),
+                    Property::TopicAliasMaximum(p) => topic_alias_maximum = Some(p),
+                    Property::RequestResponseInformation(p) => {
+                        request_response_information = Some(p)
+                    },
+                    Property::RequestProblemInformation(p) => request_problem_information = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    Property::AuthenticationMethod(p) => authentication_method = Some(p),
+                    Property::AuthenticationData(p) => authentication_data = Some(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     // Start payload
@@ -385,7 +504,22 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
     let has_password = connect_flags & 0b0100_0000 == 0b0100_0000;
     let has_user_name = connect_flags & 0b1000_0000 == 0b1000_0000;
 
-    let client_id = read_string!(bytes);
+    // The reserved flag must be 0 [MQTT-3.1.2-3].
+    if connect_flags & 0b0000_0001 != 0 {
+        return Err(DecodeError::InvalidConnectFlags);
+    }
+
+    // The will QoS and retain flags must be 0 without a will [MQTT-3.1.2-11, MQTT-3.1.2-13].
+    if !has_will && (will_qos != QoS::AtMostOnce || retain_will) {
+        return Err(DecodeError::InvalidConnectFlags);
+    }
+
+    // Only MQTT 5 allows a password without a user name [MQTT-3.1.2-22].
+    if has_password && !has_user_name && protocol_version != ProtocolVersion::V500 {
+        return Err(DecodeError::PasswordWithoutUserName);
+    }
+
+    let client_id = read_string!(bytes, mode);
 
     let will = if has_will {
         let mut will_delay_interval = None;
@@ -397,7 +531,7 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
         let mut user_properties = vec![];
 
         if protocol_version == ProtocolVersion::V500 {
-            return_if_none!(decode_properties(bytes, |property| {
+            return_if_none!(decode_properties(bytes, mode, PropertyContext::Will, |property| {
                 match property {
                     Property::WillDelayInterval(p) => will_delay_interval = Some(p),
                     Property::PayloadFormatIndicator(p) => payload_format_indicator = Some(p),
@@ -411,10 +545,11 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
             })?);
         }
 
-        let topic = read_string!(bytes);
+        // The will is published like any other message, so its topic must be a valid topic name.
+        let topic = read_string!(bytes, mode).parse().map_err(DecodeError::InvalidTopic)?;
         let payload = read_binary_data!(bytes);
 
-        Some(FinalWill {
+        let will = FinalWill {
             topic,
             payload,
             qos: will_qos,
@@ -426,7 +561,13 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
             response_topic,
             correlation_data,
             user_properties,
-        })
+        };
+
+        if mode == DecodeMode::Strict && !will.has_valid_payload_format() {
+            return Err(DecodeError::InvalidPayloadFormat);
+        }
+
+        Some(will)
     } else {
         None
     };
@@ -435,11 +576,11 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
     let mut password = None;
 
     if has_user_name {
-        user_name = Some(read_string!(bytes));
+        user_name = Some(read_string!(bytes, mode));
     }
 
     if has_password {
-        password = Some(read_string!(bytes));
+        password = Some(read_binary_data!(bytes));
     }
 
     let packet = ConnectPacket {
@@ -466,15 +607,24 @@ fn decode_connect(bytes: &mut Cursor<&mut BytesMut>) -> Result<Option<Packet>, D
 }
 
 fn decode_connect_ack(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
+    // MQTT 3.1 has no session present flag, the byte is reserved.
     let flags = read_u8!(bytes);
-    let session_present = (flags & 0b0000_0001) == 0b0000_0001;
+    let session_present =
+        protocol_version != ProtocolVersion::V310 && (flags & 0b0000_0001) == 0b0000_0001;
 
     let reason_code_byte = read_u8!(bytes);
-    let reason_code =
-        ConnectReason::try_from(reason_code_byte).map_err(|_| DecodeError::InvalidConnectReason)?;
+    let reason_code = match protocol_version {
+        ProtocolVersion::V310 | ProtocolVersion::V311 => {
+            ConnectReason::from_v311_return_code(reason_code_byte)
+                .ok_or(DecodeError::InvalidConnectReason)?
+        },
+        ProtocolVersion::V500 => ConnectReason::try_from(reason_code_byte)
+            .map_err(|_| DecodeError::InvalidConnectReason)?,
+    };
 
     let mut session_expiry_interval = None;
     let mut receive_maximum = None;
@@ -495,32 +645,39 @@ fn decode_connect_ack(
     let mut authentication_data = None;
 
     if protocol_version == ProtocolVersion::V500 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::SessionExpiryInterval(p) => session_expiry_interval = Some(p),
-                Property::ReceiveMaximum(p) => receive_maximum = Some(p),
-                Property::MaximumQos(p) => maximum_qos = Some(p),
-                Property::RetainAvailable(p) => retain_available = Some(p),
-                Property::MaximumPacketSize(p) => maximum_packet_size = Some(p),
-                Property::AssignedClientIdentifier(p) => assigned_client_identifier = Some(p),
-                Property::TopicAliasMaximum(p) => topic_alias_maximum = Some(p),
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                Property::WildcardSubscriptionAvailable(p) => {
-                    wildcard_subscription_available = Some(p)
-                },
-                Property::SubscriptionIdentifierAvailable(p) => {
-                    subscription_identifiers_available = Some(p)
-                },
-                Property::SharedSubscriptionAvailable(p) => shared_subscription_available = Some(p),
-                Property::ServerKeepAlive(p) => server_keep_alive = Some(p),
-                Property::ResponseInformation(p) => response_information = Some(p),
-                Property::ServerReference(p) => server_reference = Some(p),
-                Property::AuthenticationMethod(p) => authentication_method = Some(p),
-                Property::AuthenticationData(p) => authentication_data = Some(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::ConnectAck),
+            |property| {
+                match property {
+                    Property::SessionExpiryInterval(p) => session_expiry_interval = Some(p),
+                    Property::ReceiveMaximum(p) => receive_maximum = Some(p),
+                    Property::MaximumQos(p) => maximum_qos = Some(p),
+                    Property::RetainAvailable(p) => retain_available = Some(p),
+                    Property::MaximumPacketSize(p) => maximum_packet_size = Some(p),
+                    Property::AssignedClientIdentifier(p) => assigned_client_identifier = Some(p),
+                    Property::TopicAliasMaximum(p) => topic_alias_maximum = Some(p),
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    Property::WildcardSubscriptionAvailable(p) => {
+                        wildcard_subscription_available = Some(p)
+                    },
+                    Property::SubscriptionIdentifierAvailable(p) => {
+                        subscription_identifiers_available = Some(p)
+                    },
+                    Property::SharedSubscriptionAvailable(p) => {
+                        shared_subscription_available = Some(p)
+                    },
+                    Property::ServerKeepAlive(p) => server_keep_alive = Some(p),
+                    Property::ResponseInformation(p) => response_information = Some(p),
+                    Property::ServerReference(p) => server_reference = Some(p),
+                    Property::AuthenticationMethod(p) => authentication_method = Some(p),
+                    Property::AuthenticationData(p) => authentication_data = Some(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = ConnectAckPacket {
@@ -549,10 +706,11 @@ fn decode_connect_ack(
 }
 
 fn decode_publish(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     first_byte: u8,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let is_duplicate = (first_byte & 0b0000_1000) == 0b0000_1000;
     let qos_val = (first_byte & 0b0000_0110) >> 1;
@@ -562,8 +720,7 @@ fn decode_publish(
     // Variable header start
     let start_cursor_pos = bytes.position();
 
-    let topic_str = read_string!(bytes);
-    let topic = topic_str.parse().map_err(DecodeError::InvalidTopic)?;
+    let topic_str = read_byte_str!(bytes, mode);
 
     let packet_id = match qos {
         QoS::AtMostOnce => None,
@@ -580,50 +737,62 @@ fn decode_publish(
     let mut content_type = None;
 
     if protocol_version == ProtocolVersion::V500 {
-        try_decode_properties(bytes, |property| match property {
-            Property::PayloadFormatIndicator(p) => {
-                payload_format_indicator = Some(p);
-                Ok(())
-            },
-            Property::MessageExpiryInterval(p) => {
-                message_expiry_interval = Some(p);
-                Ok(())
-            },
-            Property::TopicAlias(p) => {
-                topic_alias = Some(p);
-                Ok(())
-            },
-            Property::ResponseTopic(p) => {
-                response_topic = Some(p);
-                Ok(())
-            },
-            Property::CorrelationData(p) => {
-                correlation_data = Some(p);
-                Ok(())
-            },
-            Property::UserProperty(p) => {
-                user_properties.push(p);
-                Ok(())
-            },
-            Property::SubscriptionIdentifier(SubscriptionIdentifier(VariableByteInt(0))) => {
-                Err(DecodeError::InvalidSubscriptionIdentifier)
-            },
-            Property::SubscriptionIdentifier(p) => {
-                subscription_identifiers.get_or_insert(Vec::new()).push(p);
-                Ok(())
-            },
-            Property::ContentType(p) => {
-                content_type = Some(p);
-                Ok(())
+        try_decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::Publish),
+            |property| match property {
+                Property::PayloadFormatIndicator(p) => {
+                    payload_format_indicator = Some(p);
+                    Ok(())
+                },
+                Property::MessageExpiryInterval(p) => {
+                    message_expiry_interval = Some(p);
+                    Ok(())
+                },
+                Property::TopicAlias(p) => {
+                    topic_alias = Some(p);
+                    Ok(())
+                },
+                Property::ResponseTopic(p) => {
+                    response_topic = Some(p);
+                    Ok(())
+                },
+                Property::CorrelationData(p) => {
+                    correlation_data = Some(p);
+                    Ok(())
+                },
+                Property::UserProperty(p) => {
+                    user_properties.push(p);
+                    Ok(())
+                },
+                Property::SubscriptionIdentifier(SubscriptionIdentifier(VariableByteInt(0))) => {
+                    Err(DecodeError::InvalidSubscriptionIdentifier)
+                },
+                Property::SubscriptionIdentifier(p) => {
+                    subscription_identifiers.get_or_insert(Vec::new()).push(p);
+                    Ok(())
+                },
+                Property::ContentType(p) => {
+                    content_type = Some(p);
+                    Ok(())
+                },
+                _ => Err(DecodeError::InvalidPropertyForPacket),
             },
-            _ => Err(DecodeError::InvalidPropertyForPacket),
-        })?;
+        )?;
     }
 
     let end_cursor_pos = bytes.position();
     let variable_header_size = (end_cursor_pos - start_cursor_pos) as u32;
     // Variable header end
 
+    // The topic name can be empty if the packet carries a topic alias [MQTT-3.3.2-8]
+    let topic = if topic_str.is_empty() && topic_alias.is_some() {
+        Topic::empty()
+    } else {
+        Topic::from_byte_str(topic_str).map_err(DecodeError::InvalidTopic)?
+    };
+
     if remaining_packet_length < variable_header_size {
         return Err(DecodeError::InvalidRemainingLength);
     }
@@ -656,9 +825,10 @@ fn decode_publish(
 }
 
 fn decode_publish_ack(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let packet_id = read_u16!(bytes);
 
@@ -679,13 +849,18 @@ fn decode_publish_ack(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 && remaining_packet_length >= 4 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::PublishAck),
+            |property| {
+                match property {
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = PublishAckPacket { packet_id, reason_code, reason_string, user_properties };
@@ -694,9 +869,10 @@ fn decode_publish_ack(
 }
 
 fn decode_publish_received(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let packet_id = read_u16!(bytes);
 
@@ -717,13 +893,18 @@ fn decode_publish_received(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 && remaining_packet_length >= 4 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::PublishReceived),
+            |property| {
+                match property {
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = PublishReceivedPacket { packet_id, reason_code, reason_string, user_properties };
@@ -732,9 +913,10 @@ fn decode_publish_received(
 }
 
 fn decode_publish_release(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let packet_id = read_u16!(bytes);
 
@@ -755,13 +937,18 @@ fn decode_publish_release(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 && remaining_packet_length >= 4 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::PublishRelease),
+            |property| {
+                match property {
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = PublishReleasePacket { packet_id, reason_code, reason_string, user_properties };
@@ -770,9 +957,10 @@ fn decode_publish_release(
 }
 
 fn decode_publish_complete(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let packet_id = read_u16!(bytes);
 
@@ -793,13 +981,18 @@ fn decode_publish_complete(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 && remaining_packet_length >= 4 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::PublishComplete),
+            |property| {
+                match property {
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = PublishCompletePacket { packet_id, reason_code, reason_string, user_properties };
@@ -808,9 +1001,10 @@ fn decode_publish_complete(
 }
 
 fn decode_subscribe(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let start_cursor_pos = bytes.position();
 
@@ -820,27 +1014,32 @@ fn decode_subscribe(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 {
-        try_decode_properties(bytes, |property| {
-            match property {
-                // [MQTT-3.8.2.1.2] The subscription identifier is allowed exactly once
-                Property::SubscriptionIdentifier(_) if subscription_identifier.is_some() => {
-                    Err(DecodeError::InvalidSubscriptionIdentifier)
-                },
-                // [MQTT-3.8.2.1.2] The subscription identifier must not be 0
-                Property::SubscriptionIdentifier(SubscriptionIdentifier(VariableByteInt(0))) => {
-                    Err(DecodeError::InvalidSubscriptionIdentifier)
-                },
-                Property::SubscriptionIdentifier(p) => {
-                    subscription_identifier = Some(p);
-                    Ok(())
-                },
-                Property::UserProperty(p) => {
-                    user_properties.push(p);
-                    Ok(())
-                },
-                _ => Err(DecodeError::InvalidPropertyForPacket),
-            }
-        })?;
+        try_decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::Subscribe),
+            |property| {
+                match property {
+                    // [MQTT-3.8.2.1.2] The subscription identifier is allowed exactly once
+                    Property::SubscriptionIdentifier(_) if subscription_identifier.is_some() => {
+                        Err(DecodeError::InvalidSubscriptionIdentifier)
+                    },
+                    // [MQTT-3.8.2.1.2] The subscription identifier must not be 0
+                    Property::SubscriptionIdentifier(SubscriptionIdentifier(VariableByteInt(
+                        0,
+                    ))) => Err(DecodeError::InvalidSubscriptionIdentifier),
+                    Property::SubscriptionIdentifier(p) => {
+                        subscription_identifier = Some(p);
+                        Ok(())
+                    },
+                    Property::UserProperty(p) => {
+                        user_properties.push(p);
+                        Ok(())
+                    },
+                    _ => Err(DecodeError::InvalidPropertyForPacket),
+                }
+            },
+        )?;
     }
 
     let variable_header_size = (bytes.position() - start_cursor_pos) as u32;
@@ -859,7 +1058,7 @@ fn decode_subscribe(
 
         let start_cursor_pos = bytes.position();
 
-        let topic_filter_str = read_string!(bytes);
+        let topic_filter_str = read_string!(bytes, mode);
         let topic_filter = topic_filter_str.parse().map_err(DecodeError::InvalidTopicFilter)?;
 
         let options_byte = read_u8!(bytes);
@@ -899,9 +1098,10 @@ fn decode_subscribe(
 }
 
 fn decode_subscribe_ack(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let start_cursor_pos = bytes.position();
 
@@ -911,13 +1111,18 @@ fn decode_subscribe_ack(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::SubscribeAck),
+            |property| {
+                match property {
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let variable_header_size = (bytes.position() - start_cursor_pos) as u32;
@@ -940,9 +1145,10 @@ fn decode_subscribe_ack(
 }
 
 fn decode_unsubscribe(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let start_cursor_pos = bytes.position();
 
@@ -951,11 +1157,16 @@ fn decode_unsubscribe(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 {
-        return_if_none!(decode_properties(bytes, |property| {
-            if let Property::UserProperty(p) = property {
-                user_properties.push(p);
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::Unsubscribe),
+            |property| {
+                if let Property::UserProperty(p) = property {
+                    user_properties.push(p);
+                }
             }
-        })?);
+        )?);
     }
 
     let variable_header_size = (bytes.position() - start_cursor_pos) as u32;
@@ -974,7 +1185,7 @@ fn decode_unsubscribe(
 
         let start_cursor_pos = bytes.position();
 
-        let topic_filter_str = read_string!(bytes);
+        let topic_filter_str = read_string!(bytes, mode);
         let topic_filter = topic_filter_str.parse().map_err(DecodeError::InvalidTopicFilter)?;
         topic_filters.push(topic_filter);
 
@@ -988,9 +1199,10 @@ fn decode_unsubscribe(
 }
 
 fn decode_unsubscribe_ack(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     let start_cursor_pos = bytes.position();
 
@@ -1000,13 +1212,18 @@ fn decode_unsubscribe_ack(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::UnsubscribeAck),
+            |property| {
+                match property {
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let variable_header_size = (bytes.position() - start_cursor_pos) as u32;
@@ -1029,9 +1246,10 @@ fn decode_unsubscribe_ack(
 }
 
 fn decode_disconnect(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     if remaining_packet_length == 0 {
         return Ok(Some(Packet::Disconnect(DisconnectPacket {
@@ -1053,15 +1271,20 @@ fn decode_disconnect(
     let mut server_reference = None;
 
     if protocol_version == ProtocolVersion::V500 && remaining_packet_length >= 2 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::SessionExpiryInterval(p) => session_expiry_interval = Some(p),
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                Property::ServerReference(p) => server_reference = Some(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::Disconnect),
+            |property| {
+                match property {
+                    Property::SessionExpiryInterval(p) => session_expiry_interval = Some(p),
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    Property::ServerReference(p) => server_reference = Some(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = DisconnectPacket {
@@ -1076,9 +1299,10 @@ fn decode_disconnect(
 }
 
 fn decode_authenticate(
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     protocol_version: ProtocolVersion,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     if remaining_packet_length == 0 {
         return Ok(Some(Packet::Authenticate(AuthenticatePacket {
@@ -1100,15 +1324,20 @@ fn decode_authenticate(
     let mut user_properties = vec![];
 
     if protocol_version == ProtocolVersion::V500 && remaining_packet_length >= 2 {
-        return_if_none!(decode_properties(bytes, |property| {
-            match property {
-                Property::AuthenticationMethod(p) => authentication_method = Some(p),
-                Property::AuthenticationData(p) => authentication_data = Some(p),
-                Property::ReasonString(p) => reason_string = Some(p),
-                Property::UserProperty(p) => user_properties.push(p),
-                _ => {}, // Invalid property for packet
+        return_if_none!(decode_properties(
+            bytes,
+            mode,
+            PropertyContext::Packet(PacketType::Authenticate),
+            |property| {
+                match property {
+                    Property::AuthenticationMethod(p) => authentication_method = Some(p),
+                    Property::AuthenticationData(p) => authentication_data = Some(p),
+                    Property::ReasonString(p) => reason_string = Some(p),
+                    Property::UserProperty(p) => user_properties.push(p),
+                    _ => {}, // Invalid property for packet
+                }
             }
-        })?);
+        )?);
     }
 
     let packet = AuthenticatePacket {
@@ -1125,45 +1354,48 @@ fn decode_authenticate(
 fn decode_packet(
     protocol_version: ProtocolVersion,
     packet_type: &PacketType,
-    bytes: &mut Cursor<&mut BytesMut>,
+    bytes: &mut Cursor<Bytes>,
     remaining_packet_length: u32,
     first_byte: u8,
+    mode: DecodeMode,
 ) -> Result<Option<Packet>, DecodeError> {
     match packet_type {
-        PacketType::Connect => decode_connect(bytes),
-        PacketType::ConnectAck => decode_connect_ack(bytes, protocol_version),
+        PacketType::Connect => decode_connect(bytes, mode),
+        PacketType::ConnectAck => decode_connect_ack(bytes, protocol_version, mode),
         PacketType::Publish => {
-            decode_publish(bytes, first_byte, remaining_packet_length, protocol_version)
+            decode_publish(bytes, first_byte, remaining_packet_length, protocol_version, mode)
         },
         PacketType::PublishAck => {
-            decode_publish_ack(bytes, remaining_packet_length, protocol_version)
+            decode_publish_ack(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::PublishReceived => {
-            decode_publish_received(bytes, remaining_packet_length, protocol_version)
+            decode_publish_received(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::PublishRelease => {
-            decode_publish_release(bytes, remaining_packet_length, protocol_version)
+            decode_publish_release(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::PublishComplete => {
-            decode_publish_complete(bytes, remaining_packet_length, protocol_version)
+            decode_publish_complete(bytes, remaining_packet_length, protocol_version, mode)
+        },
+        PacketType::Subscribe => {
+            decode_subscribe(bytes, remaining_packet_length, protocol_version, mode)
         },
-        PacketType::Subscribe => decode_subscribe(bytes, remaining_packet_length, protocol_version),
         PacketType::SubscribeAck => {
-            decode_subscribe_ack(bytes, remaining_packet_length, protocol_version)
+            decode_subscribe_ack(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::Unsubscribe => {
-            decode_unsubscribe(bytes, remaining_packet_length, protocol_version)
+            decode_unsubscribe(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::UnsubscribeAck => {
-            decode_unsubscribe_ack(bytes, remaining_packet_length, protocol_version)
+            decode_unsubscribe_ack(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::PingRequest => Ok(Some(Packet::PingRequest)),
         PacketType::PingResponse => Ok(Some(Packet::PingResponse)),
         PacketType::Disconnect => {
-            decode_disconnect(bytes, remaining_packet_length, protocol_version)
+            decode_disconnect(bytes, remaining_packet_length, protocol_version, mode)
         },
         PacketType::Authenticate => {
-            decode_authenticate(bytes, remaining_packet_length, protocol_version)
+            decode_authenticate(bytes, remaining_packet_length, protocol_version, mode)
         },
     }
 }
@@ -1172,36 +1404,125 @@ pub fn decode_mqtt(
     bytes: &mut BytesMut,
     protocol_version: ProtocolVersion,
 ) -> Result<Option<Packet>, DecodeError> {
-    let mut bytes = Cursor::new(bytes);
+    decode_mqtt_with_max_packet_size(bytes, protocol_version, None)
+}
+
+/// Decode an MQTT packet, failing with `DecodeError::PacketTooLarge` as soon as
+/// the fixed header shows the packet is larger than `maximum_packet_size` bytes.
+pub fn decode_mqtt_with_max_packet_size(
+    bytes: &mut BytesMut,
+    protocol_version: ProtocolVersion,
+    maximum_packet_size: Option<u32>,
+) -> Result<Option<Packet>, DecodeError> {
+    let fixed_header = return_if_none!(decode_fixed_header(bytes, maximum_packet_size)?);
+
+    if bytes.len() < fixed_header.packet_len() {
+        // If we don't have the full payload, just bail
+        return Ok(None);
+    }
+
+    decode_packet_body(bytes, &fixed_header, protocol_version, DecodeMode::Lenient).map(Some)
+}
+
+/// The fixed header at the start of every MQTT packet.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FixedHeader {
+    pub packet_type: PacketType,
+    pub first_byte: u8,
+    pub header_len: usize,
+    pub remaining_length: u32,
+}
+
+impl FixedHeader {
+    /// The length of the whole packet in bytes, including the fixed header.
+    pub fn packet_len(&self) -> usize {
+        self.header_len + self.remaining_length as usize
+    }
+}
+
+/// Decode the fixed header at the start of `bytes` without consuming it.
+/// Fails with `DecodeError::PacketTooLarge` if the packet is larger than
+/// `maximum_packet_size` bytes, before its payload is buffered.
+pub fn decode_fixed_header(
+    bytes: &mut BytesMut,
+    maximum_packet_size: Option<u32>,
+) -> Result<Option<FixedHeader>, DecodeError> {
+    let mut bytes = &bytes[..];
+    let packet_len = bytes.len();
     let first_byte = read_u8!(bytes);
 
     let first_byte_val = (first_byte & 0b1111_0000) >> 4;
     let packet_type =
         PacketType::try_from(first_byte_val).map_err(|_| DecodeError::InvalidPacketType)?;
-    let remaining_packet_length = read_variable_int!(&mut bytes);
+    let remaining_length = read_variable_int!(&mut bytes);
 
-    let cursor_pos = bytes.position() as usize;
-    let remaining_buffer_amount = bytes.get_ref().len() - cursor_pos;
+    let fixed_header = FixedHeader {
+        packet_type,
+        first_byte,
+        header_len: packet_len - bytes.remaining(),
+        remaining_length,
+    };
 
-    if remaining_buffer_amount < remaining_packet_length as usize {
-        // If we don't have the full payload, just bail
-        return Ok(None);
+    if let Some(maximum_packet_size) = maximum_packet_size {
+        if fixed_header.packet_len() as u64 > maximum_packet_size as u64 {
+            return Err(DecodeError::PacketTooLarge);
+        }
     }
 
-    let packet = return_if_none!(decode_packet(
-        protocol_version,
-        &packet_type,
-        &mut bytes,
-        remaining_packet_length,
-        first_byte
-    )?);
+    Ok(Some(fixed_header))
+}
 
-    let cursor_pos = bytes.position() as usize;
-    let bytes = bytes.into_inner();
+/// Read the protocol version from the body of a CONNECT packet without decoding the
+/// rest of it, so even an invalid CONNECT packet can be answered in the client's version.
+pub fn decode_connect_protocol_version(body: &[u8]) -> Option<ProtocolVersion> {
+    let protocol_name_len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let protocol_level = *body.get(2 + protocol_name_len)?;
 
-    let _rest = bytes.split_to(cursor_pos);
+    ProtocolVersion::try_from(protocol_level).ok()
+}
 
-    Ok(Some(packet))
+/// Returns true if the flags in the first byte of the fixed header are valid for
+/// the packet type. Flags which aren't used by a packet type are reserved [MQTT-2.1.3-1].
+fn has_valid_fixed_header_flags(fixed_header: &FixedHeader) -> bool {
+    let flags = fixed_header.first_byte & 0b0000_1111;
+
+    match fixed_header.packet_type {
+        // The DUP flag must be 0 for QoS 0 messages [MQTT-3.3.1-2].
+        PacketType::Publish => flags & 0b0000_1110 != 0b0000_1000,
+        PacketType::PublishRelease | PacketType::Subscribe | PacketType::Unsubscribe => {
+            flags == 0b0000_0010
+        },
+        _ => flags == 0,
+    }
+}
+
+/// Decode the packet with the given fixed header, once `bytes` holds all of it.
+/// The packet is removed from `bytes` and its body can't extend past the
+/// remaining length of the fixed header.
+pub fn decode_packet_body(
+    bytes: &mut BytesMut,
+    fixed_header: &FixedHeader,
+    protocol_version: ProtocolVersion,
+    mode: DecodeMode,
+) -> Result<Packet, DecodeError> {
+    if mode == DecodeMode::Strict && !has_valid_fixed_header_flags(fixed_header) {
+        bytes.advance(fixed_header.packet_len());
+        return Err(DecodeError::InvalidFixedHeaderFlags);
+    }
+
+    let packet_bytes = bytes.split_to(fixed_header.packet_len()).freeze();
+    let mut packet_bytes = Cursor::new(packet_bytes);
+    packet_bytes.set_position(fixed_header.header_len as u64);
+
+    decode_packet(
+        protocol_version,
+        &fixed_header.packet_type,
+        &mut packet_bytes,
+        fixed_header.remaining_length,
+        fixed_header.first_byte,
+        mode,
+    )?
+    .ok_or(DecodeError::InvalidRemainingLength)
 }
 
 #[cfg(test)]
@@ -1217,6 +1538,276 @@ mod tests {
         let _ = decode_mqtt(&mut bytes, ProtocolVersion::V500);
     }
 
+    #[test]
+    fn test_maximum_packet_size() {
+        // PUBLISH with a 1 byte topic and 3 bytes of payload, 10 bytes in total
+        let packet = [0x30, 0x08, 0x00, 0x01, 0x61, 0x00, 0x62, 0x63, 0x64, 0x65];
+
+        let mut bytes = BytesMut::from(packet.as_slice());
+        assert!(decode_mqtt_with_max_packet_size(&mut bytes, ProtocolVersion::V500, Some(10))
+            .unwrap()
+            .is_some());
+
+        let mut bytes = BytesMut::from(packet.as_slice());
+        assert!(matches!(
+            decode_mqtt_with_max_packet_size(&mut bytes, ProtocolVersion::V500, Some(9)),
+            Err(DecodeError::PacketTooLarge)
+        ));
+
+        // Only the fixed header is needed to reject a packet
+        let mut bytes = BytesMut::from([0x30, 0xff, 0xff, 0xff, 0x7f].as_slice());
+        assert!(matches!(
+            decode_mqtt_with_max_packet_size(&mut bytes, ProtocolVersion::V500, Some(1024)),
+            Err(DecodeError::PacketTooLarge)
+        ));
+    }
+
+    fn connect_bytes(protocol_level: u8, connect_flags: u8, payload: &[u8]) -> BytesMut {
+        let mut body =
+            vec![0x00, 0x04, b'M', b'Q', b'T', b'T', protocol_level, connect_flags, 0x00, 0x3C];
+        if protocol_level == ProtocolVersion::V500 as u8 {
+            body.push(0x00);
+        }
+        body.extend_from_slice(&[0x00, 0x01, b'a']);
+        body.extend_from_slice(payload);
+
+        let mut bytes = BytesMut::from(&[0x10, body.len() as u8][..]);
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    #[test]
+    fn test_decode_connect_flags() {
+        let v311 = ProtocolVersion::V311 as u8;
+        let decode = |mut bytes: BytesMut| decode_mqtt(&mut bytes, ProtocolVersion::V311);
+
+        assert!(decode(connect_bytes(v311, 0b0000_0010, &[])).unwrap().is_some());
+
+        // Reserved flag
+        assert!(matches!(
+            decode(connect_bytes(v311, 0b0000_0011, &[])),
+            Err(DecodeError::InvalidConnectFlags)
+        ));
+
+        // Will QoS and will retain without a will
+        assert!(matches!(
+            decode(connect_bytes(v311, 0b0000_1010, &[])),
+            Err(DecodeError::InvalidConnectFlags)
+        ));
+        assert!(matches!(
+            decode(connect_bytes(v311, 0b0010_0010, &[])),
+            Err(DecodeError::InvalidConnectFlags)
+        ));
+
+        // A password without a user name, which is only valid in MQTT 5
+        let password = [0x00, 0x02, 0xFF, 0x00];
+        let err = decode(connect_bytes(v311, 0b0100_0010, &password)).unwrap_err();
+        assert!(matches!(err, DecodeError::PasswordWithoutUserName));
+        assert_eq!(err.connect_reason(), Some(ConnectReason::BadUserNameOrPassword));
+
+        let mut bytes = connect_bytes(ProtocolVersion::V500 as u8, 0b0100_0010, &password);
+        match decode_mqtt(&mut bytes, ProtocolVersion::V500) {
+            Ok(Some(Packet::Connect(packet))) => {
+                assert_eq!(packet.user_name, None);
+                // Passwords are binary data, which doesn't have to be UTF-8.
+                assert_eq!(packet.password, Some(Bytes::from_static(&[0xFF, 0x00])));
+            },
+            result => panic!("Expected CONNECT, got {:?}", result),
+        }
+    }
+
+    fn decode_strict(bytes: &mut BytesMut) -> Result<Packet, DecodeError> {
+        let fixed_header = decode_fixed_header(bytes, None).unwrap().unwrap();
+        decode_packet_body(bytes, &fixed_header, ProtocolVersion::V500, DecodeMode::Strict)
+    }
+
+    #[test]
+    fn test_strict_duplicate_property() {
+        // V5 PUBACK with the reason strings "a" and "b".
+        let packet =
+            [0x40, 0x0C, 0x00, 0x01, 0x00, 0x08, 0x1F, 0x00, 0x01, b'a', 0x1F, 0x00, 0x01, b'b'];
+
+        let mut bytes = BytesMut::from(packet.as_slice());
+        match decode_mqtt(&mut bytes, ProtocolVersion::V500) {
+            Ok(Some(Packet::PublishAck(packet))) => {
+                assert_eq!(packet.reason_string, Some(ReasonString("b".to_string())));
+            },
+            result => panic!("Expected PUBACK, got {:?}", result),
+        }
+
+        let mut bytes = BytesMut::from(packet.as_slice());
+        let err = decode_strict(&mut bytes).unwrap_err();
+        assert!(matches!(err, DecodeError::DuplicateProperty));
+        assert_eq!(err.disconnect_reason(), Some(DisconnectReason::ProtocolError));
+
+        // User properties can be repeated.
+        let mut bytes = BytesMut::from(
+            &[
+                0x40, 0x12, 0x00, 0x01, 0x00, 0x0E, 0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v', 0x26,
+                0x00, 0x01, b'k', 0x00, 0x01, b'v',
+            ][..],
+        );
+        assert!(matches!(decode_strict(&mut bytes), Ok(Packet::PublishAck(_))));
+    }
+
+    #[test]
+    fn test_strict_property_for_packet() {
+        // V5 PUBACK with a topic alias.
+        let packet = [0x40, 0x07, 0x00, 0x01, 0x00, 0x03, 0x23, 0x00, 0x01];
+
+        let mut bytes = BytesMut::from(packet.as_slice());
+        assert!(decode_mqtt(&mut bytes, ProtocolVersion::V500).unwrap().is_some());
+
+        let mut bytes = BytesMut::from(packet.as_slice());
+        assert!(matches!(decode_strict(&mut bytes), Err(DecodeError::InvalidPropertyForPacket)));
+
+        // V5 CONNECT with a will delay interval, which is only allowed in the will properties.
+        let mut bytes = BytesMut::from(
+            &[
+                0x10, 0x12, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3C, 0x05, 0x18,
+                0x00, 0x00, 0x00, 0x0A, 0x00, 0x01, b'a',
+            ][..],
+        );
+        assert!(matches!(decode_strict(&mut bytes), Err(DecodeError::InvalidPropertyForPacket)));
+    }
+
+    #[test]
+    fn test_strict_fixed_header_flags() {
+        let invalid_packets: [&[u8]; 3] = [
+            // PINGREQ with a reserved flag set
+            &[0xC1, 0x00],
+            // SUBSCRIBE without its reserved flag
+            &[0x80, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, b'a', 0x00],
+            // PUBLISH with QoS 0 and the DUP flag set
+            &[0x38, 0x04, 0x00, 0x01, b'a', 0x00],
+        ];
+
+        for packet in &invalid_packets {
+            let mut bytes = BytesMut::from(*packet);
+            assert!(decode_mqtt(&mut bytes, ProtocolVersion::V500).unwrap().is_some());
+
+            // The invalid packet is skipped, so the following packet can be decoded.
+            let mut bytes = BytesMut::from(*packet);
+            bytes.extend_from_slice(&[0xC0, 0x00]);
+            assert!(matches!(decode_strict(&mut bytes), Err(DecodeError::InvalidFixedHeaderFlags)));
+            assert_eq!(decode_strict(&mut bytes).unwrap(), Packet::PingRequest);
+        }
+
+        // PUBREL with its reserved flag
+        let mut bytes = BytesMut::from(&[0x62, 0x02, 0x00, 0x01][..]);
+        assert!(matches!(decode_strict(&mut bytes), Ok(Packet::PublishRelease(_))));
+    }
+
+    #[test]
+    fn test_strict_will_payload_format() {
+        // V5 CONNECT with a will marked as UTF-8, but with the payload 0xFF.
+        let packet = [
+            0x10, 0x17, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x06, 0x00, 0x3C, 0x00, 0x00,
+            0x01, b'a', 0x02, 0x01, 0x01, 0x00, 0x01, b't', 0x00, 0x01, 0xFF,
+        ];
+
+        let mut bytes = BytesMut::from(&packet[..]);
+        assert!(matches!(
+            decode_mqtt(&mut bytes, ProtocolVersion::V500),
+            Ok(Some(Packet::Connect(_)))
+        ));
+
+        let mut bytes = BytesMut::from(&packet[..]);
+        let err = decode_strict(&mut bytes).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidPayloadFormat));
+        assert_eq!(err.connect_reason(), Some(ConnectReason::PayloadFormatInvalid));
+    }
+
+    #[test]
+    fn test_invalid_will_topic() {
+        // V5 CONNECT with a will on the topic "#".
+        let mut bytes = BytesMut::from(
+            &[
+                0x10, 0x14, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x06, 0x00, 0x3C, 0x00, 0x00,
+                0x01, b'a', 0x00, 0x00, 0x01, b'#', 0x00, 0x00,
+            ][..],
+        );
+        let err = decode_mqtt(&mut bytes, ProtocolVersion::V500).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidTopic(_)));
+        assert_eq!(err.connect_reason(), Some(ConnectReason::TopicNameInvalid));
+
+        // The same CONNECT with an empty will topic.
+        let mut bytes = BytesMut::from(
+            &[
+                0x10, 0x13, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x06, 0x00, 0x3C, 0x00, 0x00,
+                0x01, b'a', 0x00, 0x00, 0x00, 0x00, 0x00,
+            ][..],
+        );
+        let err = decode_mqtt(&mut bytes, ProtocolVersion::V500).unwrap_err();
+        assert!(matches!(err, DecodeError::InvalidTopic(_)));
+    }
+
+    #[test]
+    fn test_decode_without_copying() {
+        // V5 PUBLISH on topic "a/b" with a content type, a user property and a 3 byte payload.
+        let mut bytes = BytesMut::from(
+            &[
+                0x30, 0x17, 0x00, 0x03, b'a', b'/', b'b', 0x0E, 0x03, 0x00, 0x04, b't', b'e', b'x',
+                b't', 0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v', b'x', b'y', b'z',
+            ][..],
+        );
+        let buffer = bytes.as_ptr_range();
+        let is_in_buffer = |slice: &[u8]| buffer.contains(&slice.as_ptr());
+
+        let packet = match decode_mqtt(&mut bytes, ProtocolVersion::V500) {
+            Ok(Some(Packet::Publish(packet))) => packet,
+            packet => panic!("Expected a publish packet, got {:?}", packet),
+        };
+
+        assert_eq!(packet.topic.topic_name(), "a/b");
+        assert_eq!(packet.content_type, Some(ContentType("text".into())));
+        assert_eq!(packet.user_properties, vec![UserProperty("k".into(), "v".into())]);
+        assert_eq!(&packet.payload[..], b"xyz");
+
+        // The topic, properties and payload are slices of the decoded buffer.
+        assert!(is_in_buffer(packet.topic.topic_name().as_bytes()));
+        assert!(is_in_buffer(packet.content_type.unwrap().0.as_bytes()));
+        assert!(is_in_buffer(packet.user_properties[0].1.as_bytes()));
+        assert!(is_in_buffer(&packet.payload));
+    }
+
+    #[test]
+    fn test_decode_invalid_utf8() {
+        // V3.1.1 PUBLISH with an invalid UTF-8 topic
+        let mut bytes = BytesMut::from(&[0x30, 0x04, 0x00, 0x02, 0xC3, 0x28][..]);
+        assert!(matches!(
+            decode_mqtt(&mut bytes, ProtocolVersion::V311),
+            Err(DecodeError::InvalidUtf8)
+        ));
+
+        // V5 PUBLISH with U+0000 in its content type, only rejected in strict mode
+        let publish = [0x30, 0x09, 0x00, 0x01, b'a', 0x05, 0x03, 0x00, 0x02, b'a', 0x00];
+        let mut bytes = BytesMut::from(&publish[..]);
+        assert!(matches!(decode_strict(&mut bytes), Err(DecodeError::InvalidUtf8)));
+
+        let mut bytes = BytesMut::from(&publish[..]);
+        assert!(matches!(
+            decode_mqtt(&mut bytes, ProtocolVersion::V500),
+            Ok(Some(Packet::Publish(_)))
+        ));
+
+        // Payloads aren't strings, U+0000 is valid UTF-8 character data.
+        let mut bytes = BytesMut::from(&[0x30, 0x05, 0x00, 0x01, b'a', 0x00, b'b'][..]);
+        match decode_mqtt(&mut bytes, ProtocolVersion::V311) {
+            Ok(Some(Packet::Publish(packet))) => {
+                let packet = PublishPacket {
+                    payload_format_indicator: Some(PayloadFormatIndicator(1)),
+                    ..packet
+                };
+                assert!(packet.has_valid_payload_format());
+
+                let packet = PublishPacket { payload: Bytes::from_static(&[0xC3, 0x28]), ..packet };
+                assert!(!packet.has_valid_payload_format());
+            },
+            result => panic!("Expected PUBLISH, got {:?}", result),
+        }
+    }
+
     #[test]
     fn test_decode_variable_int() {
         // TODO - Maybe it would be better to add an abnormal system test.
@@ -2,11 +2,12 @@ use crate::types::{
     properties::*, AuthenticatePacket, ConnectAckPacket, ConnectPacket, DisconnectPacket, Encode,
     Packet, PropertySize, ProtocolVersion, PublishAckPacket, PublishCompletePacket, PublishPacket,
     PublishReceivedPacket, PublishReleasePacket, SubscribeAckPacket, SubscribePacket,
-    UnsubscribeAckPacket, UnsubscribePacket, VariableByteInt,
+    UnsubscribeAckPacket, UnsubscribePacket,
 };
 use bytes::{BufMut, BytesMut};
 
-fn encode_variable_int(value: u32, bytes: &mut BytesMut) -> usize {
+/// Encode a variable byte integer, returning the number of bytes written.
+pub fn encode_variable_int(value: u32, bytes: &mut BytesMut) -> usize {
     let mut x = value;
     let mut byte_counter = 0;
 
@@ -30,7 +31,8 @@ fn encode_variable_int(value: u32, bytes: &mut BytesMut) -> usize {
     byte_counter
 }
 
-fn encode_string(value: &str, bytes: &mut BytesMut) {
+/// Encode a UTF-8 string prefixed with its length.
+pub fn encode_string(value: &str, bytes: &mut BytesMut) {
     bytes.put_u16(value.len() as u16);
     bytes.put_slice(value.as_bytes());
 }
@@ -220,7 +222,7 @@ fn encode_connect(packet: &ConnectPacket, bytes: &mut BytesMut, protocol_version
 
     if let Some(will) = &packet.will {
         if will.should_retain {
-            connect_flags |= 0b0100_0000;
+            connect_flags |= 0b0010_0000;
         }
 
         let qos_byte: u8 = will.qos as u8;
@@ -266,7 +268,7 @@ fn encode_connect(packet: &ConnectPacket, bytes: &mut BytesMut, protocol_version
             will.user_properties.encode(bytes);
         }
 
-        encode_string(&will.topic, bytes);
+        encode_string(will.topic.topic_name(), bytes);
         encode_binary_data(&will.payload, bytes);
     }
 
@@ -275,7 +277,7 @@ fn encode_connect(packet: &ConnectPacket, bytes: &mut BytesMut, protocol_version
     }
 
     if let Some(password) = &packet.password {
-        encode_string(password, bytes);
+        encode_binary_data(password, bytes);
     }
 }
 
@@ -284,13 +286,20 @@ fn encode_connect_ack(
     bytes: &mut BytesMut,
     protocol_version: ProtocolVersion,
 ) {
+    // MQTT 3.1 has no session present flag, the byte is reserved.
     let mut connect_ack_flags: u8 = 0b0000_0000;
-    if packet.session_present {
+    if packet.session_present && protocol_version != ProtocolVersion::V310 {
         connect_ack_flags |= 0b0000_0001;
     }
 
     bytes.put_u8(connect_ack_flags);
-    bytes.put_u8(packet.reason_code as u8);
+
+    match protocol_version {
+        ProtocolVersion::V310 | ProtocolVersion::V311 => {
+            bytes.put_u8(packet.reason_code.v311_return_code())
+        },
+        ProtocolVersion::V500 => bytes.put_u8(packet.reason_code as u8),
+    }
 
     if protocol_version == ProtocolVersion::V500 {
         let property_length = packet.property_size(protocol_version);
@@ -546,16 +555,14 @@ fn encode_authenticate(
 
 pub fn encode_mqtt(packet: &Packet, bytes: &mut BytesMut, protocol_version: ProtocolVersion) {
     let remaining_length = packet.calculate_size(protocol_version);
-    let packet_size =
-        1 + VariableByteInt(remaining_length).calculate_size(protocol_version) + remaining_length;
-    bytes.reserve(packet_size as usize);
+    bytes.reserve(packet.encoded_size(protocol_version) as usize);
 
     let first_byte = packet.to_byte();
     let mut first_byte_val = (first_byte << 4) & 0b1111_0000;
     first_byte_val |= packet.fixed_header_flags();
 
     bytes.put_u8(first_byte_val);
-    encode_variable_int(remaining_length as u32, bytes);
+    encode_variable_int(remaining_length, bytes);
 
     match packet {
         Packet::Connect(p) => encode_connect(p, bytes, protocol_version),
@@ -578,7 +585,7 @@ pub fn encode_mqtt(packet: &Packet, bytes: &mut BytesMut, protocol_version: Prot
 
 #[cfg(test)]
 mod tests {
-    use crate::{decoder::*, encoder::*, types::*};
+    use crate::{decoder::*, encoder::*, topic::Topic, types::*};
     use bytes::BytesMut;
 
     #[test]
@@ -612,6 +619,127 @@ mod tests {
         assert_eq!(packet, decoded);
     }
 
+    fn connect_will_packet(protocol_version: ProtocolVersion) -> Packet {
+        Packet::Connect(ConnectPacket {
+            protocol_name: "MQTT".to_string(),
+            protocol_version,
+            clean_start: true,
+            keep_alive: 200,
+
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            user_properties: vec![],
+            authentication_method: None,
+            authentication_data: None,
+
+            client_id: "test_client".to_string(),
+            will: Some(FinalWill {
+                topic: "last/will".parse().unwrap(),
+                payload: vec![1, 2, 3].into(),
+                qos: QoS::AtLeastOnce,
+                should_retain: true,
+                will_delay_interval: None,
+                payload_format_indicator: None,
+                message_expiry_interval: None,
+                content_type: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: vec![],
+            }),
+            user_name: None,
+            password: None,
+        })
+    }
+
+    #[test]
+    fn connect_will_roundtrip() {
+        let packet = connect_will_packet(ProtocolVersion::V500);
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet, &mut bytes, ProtocolVersion::V500);
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V500).unwrap().unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn connect_will_v311_roundtrip() {
+        let packet = connect_will_packet(ProtocolVersion::V311);
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet, &mut bytes, ProtocolVersion::V311);
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V311).unwrap().unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn connect_binary_password_roundtrip() {
+        let packet = Packet::Connect(ConnectPacket {
+            protocol_name: "MQTT".to_string(),
+            protocol_version: ProtocolVersion::V311,
+            clean_start: true,
+            keep_alive: 200,
+
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            user_properties: vec![],
+            authentication_method: None,
+            authentication_data: None,
+
+            client_id: "test_client".to_string(),
+            will: None,
+            user_name: Some("user".to_string()),
+            password: Some(vec![0xFF, 0x00, 0x80].into()),
+        });
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet, &mut bytes, ProtocolVersion::V311);
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V311).unwrap().unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn connect_v310_roundtrip() {
+        let packet = Packet::Connect(ConnectPacket {
+            protocol_name: "MQIsdp".to_string(),
+            protocol_version: ProtocolVersion::V310,
+            clean_start: true,
+            keep_alive: 200,
+
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            user_properties: vec![],
+            authentication_method: None,
+            authentication_data: None,
+
+            client_id: "test_client".to_string(),
+            will: None,
+            user_name: None,
+            password: None,
+        });
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet, &mut bytes, ProtocolVersion::V310);
+        assert_eq!(&bytes[2..11], b"\x00\x06MQIsdp\x03");
+
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V311).unwrap().unwrap();
+        assert_eq!(packet, decoded);
+    }
+
     #[test]
     fn connect_ack_roundtrip() {
         let packet = Packet::ConnectAck(ConnectAckPacket {
@@ -644,6 +772,55 @@ mod tests {
         assert_eq!(packet, decoded);
     }
 
+    #[test]
+    fn connect_ack_v311_return_code() {
+        let packet = |reason_code| {
+            Packet::ConnectAck(ConnectAckPacket {
+                session_present: false,
+                reason_code,
+
+                session_expiry_interval: None,
+                receive_maximum: None,
+                maximum_qos: None,
+                retain_available: None,
+                maximum_packet_size: None,
+                assigned_client_identifier: None,
+                topic_alias_maximum: None,
+                reason_string: None,
+                user_properties: vec![],
+                wildcard_subscription_available: None,
+                subscription_identifiers_available: None,
+                shared_subscription_available: None,
+                server_keep_alive: None,
+                response_information: None,
+                server_reference: None,
+                authentication_method: None,
+                authentication_data: None,
+            })
+        };
+
+        // Reason codes without a 3.1.1 equivalent map to the closest return code.
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet(ConnectReason::Banned), &mut bytes, ProtocolVersion::V311);
+        assert_eq!(&bytes[..], &[0x20, 0x02, 0x00, 0x05]);
+
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V311).unwrap().unwrap();
+        assert_eq!(decoded, packet(ConnectReason::NotAuthorized));
+
+        // MQTT 3.1 has no session present flag.
+        let mut connect_ack = packet(ConnectReason::Success);
+        if let Packet::ConnectAck(connect_ack) = &mut connect_ack {
+            connect_ack.session_present = true;
+        }
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&connect_ack, &mut bytes, ProtocolVersion::V310);
+        assert_eq!(&bytes[..], &[0x20, 0x02, 0x00, 0x00]);
+
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V310).unwrap().unwrap();
+        assert_eq!(decoded, packet(ConnectReason::Success));
+    }
+
     #[test]
     fn publish_roundtrip() {
         let packet = Packet::Publish(PublishPacket {
@@ -673,6 +850,47 @@ mod tests {
         assert_eq!(packet, decoded);
     }
 
+    #[test]
+    fn publish_topic_alias_roundtrip() {
+        let packet = Packet::Publish(PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+
+            topic: Topic::empty(),
+            packet_id: None,
+
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: Some(TopicAlias(3)),
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            subscription_identifiers: Vec::with_capacity(0),
+            content_type: None,
+
+            payload: vec![22; 10].into(),
+        });
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet, &mut bytes, ProtocolVersion::V500);
+        let decoded = decode_mqtt(&mut bytes, ProtocolVersion::V500).unwrap().unwrap();
+
+        assert_eq!(packet, decoded);
+
+        // Without a topic alias, an empty topic name is invalid.
+        let packet = match packet {
+            Packet::Publish(packet) => {
+                Packet::Publish(PublishPacket { topic_alias: None, ..packet })
+            },
+            _ => unreachable!(),
+        };
+
+        let mut bytes = BytesMut::new();
+        encode_mqtt(&packet, &mut bytes, ProtocolVersion::V500);
+        assert!(decode_mqtt(&mut bytes, ProtocolVersion::V500).is_err());
+    }
+
     #[test]
     fn publish_ack_roundtrip() {
         let packet = Packet::PublishAck(PublishAckPacket {
@@ -10,6 +10,9 @@ pub const SHARED_SUBSCRIPTION_PREFIX: &str = "$share/";
 
 pub const MAX_TOPIC_LEN_BYTES: usize = 65_535;
 
+/// The longest client identifier in characters an MQTT 3.1 client may use.
+pub const MAX_V310_CLIENT_ID_LEN: usize = 23;
+
 pub mod decoder;
 pub mod encoder;
 pub mod topic;
@@ -18,14 +21,23 @@ pub mod types;
 #[cfg(feature = "codec")]
 pub mod codec {
     use crate::{
-        decoder, encoder,
-        types::{DecodeError, EncodeError, Packet, ProtocolVersion},
+        decoder::{self, DecodeMode, FixedHeader},
+        encoder,
+        types::{DecodeError, EncodeError, Packet, PacketType, ProtocolVersion},
     };
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
     use tokio_util::codec::{Decoder, Encoder};
 
+    /// The most buffer space reserved up front for the body of an incomplete packet,
+    /// larger packets grow the buffer as their data arrives.
+    const MAX_BODY_RESERVATION: usize = 64 * 1024;
+
     pub struct MqttCodec {
         version: ProtocolVersion,
+        maximum_packet_size: Option<u32>,
+        decode_mode: DecodeMode,
+        /// The fixed header of a packet whose body hasn't been fully received yet.
+        pending_header: Option<FixedHeader>,
     }
 
     impl Default for MqttCodec {
@@ -36,18 +48,59 @@ pub mod codec {
 
     impl MqttCodec {
         pub fn new() -> Self {
-            MqttCodec { version: ProtocolVersion::V311 }
+            MqttCodec {
+                version: ProtocolVersion::V311,
+                maximum_packet_size: None,
+                decode_mode: DecodeMode::Lenient,
+                pending_header: None,
+            }
+        }
+
+        /// Construct a codec which fails with `DecodeError::PacketTooLarge`
+        /// on packets larger than `maximum_packet_size` bytes.
+        pub fn with_maximum_packet_size(maximum_packet_size: u32) -> Self {
+            MqttCodec {
+                version: ProtocolVersion::V311,
+                maximum_packet_size: Some(maximum_packet_size),
+                decode_mode: DecodeMode::Lenient,
+                pending_header: None,
+            }
+        }
+
+        /// Set how strictly received packets are validated, `DecodeMode::Lenient` by default.
+        pub fn set_decode_mode(&mut self, decode_mode: DecodeMode) {
+            self.decode_mode = decode_mode;
         }
 
+        /// Decode the next packet in `buf`. The fixed header of an incomplete packet
+        /// is kept, so partial packets aren't parsed again as more data arrives.
         pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>, DecodeError> {
-            // TODO - Ideally we should keep a state machine to store the data we've read so far.
-            let packet = decoder::decode_mqtt(buf, self.version);
+            let fixed_header = match self.pending_header.take() {
+                Some(fixed_header) => fixed_header,
+                None => match decoder::decode_fixed_header(buf, self.maximum_packet_size)? {
+                    Some(fixed_header) => fixed_header,
+                    None => return Ok(None),
+                },
+            };
+
+            if buf.len() < fixed_header.packet_len() {
+                buf.reserve((fixed_header.packet_len() - buf.len()).min(MAX_BODY_RESERVATION));
+                self.pending_header = Some(fixed_header);
+                return Ok(None);
+            }
+
+            // Set the version before decoding the rest of the CONNECT packet, so even
+            // a malformed one is answered in the client's version.
+            if fixed_header.packet_type == PacketType::Connect {
+                let body = &buf[fixed_header.header_len..fixed_header.packet_len()];
 
-            if let Ok(Some(Packet::Connect(packet))) = &packet {
-                self.version = packet.protocol_version;
+                if let Some(version) = decoder::decode_connect_protocol_version(body) {
+                    self.version = version;
+                }
             }
 
-            packet
+            decoder::decode_packet_body(buf, &fixed_header, self.version, self.decode_mode)
+                .map(Some)
         }
 
         pub fn encode(&mut self, packet: Packet, bytes: &mut BytesMut) -> Result<(), EncodeError> {
@@ -61,7 +114,6 @@ pub mod codec {
         type Item = Packet;
 
         fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-            // TODO - Ideally we should keep a state machine to store the data we've read so far.
             self.decode(buf)
         }
     }
@@ -73,11 +125,129 @@ pub mod codec {
             self.encode(packet, bytes)
         }
     }
+
+    /// Writes bytes which are already encoded for the negotiated protocol version,
+    /// e.g. parts of a packet shared by several connections.
+    impl Encoder<Bytes> for MqttCodec {
+        type Error = EncodeError;
+
+        fn encode(&mut self, encoded: Bytes, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+            bytes.extend_from_slice(&encoded);
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{MqttCodec, MAX_BODY_RESERVATION};
+        use crate::{encoder::encode_mqtt, types::*};
+        use bytes::BytesMut;
+
+        fn publish_packet() -> Packet {
+            Packet::Publish(PublishPacket {
+                is_duplicate: false,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+
+                topic: "test_topic".parse().unwrap(),
+                packet_id: Some(42),
+
+                payload_format_indicator: None,
+                message_expiry_interval: None,
+                topic_alias: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: vec![],
+                subscription_identifiers: vec![],
+                content_type: None,
+
+                payload: vec![7; 1000].into(),
+            })
+        }
+
+        #[test]
+        fn decode_in_small_chunks() {
+            let mut encoded = BytesMut::new();
+            encode_mqtt(&publish_packet(), &mut encoded, ProtocolVersion::V311);
+            encode_mqtt(&Packet::PingRequest, &mut encoded, ProtocolVersion::V311);
+
+            let mut codec = MqttCodec::new();
+            let mut buf = BytesMut::new();
+            let mut packets = vec![];
+
+            for chunk in encoded.chunks(7) {
+                buf.extend_from_slice(chunk);
+
+                while let Some(packet) = codec.decode(&mut buf).unwrap() {
+                    packets.push(packet);
+                }
+
+                // The fixed header is kept while the rest of the packet is missing,
+                // with room reserved for it.
+                if let Some(fixed_header) = &codec.pending_header {
+                    assert!(buf.capacity() >= fixed_header.packet_len());
+                }
+            }
+
+            assert_eq!(packets, vec![publish_packet(), Packet::PingRequest]);
+            assert!(buf.is_empty());
+            assert!(codec.pending_header.is_none());
+        }
+
+        #[test]
+        fn decode_large_remaining_length_reserves_chunk() {
+            // A PUBLISH header claiming a remaining length of 256 MB - 1.
+            let mut buf = BytesMut::from(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+            let mut codec = MqttCodec::new();
+
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+            assert!(codec.pending_header.is_some());
+            assert!(buf.capacity() <= 2 * MAX_BODY_RESERVATION);
+        }
+
+        #[test]
+        fn decode_body_within_remaining_length() {
+            // A SUBSCRIBE whose remaining length ends before its topic filter.
+            let mut buf = BytesMut::from(&[0x82, 0x04, 0x00, 0x01, 0x00, 0x03, 0xC0, 0x00][..]);
+            let mut codec = MqttCodec::new();
+
+            assert!(matches!(codec.decode(&mut buf), Err(DecodeError::InvalidRemainingLength)));
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::PingRequest));
+        }
+
+        #[test]
+        fn invalid_connect_sets_version() {
+            // A V5 CONNECT with the reserved connect flag set.
+            let mut buf = BytesMut::from(
+                &[
+                    0x10, 0x0E, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x03, 0x00, 0x3C, 0x00,
+                    0x00, 0x01, b'a',
+                ][..],
+            );
+            let mut codec = MqttCodec::new();
+
+            assert!(matches!(codec.decode(&mut buf), Err(DecodeError::InvalidConnectFlags)));
+
+            // The packets refusing the connection are encoded in the client's version.
+            let mut encoded = BytesMut::new();
+            codec.encode(publish_packet(), &mut encoded).unwrap();
+
+            let mut expected = BytesMut::new();
+            encode_mqtt(&publish_packet(), &mut expected, ProtocolVersion::V500);
+            assert_eq!(encoded, expected);
+        }
+    }
 }
 
 #[cfg(feature = "websocket")]
 pub mod websocket {
-    use bytes::BytesMut;
+    use crate::{
+        codec::MqttCodec,
+        decoder::DecodeMode,
+        types::{DecodeError, EncodeError, Packet},
+    };
+    use bytes::{Bytes, BytesMut};
+    use std::convert::{TryFrom, TryInto};
     use tokio_util::codec::{Decoder, Encoder};
 
     pub use websocket_codec as codec;
@@ -165,23 +335,24 @@ pub mod websocket {
                     split_line.next().ok_or(WsDecodeError::InvalidUpgradeHeaders)?.trim();
 
                 match header_name {
-                    header if header.eq_ignore_ascii_case("Upgrade") => {
-                        if header_val != "websocket" {
-                            return Err(WsDecodeError::InvalidUpgradeHeaders);
-                        }
+                    header
+                        if header.eq_ignore_ascii_case("Upgrade") && header_val != "websocket" =>
+                    {
+                        return Err(WsDecodeError::InvalidUpgradeHeaders);
                     },
-                    header if header.eq_ignore_ascii_case("Connection") => {
-                        if header_val != "Upgrade" {
-                            return Err(WsDecodeError::InvalidUpgradeHeaders);
-                        }
+                    header
+                        if header.eq_ignore_ascii_case("Connection") && header_val != "Upgrade" =>
+                    {
+                        return Err(WsDecodeError::InvalidUpgradeHeaders);
                     },
                     header if header.eq_ignore_ascii_case("Sec-WebSocket-Key") => {
                         websocket_key = Some(header_val);
                     },
-                    header if header.eq_ignore_ascii_case("Sec-WebSocket-Version") => {
-                        if header_val != "13" {
-                            return Err(WsDecodeError::InvalidUpgradeHeaders);
-                        }
+                    header
+                        if header.eq_ignore_ascii_case("Sec-WebSocket-Version")
+                            && header_val != "13" =>
+                    {
+                        return Err(WsDecodeError::InvalidUpgradeHeaders);
                     },
                     header if header.eq_ignore_ascii_case("Sec-WebSocket-Protocol") => {
                         let mut versions = header_val.split(',');
@@ -255,4 +426,309 @@ pub mod websocket {
             Ok(())
         }
     }
+
+    /// A codec for MQTT packets carried in WebSocket binary messages.
+    /// Decoding and encoding share the protocol version of the CONNECT packet,
+    /// so both halves of a split `Framed` speak the negotiated version.
+    pub struct WsMqttCodec {
+        ws_codec: codec::MessageCodec,
+        mqtt_codec: MqttCodec,
+        read_buf: BytesMut,
+        /// The largest WebSocket message accepted, checked against the frame headers
+        /// before the frame data is buffered.
+        maximum_message_size: Option<u32>,
+        /// The data length of a fragmented message which `ws_codec` holds until its last frame.
+        interrupted_message_len: u64,
+    }
+
+    impl Default for WsMqttCodec {
+        fn default() -> Self {
+            WsMqttCodec::new()
+        }
+    }
+
+    impl WsMqttCodec {
+        pub fn new() -> Self {
+            Self::with_mqtt_codec(MqttCodec::new(), None)
+        }
+
+        /// Construct a codec which fails with `DecodeError::PacketTooLarge`
+        /// on packets larger than `maximum_packet_size` bytes. WebSocket messages
+        /// larger than that are rejected as well.
+        pub fn with_maximum_packet_size(maximum_packet_size: u32) -> Self {
+            Self::with_mqtt_codec(
+                MqttCodec::with_maximum_packet_size(maximum_packet_size),
+                Some(maximum_packet_size),
+            )
+        }
+
+        /// Set how strictly received packets are validated, `DecodeMode::Lenient` by default.
+        pub fn set_decode_mode(&mut self, decode_mode: DecodeMode) {
+            self.mqtt_codec.set_decode_mode(decode_mode);
+        }
+
+        fn with_mqtt_codec(mqtt_codec: MqttCodec, maximum_message_size: Option<u32>) -> Self {
+            WsMqttCodec {
+                ws_codec: codec::MessageCodec::server(),
+                mqtt_codec,
+                read_buf: BytesMut::new(),
+                maximum_message_size,
+                interrupted_message_len: 0,
+            }
+        }
+
+        /// Fails with `DecodeError::PacketTooLarge` if the frame headers in `buf` announce
+        /// a message larger than `maximum_message_size`, so the data of such a frame is
+        /// never buffered. Returns the data length of the fragmented message `ws_codec`
+        /// holds after decoding `buf`.
+        fn check_message_size(&self, buf: &[u8]) -> Result<u64, DecodeError> {
+            let maximum_message_size = self.maximum_message_size.map_or(u64::MAX, u64::from);
+
+            let mut message_len = self.interrupted_message_len;
+            let mut frames = buf;
+            // `ws_codec` takes the complete frames up to the first message it returns.
+            let mut interrupted_message_len = None;
+
+            while let Some((fin, opcode, header_len, data_len)) = parse_frame_header(frames) {
+                // Control frames carry at most 125 bytes and may be sent in between
+                // the frames of a fragmented message.
+                let is_control = opcode & CONTROL_OPCODE_BIT != 0;
+
+                if !is_control && message_len.saturating_add(data_len) > maximum_message_size {
+                    return Err(DecodeError::PacketTooLarge);
+                }
+
+                match usize::try_from(header_len as u64 + data_len) {
+                    Ok(frame_len) if frame_len <= frames.len() => frames = &frames[frame_len..],
+                    _ => break,
+                }
+
+                if is_control {
+                    interrupted_message_len.get_or_insert(message_len);
+                } else if fin {
+                    interrupted_message_len.get_or_insert(0);
+                    message_len = 0;
+                } else {
+                    message_len += data_len;
+                }
+            }
+
+            Ok(interrupted_message_len.unwrap_or(message_len))
+        }
+    }
+
+    /// Control frame opcodes have the highest opcode bit set.
+    const CONTROL_OPCODE_BIT: u8 = 0x08;
+
+    /// Parse the FIN bit, opcode, header length and data length of the WebSocket frame
+    /// at the start of `bytes`, or None if the header is incomplete.
+    fn parse_frame_header(bytes: &[u8]) -> Option<(bool, u8, usize, u64)> {
+        let first_byte = *bytes.first()?;
+        let second_byte = *bytes.get(1)?;
+
+        let fin = first_byte & 0x80 != 0;
+        let opcode = first_byte & 0x0F;
+        let mask_len = if second_byte & 0x80 != 0 { 4 } else { 0 };
+
+        let (length_len, data_len) = match second_byte & 0x7F {
+            126 => (2, u64::from(u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?))),
+            127 => (8, u64::from_be_bytes(bytes.get(2..10)?.try_into().ok()?)),
+            data_len => (0, u64::from(data_len)),
+        };
+
+        Some((fin, opcode, 2 + length_len + mask_len, data_len))
+    }
+
+    impl Decoder for WsMqttCodec {
+        type Error = DecodeError;
+        type Item = Packet;
+
+        fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            // An MQTT packet can span several WebSocket messages, and a message
+            // can hold several MQTT packets.
+            loop {
+                if let Some(packet) = self.mqtt_codec.decode(&mut self.read_buf)? {
+                    return Ok(Some(packet));
+                }
+
+                let interrupted_message_len = self.check_message_size(buf)?;
+
+                let message = self.ws_codec.decode(buf);
+                self.interrupted_message_len = interrupted_message_len;
+
+                let message = match message {
+                    Ok(Some(message)) => message,
+                    Ok(None) => return Ok(None),
+                    Err(_) => return Err(DecodeError::BadTransport),
+                };
+
+                match message.opcode() {
+                    codec::Opcode::Binary => self.read_buf.extend_from_slice(message.data()),
+                    codec::Opcode::Ping | codec::Opcode::Pong => {},
+                    // MQTT Control Packets MUST be sent in WebSocket binary data frames,
+                    // and a close ends the transport.
+                    codec::Opcode::Text | codec::Opcode::Close => {
+                        return Err(DecodeError::BadTransport)
+                    },
+                }
+            }
+        }
+    }
+
+    impl Encoder<Packet> for WsMqttCodec {
+        type Error = EncodeError;
+
+        fn encode(&mut self, packet: Packet, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+            let mut payload = BytesMut::new();
+            self.mqtt_codec.encode(packet, &mut payload)?;
+
+            let message = codec::Message::binary(payload.freeze());
+            self.ws_codec.encode(message, bytes).map_err(|_| EncodeError::BadTransport)
+        }
+    }
+
+    /// Writes bytes which are already encoded for the negotiated protocol version
+    /// in a binary message of their own. MQTT packets may span several messages.
+    impl Encoder<Bytes> for WsMqttCodec {
+        type Error = EncodeError;
+
+        fn encode(&mut self, encoded: Bytes, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+            let message = codec::Message::binary(encoded);
+            self.ws_codec.encode(message, bytes).map_err(|_| EncodeError::BadTransport)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{
+            codec::{Message, MessageCodec},
+            WsMqttCodec,
+        };
+        use crate::{decoder::decode_mqtt, encoder::encode_mqtt, types::*};
+        use bytes::BytesMut;
+        use tokio_util::codec::{Decoder, Encoder};
+
+        fn connect_packet() -> Packet {
+            Packet::Connect(ConnectPacket {
+                protocol_name: "MQTT".to_string(),
+                protocol_version: ProtocolVersion::V500,
+                clean_start: true,
+                keep_alive: 200,
+
+                session_expiry_interval: None,
+                receive_maximum: None,
+                maximum_packet_size: None,
+                topic_alias_maximum: None,
+                request_response_information: None,
+                request_problem_information: None,
+                user_properties: vec![],
+                authentication_method: None,
+                authentication_data: None,
+
+                client_id: "web_dashboard".to_string(),
+                will: None,
+                user_name: None,
+                password: None,
+            })
+        }
+
+        #[test]
+        fn ws_mqtt_codec_uses_negotiated_version() {
+            let mut client_codec = MessageCodec::client();
+            let mut server_codec = WsMqttCodec::new();
+
+            // Split the CONNECT packet over two WebSocket messages.
+            let mut connect_bytes = BytesMut::new();
+            encode_mqtt(&connect_packet(), &mut connect_bytes, ProtocolVersion::V500);
+            let second_half = connect_bytes.split_off(connect_bytes.len() / 2);
+
+            let mut bytes = BytesMut::new();
+            client_codec.encode(Message::binary(connect_bytes.freeze()), &mut bytes).unwrap();
+            assert_eq!(server_codec.decode(&mut bytes).unwrap(), None);
+
+            client_codec.encode(Message::binary(second_half.freeze()), &mut bytes).unwrap();
+            assert_eq!(server_codec.decode(&mut bytes).unwrap(), Some(connect_packet()));
+
+            // The CONNACK is encoded with V5 properties.
+            let connect_ack = || {
+                Packet::ConnectAck(ConnectAckPacket {
+                    session_present: false,
+                    reason_code: ConnectReason::Success,
+
+                    session_expiry_interval: None,
+                    receive_maximum: None,
+                    maximum_qos: None,
+                    retain_available: None,
+                    maximum_packet_size: None,
+                    assigned_client_identifier: None,
+                    topic_alias_maximum: None,
+                    reason_string: Some(properties::ReasonString("Welcome".to_string())),
+                    user_properties: vec![],
+                    wildcard_subscription_available: None,
+                    subscription_identifiers_available: None,
+                    shared_subscription_available: None,
+                    server_keep_alive: None,
+                    response_information: None,
+                    server_reference: None,
+                    authentication_method: None,
+                    authentication_data: None,
+                })
+            };
+
+            let mut bytes = BytesMut::new();
+            server_codec.encode(connect_ack(), &mut bytes).unwrap();
+
+            let message = client_codec.decode(&mut bytes).unwrap().unwrap();
+            let mut payload = BytesMut::from(&message.data()[..]);
+            assert_eq!(
+                decode_mqtt(&mut payload, ProtocolVersion::V500).unwrap(),
+                Some(connect_ack())
+            );
+        }
+
+        /// A masked client frame with a zero mask key, so `data` goes out unchanged.
+        fn client_frame(first_byte: u8, data: &[u8]) -> Vec<u8> {
+            let mut frame = vec![first_byte, 0x80 | data.len() as u8, 0, 0, 0, 0];
+            frame.extend_from_slice(data);
+            frame
+        }
+
+        #[test]
+        fn ws_mqtt_codec_limits_message_size() {
+            // Only the header of a frame announcing 64 KiB of data has arrived.
+            let mut server_codec = WsMqttCodec::with_maximum_packet_size(1024);
+            let mut bytes = BytesMut::from(&[0x82, 0xFE, 0xFF, 0xFF][..]);
+            assert!(matches!(server_codec.decode(&mut bytes), Err(DecodeError::PacketTooLarge)));
+
+            // A fragmented message grows past the limit, with a ping in between.
+            let mut server_codec = WsMqttCodec::with_maximum_packet_size(100);
+            let mut bytes = BytesMut::from(&client_frame(0x02, &[0; 60])[..]);
+            bytes.extend_from_slice(&client_frame(0x89, b"ping"));
+            assert_eq!(server_codec.decode(&mut bytes).unwrap(), None);
+
+            bytes.extend_from_slice(&client_frame(0x80, &[0; 60])[..2]);
+            assert!(matches!(server_codec.decode(&mut bytes), Err(DecodeError::PacketTooLarge)));
+
+            // Messages within the limit are decoded as usual.
+            let mut connect_bytes = BytesMut::new();
+            encode_mqtt(&connect_packet(), &mut connect_bytes, ProtocolVersion::V500);
+            let mut server_codec =
+                WsMqttCodec::with_maximum_packet_size(connect_bytes.len() as u32);
+            let (first_half, second_half) = connect_bytes.split_at(connect_bytes.len() / 2);
+
+            let mut bytes = BytesMut::from(&client_frame(0x02, first_half)[..]);
+            bytes.extend_from_slice(&client_frame(0x80, second_half));
+            assert_eq!(server_codec.decode(&mut bytes).unwrap(), Some(connect_packet()));
+        }
+
+        #[test]
+        fn ws_mqtt_codec_rejects_text_messages() {
+            let mut client_codec = MessageCodec::client();
+            let mut server_codec = WsMqttCodec::new();
+
+            let mut bytes = BytesMut::new();
+            client_codec.encode(Message::text("CONNECT"), &mut bytes).unwrap();
+            assert!(matches!(server_codec.decode(&mut bytes), Err(DecodeError::BadTransport)));
+        }
+    }
 }
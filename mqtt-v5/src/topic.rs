@@ -1,14 +1,14 @@
 use std::str::FromStr;
 
 use crate::{
-    MAX_TOPIC_LEN_BYTES, MULTI_LEVEL_WILDCARD, MULTI_LEVEL_WILDCARD_STR,
+    types::ByteStr, MAX_TOPIC_LEN_BYTES, MULTI_LEVEL_WILDCARD, MULTI_LEVEL_WILDCARD_STR,
     SHARED_SUBSCRIPTION_PREFIX, SINGLE_LEVEL_WILDCARD, SINGLE_LEVEL_WILDCARD_STR, TOPIC_SEPARATOR,
 };
 
 /// A filter for subscribers to indicate which topics they want
 /// to receive messages from. Can contain wildcards.
 /// Shared topic filter example: $share/group_name_a/home/kitchen/temperature
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum TopicFilter {
     Concrete { filter: String, level_count: u32 },
     Wildcard { filter: String, level_count: u32 },
@@ -18,16 +18,67 @@ pub enum TopicFilter {
 
 /// A topic name publishers use when sending MQTT messages.
 /// Cannot contain wildcards.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Topic {
-    topic_name: String,
+    topic_name: ByteStr,
     level_count: u32,
 }
 
 impl Topic {
+    /// An empty topic name, which is only valid in a PUBLISH packet
+    /// that carries a topic alias.
+    pub fn empty() -> Self {
+        Topic { topic_name: ByteStr::default(), level_count: 0 }
+    }
+
+    /// Parse a topic name without copying it.
+    pub fn from_byte_str(topic_name: ByteStr) -> Result<Self, TopicParseError> {
+        let level_count = Self::validate(&topic_name)?;
+        Ok(Topic { topic_name, level_count })
+    }
+
+    /// Check the topic name is valid, returning its number of levels.
+    fn validate(topic: &str) -> Result<u32, TopicParseError> {
+        // TODO - Consider disallowing leading $ characters
+
+        // Topics cannot be empty
+        if topic.is_empty() {
+            return Err(TopicParseError::EmptyTopic);
+        }
+
+        // Topics cannot exceed the byte length in the MQTT spec
+        if topic.len() > MAX_TOPIC_LEN_BYTES {
+            return Err(TopicParseError::TopicTooLong);
+        }
+
+        // Topics cannot contain wildcards or null characters
+        let topic_contains_wildcards = topic.contains(|x: char| {
+            x == SINGLE_LEVEL_WILDCARD || x == MULTI_LEVEL_WILDCARD || x == '\0'
+        });
+
+        if topic_contains_wildcards {
+            return Err(TopicParseError::WildcardOrNullInTopic);
+        }
+
+        Ok(topic.split(TOPIC_SEPARATOR).count() as u32)
+    }
+
+    pub fn as_byte_str(&self) -> &ByteStr {
+        &self.topic_name
+    }
+
+    /// Copy the topic name into its own allocation, see `ByteStr::detached`.
+    pub fn detached(&self) -> Self {
+        Topic { topic_name: self.topic_name.detached(), level_count: self.level_count }
+    }
+
     pub fn topic_name(&self) -> &str {
         &self.topic_name
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.topic_name.is_empty()
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -82,8 +133,7 @@ fn process_filter(filter: &str) -> Result<(u32, bool), TopicParseError> {
     let mut level_count = 0;
     let mut contains_wildcards = false;
     for level in filter.split(TOPIC_SEPARATOR) {
-        let level_contains_wildcard =
-            level.contains(|x: char| x == SINGLE_LEVEL_WILDCARD || x == MULTI_LEVEL_WILDCARD);
+        let level_contains_wildcard = level.contains([SINGLE_LEVEL_WILDCARD, MULTI_LEVEL_WILDCARD]);
         if level_contains_wildcard {
             // Any wildcards on a particular level must be specified on their own
             if level.len() > 1 {
@@ -143,9 +193,7 @@ impl FromStr for TopicFilter {
                     return Err(TopicParseError::EmptySharedGroupName);
                 }
 
-                if shared_name
-                    .contains(|x: char| x == SINGLE_LEVEL_WILDCARD || x == MULTI_LEVEL_WILDCARD)
-                {
+                if shared_name.contains([SINGLE_LEVEL_WILDCARD, MULTI_LEVEL_WILDCARD]) {
                     return Err(TopicParseError::InvalidSharedGroupName);
                 }
 
@@ -193,32 +241,8 @@ impl FromStr for Topic {
     type Err = TopicParseError;
 
     fn from_str(topic: &str) -> Result<Self, Self::Err> {
-        // TODO - Consider disallowing leading $ characters
-
-        // Topics cannot be empty
-        if topic.is_empty() {
-            return Err(TopicParseError::EmptyTopic);
-        }
-
-        // Topics cannot exceed the byte length in the MQTT spec
-        if topic.len() > MAX_TOPIC_LEN_BYTES {
-            return Err(TopicParseError::TopicTooLong);
-        }
-
-        // Topics cannot contain wildcards or null characters
-        let topic_contains_wildcards = topic.contains(|x: char| {
-            x == SINGLE_LEVEL_WILDCARD || x == MULTI_LEVEL_WILDCARD || x == '\0'
-        });
-
-        if topic_contains_wildcards {
-            return Err(TopicParseError::WildcardOrNullInTopic);
-        }
-
-        let level_count = topic.split(TOPIC_SEPARATOR).count() as u32;
-
-        let topic = Topic { topic_name: topic.to_string(), level_count };
-
-        Ok(topic)
+        let level_count = Topic::validate(topic)?;
+        Ok(Topic { topic_name: topic.into(), level_count })
     }
 }
 
@@ -239,6 +263,38 @@ impl<'a> TopicFilter {
     pub fn levels(&'a self) -> TopicLevels<'a> {
         TopicLevels { levels_iter: self.filter().split(TOPIC_SEPARATOR) }
     }
+
+    pub fn is_shared(&self) -> bool {
+        matches!(self, TopicFilter::SharedConcrete { .. } | TopicFilter::SharedWildcard { .. })
+    }
+
+    /// Returns true if `topic` would be delivered to a subscriber of this filter.
+    /// For shared filters, only the filter part after the group name is considered.
+    pub fn matches(&self, topic: &Topic) -> bool {
+        let mut topic_levels = topic.levels();
+
+        for (index, filter_level) in self.levels().enumerate() {
+            let topic_level = topic_levels.next();
+
+            match (filter_level, topic_level) {
+                // Don't allow wildcard filters to match topics
+                // with leading dollar signs, like '$SYS/stats'
+                (TopicLevel::MultiLevelWildcard, Some(level))
+                | (TopicLevel::SingleLevelWildcard, Some(level))
+                    if index == 0 && level.has_leading_dollar() =>
+                {
+                    return false;
+                },
+                (TopicLevel::MultiLevelWildcard, _) => return true,
+                (TopicLevel::SingleLevelWildcard, Some(_)) => {},
+                (TopicLevel::Concrete(filter_level), Some(TopicLevel::Concrete(topic_level)))
+                    if filter_level == topic_level => {},
+                _ => return false,
+            }
+        }
+
+        topic_levels.next().is_none()
+    }
 }
 
 impl<'a> Topic {
@@ -511,24 +567,21 @@ mod tests {
 
     #[test]
     fn test_topic_name_success() {
-        assert_eq!(
-            "/".parse::<Topic>().unwrap(),
-            Topic { topic_name: "/".to_string(), level_count: 2 }
-        );
+        assert_eq!("/".parse::<Topic>().unwrap(), Topic { topic_name: "/".into(), level_count: 2 });
 
         assert_eq!(
             "Accounts payable".parse::<Topic>().unwrap(),
-            Topic { topic_name: "Accounts payable".to_string(), level_count: 1 }
+            Topic { topic_name: "Accounts payable".into(), level_count: 1 }
         );
 
         assert_eq!(
             "home/kitchen".parse::<Topic>().unwrap(),
-            Topic { topic_name: "home/kitchen".to_string(), level_count: 2 }
+            Topic { topic_name: "home/kitchen".into(), level_count: 2 }
         );
 
         assert_eq!(
             "home/kitchen/temperature".parse::<Topic>().unwrap(),
-            Topic { topic_name: "home/kitchen/temperature".to_string(), level_count: 3 }
+            Topic { topic_name: "home/kitchen/temperature".into(), level_count: 3 }
         );
     }
 
@@ -624,4 +677,30 @@ mod tests {
         assert_eq!(levels.next(), Some(TopicLevel::MultiLevelWildcard));
         assert_eq!(levels.next(), None);
     }
+
+    #[test]
+    fn test_topic_filter_matches() {
+        fn matches(filter: &str, topic: &str) -> bool {
+            filter.parse::<TopicFilter>().unwrap().matches(&topic.parse().unwrap())
+        }
+
+        assert!(matches("home/kitchen", "home/kitchen"));
+        assert!(!matches("home/kitchen", "home/kitchen/temperature"));
+        assert!(!matches("home/kitchen/temperature", "home/kitchen"));
+
+        assert!(matches("home/+/temperature", "home/kitchen/temperature"));
+        assert!(!matches("home/+", "home/kitchen/temperature"));
+        assert!(matches("+/+", "/"));
+
+        assert!(matches("home/#", "home"));
+        assert!(matches("home/#", "home/kitchen/temperature"));
+        assert!(matches("#", "home/kitchen"));
+
+        assert!(matches("$share/group_a/home/+", "home/kitchen"));
+
+        assert!(!matches("#", "$SYS/stats"));
+        assert!(!matches("+/stats", "$SYS/stats"));
+        assert!(matches("$SYS/#", "$SYS/stats"));
+        assert!(matches("/+", "/$SYS"));
+    }
 }
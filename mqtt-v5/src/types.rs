@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{borrow::Borrow, fmt, hash::Hash, ops::Deref, str::Utf8Error, time::Duration};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use num_enum::TryFromPrimitive;
@@ -30,12 +30,54 @@ pub enum DecodeError {
     InvalidAuthenticateReason,
     InvalidPropertyId,
     InvalidPropertyForPacket,
+    DuplicateProperty,
+    InvalidFixedHeaderFlags,
+    InvalidConnectFlags,
+    PasswordWithoutUserName,
+    InvalidPayloadFormat,
     InvalidTopic(TopicParseError),
     InvalidTopicFilter(TopicParseError),
     Io(std::io::Error),
     BadTransport, // When errors occur on a lower level transport like WS
 }
 
+impl DecodeError {
+    /// The reason code of the DISCONNECT packet to close a connection with after
+    /// this error, or `None` if the error happened on the transport.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        match self {
+            DecodeError::Io(_) | DecodeError::BadTransport => None,
+            DecodeError::PacketTooLarge => Some(DisconnectReason::PacketTooLarge),
+            DecodeError::InvalidTopic(_) => Some(DisconnectReason::TopicNameInvalid),
+            DecodeError::InvalidTopicFilter(_) => Some(DisconnectReason::TopicFilterInvalid),
+            DecodeError::InvalidPayloadFormat => Some(DisconnectReason::PayloadFormatInvalid),
+            DecodeError::InvalidSubscriptionIdentifier
+            | DecodeError::InvalidRetainHandling
+            | DecodeError::InvalidPropertyForPacket
+            | DecodeError::DuplicateProperty
+            | DecodeError::InvalidFixedHeaderFlags => Some(DisconnectReason::ProtocolError),
+            _ => Some(DisconnectReason::MalformedPacket),
+        }
+    }
+
+    /// The reason code of the CONNACK packet to refuse a connection with after this
+    /// error in its CONNECT packet, or `None` if the error happened on the transport.
+    pub fn connect_reason(&self) -> Option<ConnectReason> {
+        match self {
+            DecodeError::Io(_) | DecodeError::BadTransport => None,
+            DecodeError::InvalidProtocolVersion => Some(ConnectReason::UnsupportedProtocolVersion),
+            DecodeError::PacketTooLarge => Some(ConnectReason::PacketTooLarge),
+            DecodeError::InvalidTopic(_) => Some(ConnectReason::TopicNameInvalid),
+            DecodeError::PasswordWithoutUserName => Some(ConnectReason::BadUserNameOrPassword),
+            DecodeError::InvalidPayloadFormat => Some(ConnectReason::PayloadFormatInvalid),
+            DecodeError::InvalidPropertyForPacket
+            | DecodeError::DuplicateProperty
+            | DecodeError::InvalidFixedHeaderFlags => Some(ConnectReason::ProtocolError),
+            _ => Some(ConnectReason::MalformedPacket),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum EncodeError {
     BadTransport,
@@ -54,16 +96,43 @@ pub enum ProtocolError {
     ConnectTimedOut,
     FirstPacketNotConnect,
     InvalidProtocolName,
+    InvalidClientId,
     KeepAliveTimeout,
 }
 
+impl ProtocolError {
+    /// The reason code of the CONNACK packet to refuse a connection with, or `None`
+    /// if the connection is closed without a CONNACK.
+    pub fn connect_reason(&self) -> Option<ConnectReason> {
+        match self {
+            ProtocolError::MalformedPacket(err) => err.connect_reason(),
+            ProtocolError::InvalidProtocolName => Some(ConnectReason::UnsupportedProtocolVersion),
+            ProtocolError::InvalidClientId => Some(ConnectReason::ClientIdentifierNotValid),
+            ProtocolError::ConnectTimedOut
+            | ProtocolError::FirstPacketNotConnect
+            | ProtocolError::KeepAliveTimeout => None,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 pub enum ProtocolVersion {
+    V310 = 3,
     V311 = 4,
     V500 = 5,
 }
 
+impl ProtocolVersion {
+    /// The protocol name of CONNECT packets with this protocol version.
+    pub fn protocol_name(self) -> &'static str {
+        match self {
+            ProtocolVersion::V310 => "MQIsdp",
+            ProtocolVersion::V311 | ProtocolVersion::V500 => "MQTT",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct VariableByteInt(pub u32);
 
@@ -92,6 +161,113 @@ impl VariableByteInt {
     }
 }
 
+/// A UTF-8 string backed by `Bytes`. Decoded strings share the buffer of the
+/// packet they were decoded from, so cloning them doesn't copy the string.
+#[derive(Clone, Default, Eq, PartialOrd, Ord)]
+pub struct ByteStr(Bytes);
+
+impl ByteStr {
+    pub const fn from_static(value: &'static str) -> Self {
+        ByteStr(Bytes::from_static(value.as_bytes()))
+    }
+
+    /// Validate that `bytes` is UTF-8 and wrap it without copying.
+    pub fn from_utf8(bytes: Bytes) -> Result<Self, Utf8Error> {
+        std::str::from_utf8(&bytes)?;
+        Ok(ByteStr(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: All constructors ensure the bytes are valid UTF-8.
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+
+    /// Copy the string into its own allocation, so it doesn't keep the
+    /// buffer it was decoded from alive.
+    pub fn detached(&self) -> Self {
+        ByteStr(Bytes::copy_from_slice(&self.0))
+    }
+}
+
+impl Deref for ByteStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for ByteStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for ByteStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+// Hash like a `str`, which `Borrow<str>` requires.
+impl Hash for ByteStr {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl PartialEq for ByteStr {
+    fn eq(&self, other: &ByteStr) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl PartialEq<str> for ByteStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for ByteStr {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for ByteStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for ByteStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl From<String> for ByteStr {
+    fn from(value: String) -> Self {
+        ByteStr(Bytes::from(value))
+    }
+}
+
+impl From<&str> for ByteStr {
+    fn from(value: &str) -> Self {
+        ByteStr(Bytes::copy_from_slice(value.as_bytes()))
+    }
+}
+
+impl From<ByteStr> for String {
+    fn from(value: ByteStr) -> Self {
+        value.as_str().to_string()
+    }
+}
+
 impl From<std::io::Error> for DecodeError {
     fn from(err: std::io::Error) -> Self {
         DecodeError::Io(err)
@@ -164,6 +340,12 @@ impl PacketSize for String {
     }
 }
 
+impl PacketSize for ByteStr {
+    fn calc_size(&self, _protocol_version: ProtocolVersion) -> u32 {
+        2 + self.len() as u32
+    }
+}
+
 impl PacketSize for &str {
     fn calc_size(&self, _protocol_version: ProtocolVersion) -> u32 {
         2 + self.len() as u32
@@ -236,7 +418,7 @@ impl PacketSize for Vec<TopicFilter> {
 }
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
 pub enum PacketType {
     Connect = 1,
     ConnectAck = 2,
@@ -256,7 +438,7 @@ pub enum PacketType {
 }
 
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, TryFromPrimitive)]
 pub enum QoS {
     AtMostOnce = 0,  // QoS 0
     AtLeastOnce = 1, // QoS 1
@@ -272,7 +454,7 @@ pub enum RetainHandling {
 }
 
 pub mod properties {
-    use super::{PacketSize, QoS, VariableByteInt};
+    use super::{ByteStr, PacketSize, QoS, VariableByteInt};
     use crate::types::ProtocolVersion;
     use bytes::Bytes;
     use num_enum::TryFromPrimitive;
@@ -298,20 +480,30 @@ pub mod properties {
     }
 
     #[derive(Debug, Clone, Eq, PartialEq)]
-    pub struct ContentType(pub String);
+    pub struct ContentType(pub ByteStr);
     impl PacketSize for ContentType {
         fn calc_size(&self, protocol_version: ProtocolVersion) -> u32 {
             1 + self.0.calc_size(protocol_version)
         }
     }
+    impl From<String> for ContentType {
+        fn from(value: String) -> Self {
+            ContentType(value.into())
+        }
+    }
 
     #[derive(Debug, Clone, Eq, PartialEq)]
-    pub struct ResponseTopic(pub String);
+    pub struct ResponseTopic(pub ByteStr);
     impl PacketSize for ResponseTopic {
         fn calc_size(&self, protocol_version: ProtocolVersion) -> u32 {
             1 + self.0.calc_size(protocol_version)
         }
     }
+    impl From<String> for ResponseTopic {
+        fn from(value: String) -> Self {
+            ResponseTopic(value.into())
+        }
+    }
 
     #[derive(Debug, Clone, Eq, PartialEq)]
     pub struct CorrelationData(pub Bytes);
@@ -463,12 +655,17 @@ pub mod properties {
     }
 
     #[derive(Debug, Clone, Eq, PartialEq)]
-    pub struct UserProperty(pub String, pub String);
+    pub struct UserProperty(pub ByteStr, pub ByteStr);
     impl PacketSize for UserProperty {
         fn calc_size(&self, protocol_version: ProtocolVersion) -> u32 {
             1 + self.0.calc_size(protocol_version) + self.1.calc_size(protocol_version)
         }
     }
+    impl From<(String, String)> for UserProperty {
+        fn from((key, value): (String, String)) -> Self {
+            UserProperty(key.into(), value.into())
+        }
+    }
 
     #[derive(Debug, Clone, Eq, PartialEq)]
     pub struct MaximumPacketSize(pub u32);
@@ -503,7 +700,7 @@ pub mod properties {
     }
 
     #[repr(u32)]
-    #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
     pub enum PropertyType {
         PayloadFormatIndicator = 1,
         MessageExpiryInterval = 2,
@@ -633,6 +830,33 @@ pub enum ConnectReason {
     ConnectionRateExceeded = 159,
 }
 
+impl ConnectReason {
+    /// The MQTT 3.1 and 3.1.1 CONNACK return code closest to this reason code.
+    pub fn v311_return_code(self) -> u8 {
+        match self {
+            ConnectReason::Success => 0,
+            ConnectReason::UnsupportedProtocolVersion => 1,
+            ConnectReason::ClientIdentifierNotValid => 2,
+            ConnectReason::BadUserNameOrPassword | ConnectReason::BadAuthenticationMethod => 4,
+            ConnectReason::NotAuthorized | ConnectReason::Banned => 5,
+            _ => 3,
+        }
+    }
+
+    /// The reason code for an MQTT 3.1 or 3.1.1 CONNACK return code.
+    pub fn from_v311_return_code(return_code: u8) -> Option<Self> {
+        match return_code {
+            0 => Some(ConnectReason::Success),
+            1 => Some(ConnectReason::UnsupportedProtocolVersion),
+            2 => Some(ConnectReason::ClientIdentifierNotValid),
+            3 => Some(ConnectReason::ServerUnavailable),
+            4 => Some(ConnectReason::BadUserNameOrPassword),
+            5 => Some(ConnectReason::NotAuthorized),
+            _ => None,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
 pub enum PublishAckReason {
@@ -749,7 +973,7 @@ pub enum AuthenticateReason {
 // Payloads
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FinalWill {
-    pub topic: String, // TODO(bschwind) - Use Topic type here.
+    pub topic: Topic,
     pub payload: Bytes,
     pub qos: QoS,
     pub should_retain: bool,
@@ -768,6 +992,15 @@ impl FinalWill {
     pub fn will_delay_duration(&self) -> Option<Duration> {
         self.will_delay_interval.as_ref().map(|d| Duration::from_secs(d.0 as u64))
     }
+
+    /// Returns false if the payload format indicator marks the payload as UTF-8
+    /// encoded character data, but the payload isn't well-formed UTF-8.
+    pub fn has_valid_payload_format(&self) -> bool {
+        match self.payload_format_indicator {
+            Some(PayloadFormatIndicator(1)) => std::str::from_utf8(&self.payload).is_ok(),
+            _ => true,
+        }
+    }
 }
 
 impl PacketSize for FinalWill {
@@ -777,8 +1010,11 @@ impl PacketSize for FinalWill {
         size += self.topic.calc_size(protocol_version);
         size += self.payload.calc_size(protocol_version);
 
-        let property_size = self.property_size(protocol_version);
-        size += property_size + VariableByteInt(property_size).calc_size(protocol_version);
+        // Wills only have properties since MQTT 5.
+        if protocol_version == ProtocolVersion::V500 {
+            let property_size = self.property_size(protocol_version);
+            size += property_size + VariableByteInt(property_size).calc_size(protocol_version);
+        }
 
         size
     }
@@ -838,7 +1074,7 @@ pub struct ConnectPacket {
     pub client_id: String,
     pub will: Option<FinalWill>,
     pub user_name: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<Bytes>,
 }
 
 impl ConnectPacket {
@@ -956,6 +1192,88 @@ impl PropertySize for PublishPacket {
     }
 }
 
+impl PacketSize for PublishPacket {
+    fn calc_size(&self, protocol_version: ProtocolVersion) -> u32 {
+        let mut size = self.topic.calc_size(protocol_version);
+        size += self.packet_id.calc_size(protocol_version);
+
+        if protocol_version == ProtocolVersion::V500 {
+            let property_size = self.property_size(protocol_version);
+            size += property_size + VariableByteInt(property_size).calc_size(protocol_version);
+        }
+
+        // This payload does not have a length prefix
+        size += self.payload.len() as u32;
+
+        size
+    }
+}
+
+impl PublishPacket {
+    /// The size of the encoded packet, including the fixed header.
+    pub fn encoded_size(&self, protocol_version: ProtocolVersion) -> u32 {
+        let remaining_length = self.calc_size(protocol_version);
+        1 + VariableByteInt(remaining_length).calc_size(protocol_version) + remaining_length
+    }
+
+    /// Returns false if the payload format indicator marks the payload as UTF-8
+    /// encoded character data, but the payload isn't well-formed UTF-8.
+    pub fn has_valid_payload_format(&self) -> bool {
+        match self.payload_format_indicator {
+            Some(PayloadFormatIndicator(1)) => std::str::from_utf8(&self.payload).is_ok(),
+            _ => true,
+        }
+    }
+
+    /// Copy the topic, properties and payload into their own allocations, so a packet
+    /// which is kept around doesn't keep the buffer it was decoded from alive.
+    pub fn detached(&self) -> Self {
+        PublishPacket {
+            is_duplicate: self.is_duplicate,
+            qos: self.qos,
+            retain: self.retain,
+
+            topic: self.topic.detached(),
+            packet_id: self.packet_id,
+
+            payload_format_indicator: self.payload_format_indicator.clone(),
+            message_expiry_interval: self.message_expiry_interval.clone(),
+            topic_alias: self.topic_alias.clone(),
+            response_topic: self
+                .response_topic
+                .as_ref()
+                .map(|response_topic| ResponseTopic(response_topic.0.detached())),
+            correlation_data: self.correlation_data.as_ref().map(|correlation_data| {
+                CorrelationData(Bytes::copy_from_slice(&correlation_data.0))
+            }),
+            user_properties: self
+                .user_properties
+                .iter()
+                .map(|UserProperty(key, value)| UserProperty(key.detached(), value.detached()))
+                .collect(),
+            subscription_identifiers: self.subscription_identifiers.clone(),
+            content_type: self
+                .content_type
+                .as_ref()
+                .map(|content_type| ContentType(content_type.0.detached())),
+
+            payload: Bytes::copy_from_slice(&self.payload),
+        }
+    }
+}
+
+impl From<DisconnectReason> for DisconnectPacket {
+    fn from(reason_code: DisconnectReason) -> Self {
+        Self {
+            reason_code,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: Vec::with_capacity(0),
+            server_reference: None,
+        }
+    }
+}
+
 impl From<FinalWill> for PublishPacket {
     fn from(will: FinalWill) -> Self {
         Self {
@@ -964,7 +1282,7 @@ impl From<FinalWill> for PublishPacket {
             retain: will.should_retain,
 
             // Variable header
-            topic: will.topic.parse().unwrap(), // TODO(bschwind) - Add a Topic type directly to FinalWill
+            topic: will.topic,
             packet_id: None,
 
             // Properties
@@ -1285,6 +1603,12 @@ impl Packet {
     pub fn calculate_size(&self, protocol_version: ProtocolVersion) -> u32 {
         self.calc_size(protocol_version)
     }
+
+    /// The size of the encoded packet, including the fixed header.
+    pub fn encoded_size(&self, protocol_version: ProtocolVersion) -> u32 {
+        let remaining_length = self.calc_size(protocol_version);
+        1 + VariableByteInt(remaining_length).calc_size(protocol_version) + remaining_length
+    }
 }
 
 impl PacketSize for Packet {
@@ -1321,21 +1645,7 @@ impl PacketSize for Packet {
 
                 size
             },
-            Packet::Publish(p) => {
-                let mut size = p.topic.calc_size(protocol_version);
-                size += p.packet_id.calc_size(protocol_version);
-
-                if protocol_version == ProtocolVersion::V500 {
-                    let property_size = p.property_size(protocol_version);
-                    size +=
-                        property_size + VariableByteInt(property_size).calc_size(protocol_version);
-                }
-
-                // This payload does not have a length prefix
-                size += p.payload.len() as u32;
-
-                size
-            },
+            Packet::Publish(p) => p.calc_size(protocol_version),
             Packet::PublishAck(p) => {
                 // packet_id
                 let mut size = 2;